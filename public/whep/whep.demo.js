@@ -18,8 +18,9 @@ window.start = async () => {
 	//Create whep client
 	const whep = new WHEPClient();
 
-	const url = "/whep/endpoint";
-	const token = document.getElementById("room-id").value;
+	const channel = document.getElementById("room-id").value;
+	const url = "/whep/" + channel;
+	const token = channel;
 
 	//Start viewing
 	whep.view(pc, url, token);
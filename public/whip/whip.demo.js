@@ -35,8 +35,9 @@ window.start = async () => {
     //Create whip client
     const whip = new WHIPClient();
 
-    const url = "/whip/endpoint";
-    const token = document.getElementById("room-id").value;
+    const channel = document.getElementById("room-id").value;
+    const url = "/whip/" + channel;
+    const token = channel;
 
     //Start publishing
     whip.publish(pc, url, token);
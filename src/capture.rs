@@ -0,0 +1,488 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+use str0m::rtp::{
+    rtcp::{Rtcp, RtcpPacket},
+    ExtensionValues, RawPacket, RtpHeader,
+};
+
+/// Packets queued for the writer thread before new ones are dropped.
+const QUEUE_SIZE: usize = 4096;
+/// The file is flushed when no packet came for this long, so it can be opened while capturing.
+const FLUSH_IDLE: Duration = Duration::from_secs(1);
+/// pcap LINKTYPE_RAW: packets start with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+
+const AUDIO_LEVEL_URI: &str = "urn:ietf:params:rtp-hdrext:ssrc-audio-level";
+const TRANSPORT_CC_URI: &str =
+    "http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01";
+const RID_URI: &str = "urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id";
+const REPAIRED_RID_URI: &str = "urn:ietf:params:rtp-hdrext:sdes:repaired-rtp-stream-id";
+
+/// Sessions to capture, sent by the admin API to every worker.
+#[derive(Debug, Clone)]
+pub enum CaptureTarget {
+    /// Session `{worker_id}-{task_id}`, as in its resource path.
+    Session { worker_id: usize, task_id: usize },
+    /// The publisher and the viewers of a channel.
+    Channel(String),
+}
+
+/// Fields of the RTP header of a captured packet.
+#[derive(Debug, Clone, Copy)]
+pub struct RtpFields {
+    pub payload_type: u8,
+    pub marker: bool,
+    pub sequence_number: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub ext_vals: ExtensionValues,
+}
+
+impl From<&RtpHeader> for RtpFields {
+    fn from(header: &RtpHeader) -> Self {
+        Self {
+            payload_type: *header.payload_type,
+            marker: header.marker,
+            sequence_number: header.sequence_number,
+            timestamp: header.timestamp,
+            ssrc: *header.ssrc,
+            ext_vals: header.ext_vals,
+        }
+    }
+}
+
+/// Ids of the RTP header extensions negotiated in a session, from its `a=extmap` lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExtensionIds {
+    pub audio_level: Option<u8>,
+    pub transport_cc: Option<u8>,
+    pub rid: Option<u8>,
+    pub repaired_rid: Option<u8>,
+}
+
+impl ExtensionIds {
+    pub fn from_sdp(sdp: &str) -> Self {
+        let mut ids = Self::default();
+        for line in sdp.lines() {
+            let mut parts = match line.trim().strip_prefix("a=extmap:") {
+                Some(extmap) => extmap.split_whitespace(),
+                None => continue,
+            };
+            //the id may be followed by a direction
+            let id = parts
+                .next()
+                .and_then(|id| id.split('/').next())
+                .and_then(|id| id.parse::<u8>().ok())
+                .filter(|id| (1..15).contains(id));
+            let slot = match parts.next() {
+                Some(AUDIO_LEVEL_URI) => &mut ids.audio_level,
+                Some(TRANSPORT_CC_URI) => &mut ids.transport_cc,
+                Some(RID_URI) => &mut ids.rid,
+                Some(REPAIRED_RID_URI) => &mut ids.repaired_rid,
+                _ => continue,
+            };
+            if slot.is_none() {
+                *slot = id;
+            }
+        }
+        ids
+    }
+}
+
+struct CapturedPacket {
+    time: SystemTime,
+    source: SocketAddr,
+    destination: SocketAddr,
+    /// RTP or RTCP packet, without SRTP.
+    data: Vec<u8>,
+}
+
+/// Capture of the decrypted RTP and RTCP packets of a session to a pcap file, for Wireshark.
+///
+/// These are not the bytes on the wire: str0m only exposes parsed packets, so RTP headers are
+/// reconstructed from their fields and the extensions str0m parses (audio level, transport-wide
+/// sequence number, rids) with the ids negotiated by the session, and RTCP is serialized again,
+/// one packet per datagram. RTCP and the RTP packets as str0m sends them only come from sessions
+/// with raw packets enabled, see [`RtpCapture::raw_packet`]; otherwise sent packets are captured
+/// when the task hands them to str0m. Packets are wrapped in UDP/IP headers with the addresses
+/// of the last datagram of the session, so "Decode As RTP" works on the file. Writing happens on
+/// a dedicated thread, the file is closed on drop.
+pub struct RtpCapture {
+    sender: Sender<CapturedPacket>,
+    extensions: ExtensionIds,
+    local: SocketAddr,
+    remote: SocketAddr,
+}
+
+impl RtpCapture {
+    pub fn start(dir: &Path, name: &str, local: SocketAddr, remote: SocketAddr) -> RtpCapture {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = dir.join(format!("{name}-{millis}.pcap"));
+        log::info!("Capturing session {name} to {}", path.display());
+        let (sender, receiver) = crossbeam::channel::bounded(QUEUE_SIZE);
+        std::thread::spawn(move || {
+            if let Err(e) = Self::run(&path, receiver) {
+                log::error!("Capture {} failed: {e}", path.display());
+            } else {
+                log::info!("Capture {} closed", path.display());
+            }
+        });
+        RtpCapture {
+            sender,
+            extensions: ExtensionIds::default(),
+            local,
+            remote,
+        }
+    }
+
+    pub fn set_extensions(&mut self, extensions: ExtensionIds) {
+        self.extensions = extensions;
+    }
+
+    /// Follow the addresses of the session datagrams, the remote one changes with ICE.
+    pub fn set_addresses(&mut self, local: SocketAddr, remote: SocketAddr) {
+        self.local = local;
+        self.remote = remote;
+    }
+
+    pub fn incoming(&self, rtp: RtpFields, payload: &[u8]) {
+        self.push(self.remote, self.local, rtp, payload);
+    }
+
+    pub fn outgoing(&self, rtp: RtpFields, payload: &[u8]) {
+        self.push(self.local, self.remote, rtp, payload);
+    }
+
+    /// Capture a packet received or sent by a session with raw packets enabled.
+    pub fn raw_packet(&self, packet: &RawPacket) {
+        match packet {
+            RawPacket::RtpRx(header, payload) => self.incoming(header.into(), payload),
+            RawPacket::RtpTx(header, payload) => self.outgoing(header.into(), payload),
+            RawPacket::RtcpRx(rtcp) => self.push_data(self.remote, self.local, rtcp_packet(rtcp)),
+            RawPacket::RtcpTx(rtcp) => self.push_data(self.local, self.remote, rtcp_packet(rtcp)),
+        }
+    }
+
+    fn push(&self, source: SocketAddr, destination: SocketAddr, rtp: RtpFields, payload: &[u8]) {
+        self.push_data(
+            source,
+            destination,
+            rtp_packet(&rtp, &self.extensions, payload),
+        );
+    }
+
+    fn push_data(&self, source: SocketAddr, destination: SocketAddr, data: Vec<u8>) {
+        if self
+            .sender
+            .try_send(CapturedPacket {
+                time: SystemTime::now(),
+                source,
+                destination,
+                data,
+            })
+            .is_err()
+        {
+            log::debug!("Capture queue full, packet dropped");
+        }
+    }
+
+    fn run(path: &Path, receiver: Receiver<CapturedPacket>) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        //pcap header: magic, version 2.4, timezone, accuracy, snaplen, link type
+        out.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&4u16.to_le_bytes())?;
+        out.write_all(&[0; 8])?;
+        out.write_all(&65535u32.to_le_bytes())?;
+        out.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+
+        loop {
+            let packet = match receiver.recv_timeout(FLUSH_IDLE) {
+                Ok(packet) => packet,
+                Err(RecvTimeoutError::Timeout) => {
+                    out.flush()?;
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let data = ip_udp_packet(packet.source, packet.destination, &packet.data);
+            let time = packet.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            out.write_all(&(time.as_secs() as u32).to_le_bytes())?;
+            out.write_all(&time.subsec_micros().to_le_bytes())?;
+            out.write_all(&(data.len() as u32).to_le_bytes())?;
+            out.write_all(&(data.len() as u32).to_le_bytes())?;
+            out.write_all(&data)?;
+        }
+        out.flush()
+    }
+}
+
+/// RTP packet with the one-byte header extensions of RFC 8285 which have a negotiated id.
+fn rtp_packet(rtp: &RtpFields, ids: &ExtensionIds, payload: &[u8]) -> Vec<u8> {
+    let mut elements = Vec::new();
+    let mut element = |id: Option<u8>, value: &[u8]| {
+        if let Some(id) = id {
+            if (1..=16).contains(&value.len()) {
+                elements.push((id << 4) | (value.len() as u8 - 1));
+                elements.extend_from_slice(value);
+            }
+        }
+    };
+    if let Some(level) = rtp.ext_vals.audio_level {
+        let voice = rtp.ext_vals.voice_activity.unwrap_or(false) as u8;
+        element(
+            ids.audio_level,
+            &[(voice << 7) | (level.unsigned_abs() & 0x7f)],
+        );
+    }
+    if let Some(seq) = rtp.ext_vals.transport_cc {
+        element(ids.transport_cc, &seq.to_be_bytes());
+    }
+    if let Some(rid) = rtp.ext_vals.rid {
+        element(ids.rid, rid.to_string().as_bytes());
+    }
+    if let Some(rid) = rtp.ext_vals.rid_repair {
+        element(ids.repaired_rid, rid.to_string().as_bytes());
+    }
+
+    let mut packet = Vec::with_capacity(16 + elements.len() + payload.len());
+    packet.push(if elements.is_empty() { 0x80 } else { 0x90 });
+    packet.push(((rtp.marker as u8) << 7) | (rtp.payload_type & 0x7f));
+    packet.extend_from_slice(&rtp.sequence_number.to_be_bytes());
+    packet.extend_from_slice(&rtp.timestamp.to_be_bytes());
+    packet.extend_from_slice(&rtp.ssrc.to_be_bytes());
+    if !elements.is_empty() {
+        //padded to 32 bits words
+        elements.resize(elements.len().div_ceil(4) * 4, 0);
+        packet.extend_from_slice(&[0xbe, 0xde]);
+        packet.extend_from_slice(&((elements.len() / 4) as u16).to_be_bytes());
+        packet.extend_from_slice(&elements);
+    }
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// RTCP packet serialized from the packet parsed by str0m.
+fn rtcp_packet(rtcp: &Rtcp) -> Vec<u8> {
+    let mut packet = vec![0; rtcp.length_words() * 4];
+    let len = rtcp.write_to(&mut packet);
+    packet.truncate(len);
+    packet
+}
+
+/// Wrap a payload in UDP and IP headers, IPv6 if any of the addresses is IPv6.
+fn ip_udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let mut packet = Vec::with_capacity(40 + udp_len);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut header = [0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            //don't fragment, TTL 64, UDP
+            header[6] = 0x40;
+            header[8] = 64;
+            header[9] = 17;
+            header[12..16].copy_from_slice(&src_ip.octets());
+            header[16..20].copy_from_slice(&dst_ip.octets());
+            let checksum = ipv4_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&header);
+        }
+        (src_ip, dst_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            //UDP, hop limit 64
+            packet.extend_from_slice(&[17, 64]);
+            packet.extend_from_slice(&to_v6(src_ip).octets());
+            packet.extend_from_slice(&to_v6(dst_ip).octets());
+        }
+    }
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    //no UDP checksum
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use str0m::rtp::ExtensionValues;
+
+    use super::{ip_udp_packet, rtp_packet, ExtensionIds, RtpCapture, RtpFields};
+
+    const SDP: &str = "v=0\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+        a=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r\n\
+        a=extmap:3 http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+        a=extmap:3 http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01\r\n\
+        a=extmap:10/sendonly urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id\r\n\
+        a=extmap:11 urn:ietf:params:rtp-hdrext:sdes:repaired-rtp-stream-id\r\n\
+        a=extmap:12 http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time\r\n";
+
+    fn fields(ext_vals: ExtensionValues) -> RtpFields {
+        RtpFields {
+            payload_type: 111,
+            marker: true,
+            sequence_number: 0x1234,
+            timestamp: 0x01020304,
+            ssrc: 0xaabbccdd,
+            ext_vals,
+        }
+    }
+
+    #[test]
+    fn extension_ids_from_sdp() {
+        assert_eq!(
+            ExtensionIds::from_sdp(SDP),
+            ExtensionIds {
+                audio_level: Some(1),
+                transport_cc: Some(3),
+                rid: Some(10),
+                repaired_rid: Some(11),
+            }
+        );
+        assert_eq!(ExtensionIds::from_sdp("v=0\r\n"), ExtensionIds::default());
+    }
+
+    #[test]
+    fn rtp_without_extensions() {
+        let ext_vals = ExtensionValues {
+            audio_level: Some(-30),
+            ..Default::default()
+        };
+        //no negotiated id, the extension is left out
+        let packet = rtp_packet(&fields(ext_vals), &ExtensionIds::default(), &[0xfe]);
+        assert_eq!(
+            packet,
+            [0x80, 0xef, 0x12, 0x34, 1, 2, 3, 4, 0xaa, 0xbb, 0xcc, 0xdd, 0xfe]
+        );
+    }
+
+    #[test]
+    fn rtp_with_extensions() {
+        let ext_vals = ExtensionValues {
+            audio_level: Some(-30),
+            voice_activity: Some(true),
+            transport_cc: Some(0x0102),
+            rid: Some("h".into()),
+            ..Default::default()
+        };
+        let packet = rtp_packet(&fields(ext_vals), &ExtensionIds::from_sdp(SDP), &[0xfe]);
+        assert_eq!(packet[0], 0x90);
+        //one-byte header, 2 words: level, transport-cc, rid and padding
+        assert_eq!(packet[12..16], [0xbe, 0xde, 0x00, 0x02]);
+        assert_eq!(
+            packet[16..24],
+            [0x10, 0x80 | 30, 0x31, 0x01, 0x02, 0xa0, b'h', 0x00]
+        );
+        assert_eq!(packet[24..], [0xfe]);
+    }
+
+    #[test]
+    fn ipv4_udp_headers() {
+        let src: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let dst: SocketAddr = "192.168.1.2:6000".parse().unwrap();
+        let packet = ip_udp_packet(src, dst, &[1, 2, 3]);
+        assert_eq!(packet.len(), 20 + 8 + 3);
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(packet[2..4], 31u16.to_be_bytes());
+        assert_eq!(packet[12..20], [10, 0, 0, 1, 192, 168, 1, 2]);
+        //a valid header sums to 0xffff with its checksum
+        let sum: u32 = packet[..20]
+            .chunks(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+            .sum();
+        assert_eq!((sum & 0xffff) + (sum >> 16), 0xffff);
+        assert_eq!(packet[20..28], [0x13, 0x88, 0x17, 0x70, 0, 11, 0, 0]);
+        assert_eq!(packet[28..], [1, 2, 3]);
+    }
+
+    #[test]
+    fn ipv6_udp_headers() {
+        let src: SocketAddr = "[::1]:5000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let packet = ip_udp_packet(src, dst, &[1, 2, 3]);
+        assert_eq!(packet.len(), 40 + 8 + 3);
+        assert_eq!(packet[0], 0x60);
+        assert_eq!(packet[4..7], [0, 11, 17]);
+        //IPv4 addresses are mapped
+        assert_eq!(
+            packet[24..40],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn pcap_file() {
+        let dir = std::env::temp_dir().join(format!("capture-{}", std::process::id()));
+        let local: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let remote: SocketAddr = "10.0.0.2:6000".parse().unwrap();
+        let mut capture = RtpCapture::start(&dir, "test", local, "0.0.0.0:0".parse().unwrap());
+        capture.set_addresses(local, remote);
+        capture.incoming(fields(ExtensionValues::default()), &[0xfe]);
+        capture.outgoing(fields(ExtensionValues::default()), &[0xfd, 0xfc]);
+        drop(capture);
+
+        //the writer thread closes the file once the capture is dropped
+        let mut data = Vec::new();
+        for _ in 0..50 {
+            std::thread::sleep(Duration::from_millis(20));
+            let path = std::fs::read_dir(&dir)
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap()
+                .path();
+            data = std::fs::read(path).unwrap();
+            if data.len() >= 24 + 2 * 16 + 2 * 40 + 3 {
+                break;
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(data[..4], 0xa1b2c3d4u32.to_le_bytes());
+        assert_eq!(data[20..24], 101u32.to_le_bytes());
+        //record header, then the incoming packet from the remote address
+        let len = u32::from_le_bytes(data[32..36].try_into().unwrap()) as usize;
+        assert_eq!(len, 20 + 8 + 12 + 1);
+        let first = &data[40..40 + len];
+        assert_eq!(first[12..20], [10, 0, 0, 2, 10, 0, 0, 1]);
+        let second = &data[40 + len + 16..];
+        assert_eq!(second[12..20], [10, 0, 0, 1, 10, 0, 0, 2]);
+        assert_eq!(second[second.len() - 2..], [0xfd, 0xfc]);
+    }
+}
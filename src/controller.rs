@@ -1,12 +1,25 @@
-use std::{net::IpAddr, sync::Arc, thread::JoinHandle};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Arc,
+    thread::JoinHandle,
+};
 
 use bus::Bus;
 use crossbeam::channel::{Receiver, Sender};
 use parking_lot::Mutex;
 
 use crate::{
-    io::{IoAction, IoEvent},
-    worker::Worker,
+    capture::CaptureTarget,
+    http::{
+        auth::has_bearer_token,
+        get_request_channel, parse_channel_path, parse_resource_path, problem_response,
+        webhook::{AdmissionResult, WebhookAdmission, WebhookConfig},
+    },
+    io::{HttpRequest, HttpResponse, IoAction, IoEvent},
+    metrics::Metrics,
+    rtmp::RtmpPublish,
+    worker::{BusEvent, PublisherRegistry, RecordingRegistry, RoomRegistry, Worker, WorkerConfig},
 };
 
 struct WorkerSlot {
@@ -18,20 +31,58 @@ pub struct Controller {
     count: usize,
     joins: Vec<WorkerSlot>,
     worker_recv: Receiver<IoAction>,
+    outputs: VecDeque<IoAction>,
+    legacy_auth_channel: bool,
+    webhook: Option<WebhookAdmission>,
+    /// RTMP publishes waiting for the admission webhook, by request id.
+    rtmp_pending: HashMap<u64, RtmpPublish>,
+    metrics: Arc<Metrics>,
+    bus: Arc<Mutex<Bus<BusEvent>>>,
+    recordings: RecordingRegistry,
+    admin_token: Option<String>,
 }
 
 impl Controller {
-    pub fn new(workers: usize, ip_addr: IpAddr) -> Controller {
+    pub fn new(
+        workers: usize,
+        ip_addr: IpAddr,
+        config: WorkerConfig,
+        webhook: Option<WebhookConfig>,
+    ) -> Controller {
+        let legacy_auth_channel = config.legacy_auth_channel;
+        let admin_token = config.admin_token.clone();
         let bus = Arc::new(Mutex::new(Bus::new(1000)));
+        let publishers = PublisherRegistry::default();
+        let rooms = RoomRegistry::default();
+        let metrics = Arc::new(Metrics::default());
+        let recordings: RecordingRegistry =
+            Arc::new(Mutex::new(config.record_channels.iter().cloned().collect()));
         let (worker_send, worker_recv) = crossbeam::channel::bounded(100);
         let mut joins = Vec::new();
-        for _ in 0..workers {
+        for worker_id in 0..workers {
             let (sender, receiver) = crossbeam::channel::bounded(100);
             let worker_send = worker_send.clone();
             let bus = bus.clone();
+            let config = config.clone();
+            let publishers = publishers.clone();
+            let rooms = rooms.clone();
+            let recordings = recordings.clone();
+            let metrics = metrics.clone();
             let thread = std::thread::spawn(move || {
                 let bus_rx = bus.lock().add_rx();
-                let mut worker = Worker::new(ip_addr, worker_send, receiver, bus, bus_rx);
+                let mut worker = Worker::new(
+                    worker_id,
+                    config,
+                    ip_addr,
+                    worker_send,
+                    receiver,
+                    bus,
+                    bus_rx,
+                    publishers,
+                    rooms,
+                    recordings,
+                    metrics,
+                );
                 worker.prepare();
                 while let Some(_) = worker.process_cycle() {
                     // Do nothing
@@ -47,28 +98,256 @@ impl Controller {
             count: 0,
             joins,
             worker_recv,
+            outputs: VecDeque::new(),
+            legacy_auth_channel,
+            webhook: webhook.map(WebhookAdmission::new),
+            rtmp_pending: HashMap::new(),
+            metrics,
+            bus,
+            recordings,
+            admin_token,
         }
     }
 
     pub fn input<'a>(&mut self, event: IoEvent<'a>) {
         match event {
+            IoEvent::HttpRequest(req) if req.method == "GET" && req.path == "/metrics" => {
+                self.outputs.push_back(IoAction::HttpResponse(HttpResponse {
+                    req_id: req.req_id,
+                    status: 200,
+                    headers: HashMap::from([(
+                        "Content-Type".to_string(),
+                        "text/plain; version=0.0.4".to_string(),
+                    )]),
+                    body: self.metrics.render().into_bytes(),
+                }));
+            }
+            IoEvent::HttpRequest(req) if req.path.starts_with("/admin/") => {
+                self.admin(req);
+            }
+            //playbacks are started with the admin token, so they are ended with it too
+            IoEvent::HttpRequest(req) if req.path.starts_with("/playback/") => {
+                if self.check_admin_token(&req) {
+                    self.dispatch(req);
+                }
+            }
             IoEvent::HttpRequest(req) => {
-                let slot_index = self.count % self.joins.len();
-                let slot = &mut self.joins[slot_index];
-                if let Err(e) = slot.sender.try_send(IoEvent::HttpRequest(req)) {
-                    log::error!("Failed to send request to worker {slot_index}: {e}");
+                if let Some(webhook) = &self.webhook {
+                    if req.method == "POST" {
+                        for kind in ["whip", "whep", "room"] {
+                            if let Some(channel) =
+                                get_request_channel(&req, kind, self.legacy_auth_channel)
+                            {
+                                //admission is checked off-thread, request is dispatched in pop_action
+                                webhook.check(kind, channel, req);
+                                return;
+                            }
+                        }
+                    }
                 }
-                self.count += 1;
+                self.dispatch(req);
+            }
+            IoEvent::RtmpPublish(publish) => {
+                if let Some(webhook) = &self.webhook {
+                    webhook.check("rtmp", publish.channel.clone(), publish.req.clone());
+                    self.rtmp_pending.insert(publish.req.req_id, publish);
+                    return;
+                }
+                self.dispatch_rtmp(publish);
             }
             _ => panic!("Should not receive this event."),
         }
     }
 
+    /// Answer 401 and return false when the admin token is set and the request lacks it.
+    fn check_admin_token(&mut self, req: &HttpRequest) -> bool {
+        if let Some(token) = &self.admin_token {
+            if !has_bearer_token(req, token) {
+                self.outputs
+                    .push_back(IoAction::HttpResponse(problem_response(
+                        req.req_id,
+                        401,
+                        "Unauthorized",
+                        "missing or invalid admin token",
+                    )));
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Admin API: `GET /admin/recordings` lists the recorded channels,
+    /// `POST|DELETE /admin/recordings/{channel}` starts or stops recording a channel,
+    /// `POST|DELETE /admin/captures/{session/{worker}-{task}|channel/{channel}}` starts or
+    /// stops the RTP capture of sessions, `POST /admin/playbacks/{channel}` plays a file to a
+    /// channel on one of the workers.
+    fn admin(&mut self, req: HttpRequest) {
+        if !self.check_admin_token(&req) {
+            return;
+        }
+
+        let path = req.path.split('?').next().unwrap_or_default();
+        if req.method == "POST" && parse_channel_path(path, "admin/playbacks").is_some() {
+            //the playback task is created like a publisher session
+            self.dispatch(req);
+            return;
+        }
+        let res = if req.method == "GET" && path == "/admin/recordings" {
+            let mut channels: Vec<String> = self.recordings.lock().iter().cloned().collect();
+            channels.sort();
+            HttpResponse {
+                req_id: req.req_id,
+                status: 200,
+                headers: HashMap::from([(
+                    "Content-Type".to_string(),
+                    "application/json".to_string(),
+                )]),
+                body: serde_json::to_vec(&channels).expect("Should serialize channels"),
+            }
+        } else if let Some(channel) = parse_channel_path(&req.path, "admin/recordings") {
+            let changed = match req.method.as_str() {
+                //the publisher worker ignores the request when already recording
+                "POST" => {
+                    self.recordings.lock().insert(channel.to_string());
+                    Some(true)
+                }
+                "DELETE" => Some(self.recordings.lock().remove(channel)),
+                _ => None,
+            };
+            match changed {
+                Some(true) => {
+                    let enabled = req.method == "POST";
+                    log::info!("Admin set recording of channel {channel} to {enabled}");
+                    self.bus.lock().broadcast(BusEvent::Record {
+                        channel: channel.to_string(),
+                        enabled,
+                    });
+                    HttpResponse {
+                        req_id: req.req_id,
+                        status: 204,
+                        headers: Default::default(),
+                        body: Vec::new(),
+                    }
+                }
+                Some(false) => {
+                    problem_response(req.req_id, 404, "Not Found", "channel is not recorded")
+                }
+                None => {
+                    problem_response(req.req_id, 405, "Method Not Allowed", "use POST or DELETE")
+                }
+            }
+        } else if let Some(target) = path
+            .strip_prefix("/admin/captures/")
+            .and_then(parse_capture_target)
+        {
+            match req.method.as_str() {
+                "POST" | "DELETE" => {
+                    let enabled = req.method == "POST";
+                    log::info!("Admin set capture of {:?} to {enabled}", target);
+                    self.bus
+                        .lock()
+                        .broadcast(BusEvent::Capture { target, enabled });
+                    //applied by the workers owning the sessions
+                    HttpResponse {
+                        req_id: req.req_id,
+                        status: 202,
+                        headers: Default::default(),
+                        body: Vec::new(),
+                    }
+                }
+                _ => problem_response(req.req_id, 405, "Method Not Allowed", "use POST or DELETE"),
+            }
+        } else {
+            problem_response(req.req_id, 404, "Not Found", "unknown admin resource")
+        };
+        self.outputs.push_back(IoAction::HttpResponse(res));
+    }
+
+    fn dispatch(&mut self, req: HttpRequest) {
+        //requests to a session resource must go to the worker which owns that session
+        let slot_index = if let Some(resource) = parse_resource_path(&req.path) {
+            let worker_id = resource.worker_id;
+            if worker_id >= self.joins.len() {
+                log::warn!("Request to unknown worker {worker_id}: {}", req.path);
+                self.outputs.push_back(IoAction::HttpResponse(HttpResponse {
+                    req_id: req.req_id,
+                    status: 404,
+                    headers: Default::default(),
+                    body: b"Not Found".to_vec(),
+                }));
+                return;
+            }
+            worker_id
+        } else {
+            let slot_index = self.count % self.joins.len();
+            self.count += 1;
+            slot_index
+        };
+        let slot = &mut self.joins[slot_index];
+        if let Err(e) = slot.sender.try_send(IoEvent::HttpRequest(req)) {
+            log::error!("Failed to send request to worker {slot_index}: {e}");
+        }
+    }
+
+    fn dispatch_rtmp(&mut self, publish: RtmpPublish) {
+        let slot_index = self.count % self.joins.len();
+        self.count += 1;
+        let slot = &mut self.joins[slot_index];
+        if let Err(e) = slot.sender.try_send(IoEvent::RtmpPublish(publish)) {
+            log::error!("Failed to send rtmp publish to worker {slot_index}: {e}");
+        }
+    }
+
     pub fn pop_action(&mut self) -> Option<IoAction> {
+        while let Some((req, result)) = self.webhook.as_ref().and_then(|w| w.pop_result()) {
+            //rtmp publishes have no http response, the connection gets the answer
+            if let Some(publish) = self.rtmp_pending.remove(&req.req_id) {
+                match result {
+                    AdmissionResult::Allowed => self.dispatch_rtmp(publish),
+                    AdmissionResult::Denied(_) => {
+                        publish.reject("rejected by admission webhook".to_string())
+                    }
+                }
+                continue;
+            }
+            match result {
+                AdmissionResult::Allowed => self.dispatch(req),
+                AdmissionResult::Denied(status) => {
+                    self.outputs
+                        .push_back(IoAction::HttpResponse(problem_response(
+                            req.req_id,
+                            status,
+                            "Forbidden",
+                            "rejected by admission webhook",
+                        )));
+                }
+            }
+        }
+        if let Some(action) = self.outputs.pop_front() {
+            return Some(action);
+        }
         self.worker_recv.try_recv().ok()
     }
 }
 
+/// Parse `session/{worker_id}-{task_id}[-{token}]` or `channel/{channel}`.
+fn parse_capture_target(path: &str) -> Option<CaptureTarget> {
+    match path.split_once('/')? {
+        ("session", session) => {
+            //the admin API doesn't need the session token, accept the full id anyway
+            let mut parts = session.splitn(3, '-');
+            Some(CaptureTarget::Session {
+                worker_id: parts.next()?.parse().ok()?,
+                task_id: parts.next()?.parse().ok()?,
+            })
+        }
+        ("channel", channel) if !channel.is_empty() && !channel.contains('/') => {
+            Some(CaptureTarget::Channel(channel.to_string()))
+        }
+        _ => None,
+    }
+}
+
 impl Drop for Controller {
     fn drop(&mut self) {
         for join in self.joins.drain(..) {
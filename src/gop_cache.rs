@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+
+use crate::tasks::{payload::is_keyframe_start, TrackMedia};
+
+/// A cache without new packets for this long belongs to a gone publisher.
+const STALE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct LayerGop {
+    /// RTP timestamp of the keyframe, None until the first keyframe.
+    keyframe_ts: Option<u32>,
+    packets: Vec<TrackMedia>,
+    bytes: usize,
+}
+
+/// Packets of a video track since its last keyframe, replayed to new viewers so they don't
+/// have to wait for the next keyframe.
+///
+/// Each simulcast layer has its own GOP. A GOP bigger than `max_bytes` is dropped until the
+/// next keyframe.
+pub struct GopCache {
+    max_bytes: usize,
+    layers: Vec<LayerGop>,
+    last_push: Option<Instant>,
+}
+
+impl GopCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            layers: Vec::new(),
+            last_push: None,
+        }
+    }
+
+    pub fn is_stale(&self, now: Instant) -> bool {
+        self.last_push
+            .is_none_or(|last| now - last >= STALE_TIMEOUT)
+    }
+
+    pub fn push(&mut self, media: &TrackMedia, now: Instant) {
+        self.last_push = Some(now);
+        let index = media.simulcast.map_or(0, |layer| layer.index as usize);
+        if self.layers.len() <= index {
+            self.layers.resize_with(index + 1, Default::default);
+        }
+        let gop = &mut self.layers[index];
+        //H264 keyframes can start with several parameter set packets of the same frame
+        if is_keyframe_start(media.codec, &media.payload)
+            && gop.keyframe_ts != Some(media.header.timestamp)
+        {
+            gop.keyframe_ts = Some(media.header.timestamp);
+            gop.packets.clear();
+            gop.bytes = 0;
+        }
+        if gop.keyframe_ts.is_none() {
+            return;
+        }
+        gop.bytes += media.payload.len();
+        if gop.bytes > self.max_bytes {
+            log::debug!(
+                "GopCache layer {index} over {} bytes, dropped",
+                self.max_bytes
+            );
+            *gop = LayerGop::default();
+            return;
+        }
+        gop.packets.push(media.clone());
+    }
+
+    /// Cached packets to send to a new viewer, highest layer first so the viewer starts on it.
+    ///
+    /// RTP sequence numbers and timestamps are kept so the live packets continue the stream,
+    /// only the receive instant is moved to `now`.
+    pub fn replay(&self, now: Instant) -> Vec<TrackMedia> {
+        self.layers
+            .iter()
+            .rev()
+            .flat_map(|gop| gop.packets.iter())
+            .map(|media| {
+                let mut media = media.clone();
+                media.timestamp = now;
+                media
+            })
+            .collect()
+    }
+}
@@ -1,4 +1,9 @@
-use crate::io::HttpRequest;
+use std::collections::HashMap;
+
+use crate::io::{HttpRequest, HttpResponse};
+
+pub mod auth;
+pub mod webhook;
 
 pub fn get_http_auth(req: &HttpRequest) -> String {
     if let Some(auth) = req.headers.get("Authorization") {
@@ -9,3 +14,179 @@ pub fn get_http_auth(req: &HttpRequest) -> String {
         "demo".to_string()
     }
 }
+
+/// Get a header value, matching the header name case-insensitively.
+pub fn get_http_header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Get the bearer token from the Authorization header.
+pub fn get_http_bearer(req: &HttpRequest) -> Option<&str> {
+    let auth = get_http_header(req, "Authorization")?;
+    auth.strip_prefix("Bearer ")
+        .or_else(|| auth.strip_prefix("bearer "))
+        .map(|token| token.trim())
+}
+
+/// Parse the channel from a `/{kind}/{channel}` path, ignoring any query string.
+pub fn parse_channel_path<'a>(path: &'a str, kind: &str) -> Option<&'a str> {
+    let path = path.split('?').next().unwrap_or(path);
+    let channel = path
+        .strip_prefix('/')?
+        .strip_prefix(kind)?
+        .strip_prefix('/')?;
+    if channel.is_empty() || channel.contains('/') {
+        None
+    } else {
+        Some(channel)
+    }
+}
+
+/// Resolve the channel name of a `POST /{kind}/{channel}` request.
+/// In legacy mode `/{kind}/endpoint` takes the channel from the Authorization header instead.
+pub fn get_request_channel(
+    req: &HttpRequest,
+    kind: &str,
+    legacy_auth_channel: bool,
+) -> Option<String> {
+    let channel = parse_channel_path(&req.path, kind)?;
+    if legacy_auth_channel && channel == "endpoint" {
+        Some(get_http_auth(req))
+    } else {
+        Some(channel.to_string())
+    }
+}
+
+/// Session resource parsed from a path, see [`build_resource_path`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionResource<'a> {
+    /// `whip`, `whep`, `room` or `playback`, a session only answers to its own kind.
+    pub kind: &'a str,
+    pub worker_id: usize,
+    pub task_id: usize,
+    /// Secret part of the resource id, checked against the token of the session.
+    pub token: &'a str,
+}
+
+/// Generate the secret token of a new session resource.
+pub fn new_resource_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Build the per-session resource path which is returned in the `Location` header.
+/// The owning worker is encoded in the path so the controller can route follow-up requests.
+/// Only the request creating the session is authorized, so the path carries a random token
+/// which must be known to end or restart the session.
+pub fn build_resource_path(kind: &str, worker_id: usize, task_id: usize, token: &str) -> String {
+    format!("/{kind}/endpoint/{worker_id}-{task_id}-{token}")
+}
+
+/// Parse a session resource path
+/// `/{whip|whep|room|playback}/endpoint/{worker_id}-{task_id}-{token}[/{sub_resource}]`.
+pub fn parse_resource_path(path: &str) -> Option<SessionResource<'_>> {
+    let path = path.split('?').next().unwrap_or(path);
+    let (kind, resource) = path.strip_prefix('/')?.split_once("/endpoint/")?;
+    if !matches!(kind, "whip" | "whep" | "room" | "playback") {
+        return None;
+    }
+    let resource = resource.split('/').next()?;
+    let mut parts = resource.splitn(3, '-');
+    let worker_id = parts.next()?.parse().ok()?;
+    let task_id = parts.next()?.parse().ok()?;
+    let token = parts.next().filter(|token| !token.is_empty())?;
+    Some(SessionResource {
+        kind,
+        worker_id,
+        task_id,
+        token,
+    })
+}
+
+/// Get the sub resource of a session resource path, e.g. `layer`, or an empty string.
+pub fn get_resource_sub_path(path: &str) -> &str {
+    let path = path.split('?').next().unwrap_or(path);
+    path.splitn(5, '/').nth(4).unwrap_or_default()
+}
+
+/// Build an RFC 7807 problem details response.
+pub fn problem_response(req_id: u64, status: u16, title: &str, detail: &str) -> HttpResponse {
+    HttpResponse {
+        req_id,
+        status,
+        headers: HashMap::from([(
+            "Content-Type".to_string(),
+            "application/problem+json".to_string(),
+        )]),
+        body: format!(
+            "{{\"type\":\"about:blank\",\"status\":{},\"title\":\"{}\",\"detail\":\"{}\"}}",
+            status,
+            json_escape(title),
+            json_escape(detail)
+        )
+        .into_bytes(),
+    }
+}
+
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_resource_path, new_resource_token, parse_resource_path, SessionResource};
+
+    #[test]
+    fn resource_path_round_trip() {
+        let token = new_resource_token();
+        assert_eq!(token.len(), 32);
+        let path = build_resource_path("whep", 1, 42, &token);
+        assert_eq!(
+            parse_resource_path(&path),
+            Some(SessionResource {
+                kind: "whep",
+                worker_id: 1,
+                task_id: 42,
+                token: &token,
+            })
+        );
+        let layer = format!("{path}/layer?x=1");
+        assert_eq!(
+            parse_resource_path(&layer).map(|r| r.token),
+            Some(token.as_str())
+        );
+    }
+
+    #[test]
+    fn resource_path_requires_token() {
+        assert_eq!(parse_resource_path("/whip/endpoint/0-1"), None);
+        assert_eq!(parse_resource_path("/whip/endpoint/0-1-"), None);
+        assert_eq!(parse_resource_path("/whip/endpoint/a-1-abc"), None);
+        assert_eq!(parse_resource_path("/other/endpoint/0-1-abc"), None);
+        assert_eq!(parse_resource_path("/rtmp/endpoint/0-1-abc"), None);
+        assert_eq!(parse_resource_path("/a/whip/endpoint/0-1-abc"), None);
+        assert_eq!(
+            parse_resource_path("/playback/endpoint/0-1-abc").map(|r| r.kind),
+            Some("playback")
+        );
+    }
+
+    #[test]
+    fn resource_tokens_are_unique() {
+        assert_ne!(new_resource_token(), new_resource_token());
+    }
+}
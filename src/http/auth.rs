@@ -0,0 +1,336 @@
+use std::{
+    fmt::Debug,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::io::HttpRequest;
+
+use super::get_http_bearer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthRole {
+    Publish,
+    Subscribe,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// No bearer token in the request.
+    MissingToken,
+    /// Token is malformed, badly signed or expired.
+    InvalidToken(String),
+    /// Token is valid but doesn't grant access to the channel with the requested role.
+    Forbidden(String),
+}
+
+impl AuthError {
+    pub fn status(&self) -> u16 {
+        match self {
+            AuthError::MissingToken | AuthError::InvalidToken(_) => 401,
+            AuthError::Forbidden(_) => 403,
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            AuthError::MissingToken | AuthError::InvalidToken(_) => "Unauthorized",
+            AuthError::Forbidden(_) => "Forbidden",
+        }
+    }
+
+    pub fn detail(&self) -> &str {
+        match self {
+            AuthError::MissingToken => "missing bearer token",
+            AuthError::InvalidToken(detail) | AuthError::Forbidden(detail) => detail,
+        }
+    }
+}
+
+/// Whether the request carries `token` as bearer token, e.g. the admin token.
+///
+/// The HMACs of both tokens are compared with `verify_slice`, so the time taken doesn't tell
+/// how much of the token was right, nor its length.
+pub fn has_bearer_token(req: &HttpRequest, token: &str) -> bool {
+    let mac = |data: &[u8]| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes())
+            .expect("Hmac should accept any key size");
+        mac.update(data);
+        mac
+    };
+    let expected = mac(token.as_bytes()).finalize().into_bytes();
+    get_http_bearer(req)
+        .is_some_and(|bearer| mac(bearer.as_bytes()).verify_slice(&expected).is_ok())
+}
+
+/// Decide if a request can publish or play a channel. Called before any task is created.
+pub trait Authorizer: Debug + Send + Sync {
+    fn authorize(&self, req: &HttpRequest, channel: &str, role: AuthRole) -> Result<(), AuthError>;
+}
+
+/// Accept every request, this is the behavior when no secret is configured.
+#[derive(Debug, Default)]
+pub struct AllowAllAuthorizer;
+
+impl Authorizer for AllowAllAuthorizer {
+    fn authorize(
+        &self,
+        _req: &HttpRequest,
+        _channel: &str,
+        _role: AuthRole,
+    ) -> Result<(), AuthError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtClaims {
+    channel: String,
+    role: AuthRole,
+    /// Expiry as unix timestamp in seconds.
+    exp: u64,
+}
+
+/// Validate HS256 JWT bearer tokens with `channel`, `role` and `exp` claims.
+pub struct JwtAuthorizer {
+    secret: Vec<u8>,
+}
+
+impl JwtAuthorizer {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: secret.to_vec(),
+        }
+    }
+
+    fn verify(&self, token: &str) -> Result<JwtClaims, AuthError> {
+        let invalid = || AuthError::InvalidToken("token is not a jwt".to_string());
+        let (signing_input, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let (header, claims) = signing_input.split_once('.').ok_or_else(invalid)?;
+        if claims.contains('.') {
+            return Err(invalid());
+        }
+
+        let header: JwtHeader = Self::decode_part(header)?;
+        if header.alg != "HS256" {
+            return Err(AuthError::InvalidToken(format!(
+                "unsupported alg {}",
+                header.alg
+            )));
+        }
+
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|e| AuthError::InvalidToken(format!("invalid signature encoding: {e}")))?;
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("Hmac should accept any key size");
+        mac.update(signing_input.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| AuthError::InvalidToken("invalid signature".to_string()))?;
+
+        let claims: JwtClaims = Self::decode_part(claims)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if claims.exp <= now {
+            return Err(AuthError::InvalidToken("token expired".to_string()));
+        }
+        Ok(claims)
+    }
+
+    fn decode_part<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, AuthError> {
+        let json = URL_SAFE_NO_PAD
+            .decode(part)
+            .map_err(|e| AuthError::InvalidToken(format!("invalid encoding: {e}")))?;
+        serde_json::from_slice(&json)
+            .map_err(|e| AuthError::InvalidToken(format!("invalid json: {e}")))
+    }
+}
+
+impl Debug for JwtAuthorizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtAuthorizer").finish_non_exhaustive()
+    }
+}
+
+impl Authorizer for JwtAuthorizer {
+    fn authorize(&self, req: &HttpRequest, channel: &str, role: AuthRole) -> Result<(), AuthError> {
+        let token = get_http_bearer(req).ok_or(AuthError::MissingToken)?;
+        let claims = self.verify(token)?;
+        if claims.channel != channel {
+            return Err(AuthError::Forbidden(format!(
+                "token is not valid for channel {channel}"
+            )));
+        }
+        if claims.role != role {
+            return Err(AuthError::Forbidden(format!(
+                "token role {:?} doesn't allow {:?}",
+                claims.role, role
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use crate::io::HttpRequest;
+
+    use super::{has_bearer_token, AuthError, AuthRole, Authorizer, JwtAuthorizer};
+
+    const SECRET: &[u8] = b"secret";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn sign(header: &str, claims: &str, secret: &[u8]) -> String {
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header),
+            URL_SAFE_NO_PAD.encode(claims)
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(signing_input.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{signing_input}.{signature}")
+    }
+
+    fn token(channel: &str, role: &str, exp: u64) -> String {
+        sign(
+            r#"{"alg":"HS256","typ":"JWT"}"#,
+            &format!(r#"{{"channel":"{channel}","role":"{role}","exp":{exp}}}"#),
+            SECRET,
+        )
+    }
+
+    fn request(token: Option<&str>) -> HttpRequest {
+        HttpRequest {
+            req_id: 0,
+            remote: None,
+            method: "POST".to_string(),
+            path: "/whip/demo".to_string(),
+            headers: token
+                .map(|token| {
+                    HashMap::from([("Authorization".to_string(), format!("Bearer {token}"))])
+                })
+                .unwrap_or_default(),
+            body: Vec::new(),
+        }
+    }
+
+    fn authorize(token: Option<&str>, role: AuthRole) -> Result<(), AuthError> {
+        JwtAuthorizer::new(SECRET).authorize(&request(token), "demo", role)
+    }
+
+    #[test]
+    fn accept_valid_token() {
+        let publish = token("demo", "publish", now() + 60);
+        assert!(authorize(Some(&publish), AuthRole::Publish).is_ok());
+        let subscribe = token("demo", "subscribe", now() + 60);
+        assert!(authorize(Some(&subscribe), AuthRole::Subscribe).is_ok());
+    }
+
+    #[test]
+    fn reject_missing_token() {
+        let err = authorize(None, AuthRole::Publish).unwrap_err();
+        assert!(matches!(err, AuthError::MissingToken));
+        assert_eq!(err.status(), 401);
+    }
+
+    #[test]
+    fn reject_other_channel_or_role() {
+        let other_channel = token("other", "publish", now() + 60);
+        let err = authorize(Some(&other_channel), AuthRole::Publish).unwrap_err();
+        assert!(matches!(err, AuthError::Forbidden(_)));
+        assert_eq!(err.status(), 403);
+
+        let subscribe = token("demo", "subscribe", now() + 60);
+        let err = authorize(Some(&subscribe), AuthRole::Publish).unwrap_err();
+        assert!(matches!(err, AuthError::Forbidden(_)));
+    }
+
+    #[test]
+    fn reject_expired_token() {
+        let expired = token("demo", "publish", now() - 1);
+        let err = authorize(Some(&expired), AuthRole::Publish).unwrap_err();
+        assert!(matches!(err, AuthError::InvalidToken(_)));
+        assert_eq!(err.status(), 401);
+    }
+
+    #[test]
+    fn reject_bad_signature() {
+        let claims = format!(
+            r#"{{"channel":"demo","role":"publish","exp":{}}}"#,
+            now() + 60
+        );
+        let forged = sign(r#"{"alg":"HS256"}"#, &claims, b"other secret");
+        assert!(matches!(
+            authorize(Some(&forged), AuthRole::Publish),
+            Err(AuthError::InvalidToken(_))
+        ));
+
+        //claims changed after signing
+        let valid = token("demo", "subscribe", now() + 60);
+        let mut parts: Vec<&str> = valid.split('.').collect();
+        let tampered_claims = URL_SAFE_NO_PAD.encode(&claims);
+        parts[1] = &tampered_claims;
+        assert!(matches!(
+            authorize(Some(&parts.join(".")), AuthRole::Publish),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn reject_other_alg_and_malformed() {
+        let claims = format!(
+            r#"{{"channel":"demo","role":"publish","exp":{}}}"#,
+            now() + 60
+        );
+        let none = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+            URL_SAFE_NO_PAD.encode(&claims)
+        );
+        for token in [none.as_str(), "abc", "a.b", "a.b.c.d", "..", "!.!.!"] {
+            assert!(
+                matches!(
+                    authorize(Some(token), AuthRole::Publish),
+                    Err(AuthError::InvalidToken(_))
+                ),
+                "token {token} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn bearer_token() {
+        assert!(has_bearer_token(&request(Some("admin")), "admin"));
+        assert!(!has_bearer_token(&request(Some("admin2")), "admin"));
+        assert!(!has_bearer_token(&request(Some("adm")), "admin"));
+        assert!(!has_bearer_token(&request(None), "admin"));
+    }
+}
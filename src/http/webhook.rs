@@ -0,0 +1,273 @@
+use std::time::Duration;
+
+use crossbeam::channel::{Receiver, Sender};
+use serde::Serialize;
+
+use crate::io::HttpRequest;
+
+use super::get_http_bearer;
+
+/// Number of threads calling the webhook, so a slow hook doesn't serialize all admissions.
+const WEBHOOK_THREADS: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// Url which receives a JSON POST for every publish/play request.
+    pub url: String,
+    pub timeout: Duration,
+    /// Admit the request when the hook can't be reached, times out or answers with 5xx.
+    pub default_allow: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct AdmissionRequest<'a> {
+    kind: &'a str,
+    channel: &'a str,
+    ip: Option<String>,
+    token: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub enum AdmissionResult {
+    Allowed,
+    /// Denied with the http status which should be returned to the client.
+    Denied(u16),
+}
+
+struct AdmissionJob {
+    kind: &'static str,
+    channel: String,
+    req: HttpRequest,
+}
+
+/// Call an external http endpoint to admit or deny publish/play requests.
+///
+/// Calls are made from a small thread pool; results are polled with [`WebhookAdmission::pop_result`]
+/// so neither the controller nor the workers ever block on the hook.
+pub struct WebhookAdmission {
+    job_send: Sender<AdmissionJob>,
+    result_recv: Receiver<(HttpRequest, AdmissionResult)>,
+}
+
+impl WebhookAdmission {
+    pub fn new(config: WebhookConfig) -> Self {
+        let (job_send, job_recv) = crossbeam::channel::unbounded::<AdmissionJob>();
+        let (result_send, result_recv) = crossbeam::channel::unbounded();
+        for _ in 0..WEBHOOK_THREADS {
+            let job_recv = job_recv.clone();
+            let result_send = result_send.clone();
+            let config = config.clone();
+            std::thread::spawn(move || {
+                let agent = ureq::AgentBuilder::new().timeout(config.timeout).build();
+                while let Ok(job) = job_recv.recv() {
+                    let result = Self::call(&agent, &config, &job);
+                    if result_send.send((job.req, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        Self {
+            job_send,
+            result_recv,
+        }
+    }
+
+    /// Queue a request for admission, the result is available later from `pop_result`.
+    pub fn check(&self, kind: &'static str, channel: String, req: HttpRequest) {
+        if let Err(e) = self.job_send.send(AdmissionJob { kind, channel, req }) {
+            log::error!("Failed to queue webhook admission: {e}");
+        }
+    }
+
+    pub fn pop_result(&self) -> Option<(HttpRequest, AdmissionResult)> {
+        self.result_recv.try_recv().ok()
+    }
+
+    fn call(agent: &ureq::Agent, config: &WebhookConfig, job: &AdmissionJob) -> AdmissionResult {
+        let body = AdmissionRequest {
+            kind: job.kind,
+            channel: &job.channel,
+            ip: job.req.remote.map(|addr| addr.ip().to_string()),
+            token: get_http_bearer(&job.req),
+        };
+        let body = serde_json::to_string(&body).expect("Should serialize admission request");
+        let default = if config.default_allow {
+            AdmissionResult::Allowed
+        } else {
+            AdmissionResult::Denied(403)
+        };
+
+        match agent
+            .post(&config.url)
+            .set("Content-Type", "application/json")
+            .send_string(&body)
+        {
+            Ok(_) => AdmissionResult::Allowed,
+            Err(ureq::Error::Status(status, _)) if (400..500).contains(&status) => {
+                log::info!(
+                    "Webhook denied {} channel {}: {}",
+                    job.kind,
+                    job.channel,
+                    status
+                );
+                AdmissionResult::Denied(if status == 401 { 401 } else { 403 })
+            }
+            Err(e) => {
+                log::warn!(
+                    "Webhook call failed, apply default policy {:?}: {e}",
+                    default
+                );
+                default
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use crate::io::HttpRequest;
+
+    use super::{AdmissionResult, WebhookAdmission, WebhookConfig};
+
+    /// Start a mock webhook which answers every call with `status` after `delay`.
+    /// Returns its url and the bodies it received.
+    fn mock_webhook(
+        status: u16,
+        delay: Duration,
+    ) -> (String, crossbeam::channel::Receiver<String>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").expect("Should bind mock webhook");
+        let url = format!("http://{}/admission", server.server_addr());
+        let (body_send, body_recv) = crossbeam::channel::unbounded();
+        std::thread::spawn(move || {
+            for mut req in server.incoming_requests() {
+                let mut body = String::new();
+                req.as_reader().read_to_string(&mut body).unwrap();
+                body_send.send(body).unwrap();
+                std::thread::sleep(delay);
+                let _ = req.respond(tiny_http::Response::empty(status));
+            }
+        });
+        (url, body_recv)
+    }
+
+    fn webhook(url: String, timeout_ms: u64, default_allow: bool) -> WebhookAdmission {
+        WebhookAdmission::new(WebhookConfig {
+            url,
+            timeout: Duration::from_millis(timeout_ms),
+            default_allow,
+        })
+    }
+
+    fn request(req_id: u64) -> HttpRequest {
+        HttpRequest {
+            req_id,
+            remote: Some(SocketAddr::from(([192, 0, 2, 1], 5000))),
+            method: "POST".to_string(),
+            path: "/whip/demo".to_string(),
+            headers: HashMap::from([("Authorization".to_string(), "Bearer abc".to_string())]),
+            body: Vec::new(),
+        }
+    }
+
+    fn wait_result(admission: &WebhookAdmission) -> (HttpRequest, AdmissionResult) {
+        let started = Instant::now();
+        loop {
+            if let Some(result) = admission.pop_result() {
+                return result;
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "Should get an admission result"
+            );
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn allow_on_success() {
+        let (url, bodies) = mock_webhook(200, Duration::ZERO);
+        let admission = webhook(url, 1000, false);
+        admission.check("whip", "demo".to_string(), request(1));
+        let (req, result) = wait_result(&admission);
+        assert_eq!(req.req_id, 1);
+        assert!(matches!(result, AdmissionResult::Allowed));
+
+        let body: serde_json::Value = serde_json::from_str(&bodies.recv().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "kind": "whip",
+                "channel": "demo",
+                "ip": "192.0.2.1",
+                "token": "abc",
+            })
+        );
+    }
+
+    #[test]
+    fn deny_on_client_error() {
+        let (url, _bodies) = mock_webhook(401, Duration::ZERO);
+        let admission = webhook(url, 1000, true);
+        admission.check("whep", "demo".to_string(), request(2));
+        assert!(matches!(
+            wait_result(&admission).1,
+            AdmissionResult::Denied(401)
+        ));
+
+        let (url, _bodies) = mock_webhook(404, Duration::ZERO);
+        let admission = webhook(url, 1000, true);
+        admission.check("whep", "demo".to_string(), request(3));
+        assert!(matches!(
+            wait_result(&admission).1,
+            AdmissionResult::Denied(403)
+        ));
+    }
+
+    #[test]
+    fn default_policy_on_timeout() {
+        let (url, _bodies) = mock_webhook(200, Duration::from_millis(500));
+        let admission = webhook(url.clone(), 50, false);
+        admission.check("whip", "demo".to_string(), request(4));
+        assert!(matches!(
+            wait_result(&admission).1,
+            AdmissionResult::Denied(403)
+        ));
+
+        let admission = webhook(url, 50, true);
+        admission.check("whip", "demo".to_string(), request(5));
+        assert!(matches!(
+            wait_result(&admission).1,
+            AdmissionResult::Allowed
+        ));
+    }
+
+    #[test]
+    fn default_policy_on_server_error_or_unreachable() {
+        let (url, _bodies) = mock_webhook(500, Duration::ZERO);
+        let admission = webhook(url, 1000, true);
+        admission.check("whip", "demo".to_string(), request(6));
+        assert!(matches!(
+            wait_result(&admission).1,
+            AdmissionResult::Allowed
+        ));
+
+        //nothing listens on the port of a dropped listener
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/admission", listener.local_addr().unwrap());
+        drop(listener);
+        let admission = webhook(url, 1000, false);
+        admission.check("whip", "demo".to_string(), request(7));
+        assert!(matches!(
+            wait_result(&admission).1,
+            AdmissionResult::Denied(403)
+        ));
+    }
+}
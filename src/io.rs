@@ -1,8 +1,12 @@
 use std::{collections::HashMap, net::SocketAddr};
 
-#[derive(Debug)]
+use crate::rtmp::RtmpPublish;
+
+#[derive(Debug, Clone)]
 pub struct HttpRequest {
     pub req_id: u64,
+    /// Address of the http client, if known.
+    pub remote: Option<SocketAddr>,
     pub method: String,
     pub path: String,
     pub headers: HashMap<String, String>,
@@ -24,6 +28,8 @@ pub enum IoEvent<'a> {
         to: SocketAddr,
         buf: &'a [u8],
     },
+    /// An RTMP connection wants to publish, see [`crate::rtmp::RtmpServer`].
+    RtmpPublish(RtmpPublish),
 }
 
 pub enum IoAction {
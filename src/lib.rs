@@ -1,6 +1,11 @@
+pub mod capture;
 pub mod controller;
+pub mod gop_cache;
 pub mod http;
 pub mod io;
+pub mod metrics;
 pub mod net;
+pub mod recorder;
+pub mod rtmp;
 pub mod tasks;
 pub mod worker;
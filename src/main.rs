@@ -2,11 +2,18 @@ use clap::Parser;
 use std::fs::File;
 use std::io::Read;
 use std::net::{IpAddr, SocketAddr};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::{collections::HashMap, time::Duration};
 
+use str0m::format::Codec;
 use tiny_http::{Header, Method, Response, Server};
+use tiny_media_server::http::auth::{AllowAllAuthorizer, Authorizer, JwtAuthorizer};
+use tiny_media_server::http::webhook::WebhookConfig;
 use tiny_media_server::io::IoAction;
+use tiny_media_server::rtmp::RtmpServer;
+use tiny_media_server::tasks::codec_from_name;
+use tiny_media_server::worker::{PublisherPolicy, WorkerConfig};
 use tiny_media_server::{
     controller::Controller,
     io::{HttpRequest, IoEvent},
@@ -30,6 +37,100 @@ struct Args {
     /// Listen address for media data
     #[arg(env, long, default_value = "127.0.0.1")]
     listen_addr: IpAddr,
+
+    /// Compatibility mode: /whip/endpoint and /whep/endpoint use the Authorization header as channel
+    #[arg(env, long)]
+    legacy_auth_channel: bool,
+
+    /// Secret for HS256 JWT bearer tokens. If not set, every publish and play request is accepted
+    #[arg(env, long)]
+    auth_secret: Option<String>,
+
+    /// What to do when a second publisher joins a channel
+    #[arg(env, long, value_enum, default_value_t = PublisherPolicy::Reject)]
+    publisher_policy: PublisherPolicy,
+
+    /// Codecs allowed for publishers and viewers
+    #[arg(env, long, value_delimiter = ',', value_parser = parse_codec, default_value = "opus,vp8,vp9,h264")]
+    codecs: Vec<Codec>,
+
+    /// Http url called for admission of every publish and play request
+    #[arg(env, long)]
+    webhook_url: Option<String>,
+
+    /// Timeout of admission webhook calls in milliseconds
+    #[arg(env, long, default_value_t = 1000)]
+    webhook_timeout_ms: u64,
+
+    /// Admit requests when the admission webhook fails or times out
+    #[arg(env, long)]
+    webhook_default_allow: bool,
+
+    /// Max bytes of the last video GOP cached per channel for instant viewer startup, 0 disables
+    #[arg(env, long, default_value_t = 0)]
+    gop_cache_bytes: usize,
+
+    /// Per channel GOP cache size, as channel=bytes
+    #[arg(env, long, value_delimiter = ',', value_parser = parse_gop_cache_channel)]
+    gop_cache_channel: Vec<(String, usize)>,
+
+    /// Minimum interval between keyframe requests sent to a publisher, in milliseconds
+    #[arg(env, long, default_value_t = 500)]
+    keyframe_request_interval_ms: u64,
+
+    /// Max size of a relayed data channel message in bytes
+    #[arg(env, long, default_value_t = 16384)]
+    data_channel_max_message: usize,
+
+    /// Relay viewer data channel messages to the publisher
+    #[arg(env, long)]
+    data_channel_upstream: bool,
+
+    /// In rooms, send the best simulcast layer only for the dominant speaker video
+    #[arg(env, long)]
+    room_speaker_priority: bool,
+
+    /// Directory of the channel recordings
+    #[arg(env, long, default_value = "recordings")]
+    record_dir: PathBuf,
+
+    /// Channels recorded to WebM (Matroska for H264) whenever they are published
+    #[arg(env, long, value_delimiter = ',')]
+    record_channel: Vec<String>,
+
+    /// Directory of the session RTP captures
+    #[arg(env, long, default_value = "captures")]
+    capture_dir: PathBuf,
+
+    /// Put RTCP and the RTP packets as sent by the sessions in captures, costs a copy of every packet
+    #[arg(env, long)]
+    capture_raw_packets: bool,
+
+    /// Directory of the files which can be played to a channel with the admin API
+    #[arg(env, long, default_value = "media")]
+    playback_dir: PathBuf,
+
+    /// Listen address of the RTMP ingest, disabled if not set
+    #[arg(env, long)]
+    rtmp_addr: Option<SocketAddr>,
+
+    /// Bearer token of the admin API, the admin API is open if not set
+    #[arg(env, long)]
+    admin_token: Option<String>,
+}
+
+fn parse_codec(name: &str) -> Result<Codec, String> {
+    codec_from_name(name).ok_or_else(|| format!("unsupported codec {name}"))
+}
+
+fn parse_gop_cache_channel(value: &str) -> Result<(String, usize), String> {
+    let (channel, bytes) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected channel=bytes, got {value}"))?;
+    let bytes = bytes
+        .parse()
+        .map_err(|e| format!("invalid bytes {bytes}: {e}"))?;
+    Ok((channel.to_string(), bytes))
 }
 
 fn main() {
@@ -46,7 +147,43 @@ fn main() {
     let mut reqs = HashMap::new();
     let server = Server::http(args.http_addr).unwrap();
     log::info!("server started at port {}", args.http_addr);
-    let mut controller = Controller::new(args.workers, args.listen_addr);
+    let authorizer: Arc<dyn Authorizer> = if let Some(secret) = &args.auth_secret {
+        log::info!("enable jwt authorization");
+        Arc::new(JwtAuthorizer::new(secret.as_bytes()))
+    } else {
+        Arc::new(AllowAllAuthorizer)
+    };
+    let rtmp = args.rtmp_addr.map(|addr| {
+        log::info!("rtmp ingest started at {addr}");
+        RtmpServer::start(addr).expect("Should bind rtmp listener.")
+    });
+    let mut controller = Controller::new(
+        args.workers,
+        args.listen_addr,
+        WorkerConfig {
+            legacy_auth_channel: args.legacy_auth_channel,
+            authorizer,
+            publisher_policy: args.publisher_policy,
+            codecs: args.codecs,
+            gop_cache_bytes: args.gop_cache_bytes,
+            gop_cache_channels: args.gop_cache_channel.into_iter().collect(),
+            keyframe_request_interval: Duration::from_millis(args.keyframe_request_interval_ms),
+            data_channel_max_message: args.data_channel_max_message,
+            data_channel_upstream: args.data_channel_upstream,
+            room_speaker_priority: args.room_speaker_priority,
+            record_dir: args.record_dir,
+            record_channels: args.record_channel,
+            capture_dir: args.capture_dir,
+            capture_raw_packets: args.capture_raw_packets,
+            playback_dir: args.playback_dir,
+            admin_token: args.admin_token,
+        },
+        args.webhook_url.map(|url| WebhookConfig {
+            url,
+            timeout: Duration::from_millis(args.webhook_timeout_ms),
+            default_allow: args.webhook_default_allow,
+        }),
+    );
 
     loop {
         if let Ok(Some(mut request)) = server.recv_timeout(Duration::from_millis(100)) {
@@ -96,6 +233,7 @@ fn main() {
 
             let io_event = IoEvent::HttpRequest(HttpRequest {
                 req_id,
+                remote: request.remote_addr().cloned(),
                 method: request.method().to_string(),
                 path: request.url().to_string(),
                 headers: request
@@ -110,6 +248,20 @@ fn main() {
             req_id += 1;
         }
 
+        if let Some(rtmp) = &rtmp {
+            while let Ok(mut publish) = rtmp.try_recv() {
+                log::info!(
+                    "received request_id {} rtmp publish to channel {}",
+                    req_id,
+                    publish.channel
+                );
+                //shares the request ids with http, the webhook results are matched by id
+                publish.req.req_id = req_id;
+                req_id += 1;
+                controller.input(IoEvent::RtmpPublish(publish));
+            }
+        }
+
         while let Some(action) = controller.pop_action() {
             match action {
                 IoAction::HttpResponse(res) => {
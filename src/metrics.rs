@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Server-wide counters, shared by all workers and served as Prometheus text on `GET /metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Keyframe requests sent to publishers.
+    pub keyframe_requests_forwarded: AtomicU64,
+    /// Keyframe requests from viewers dropped or merged by the throttling.
+    pub keyframe_requests_suppressed: AtomicU64,
+    /// Data channel messages not relayed, too big or upstream relay disabled.
+    pub data_channel_messages_dropped: AtomicU64,
+    /// Packets not recorded because the recording writer fell behind.
+    pub recording_packets_dropped: AtomicU64,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, value) in [
+            (
+                "keyframe_requests_forwarded_total",
+                "Keyframe requests sent to publishers",
+                &self.keyframe_requests_forwarded,
+            ),
+            (
+                "keyframe_requests_suppressed_total",
+                "Keyframe requests suppressed by throttling",
+                &self.keyframe_requests_suppressed,
+            ),
+            (
+                "data_channel_messages_dropped_total",
+                "Data channel messages not relayed",
+                &self.data_channel_messages_dropped,
+            ),
+            (
+                "recording_packets_dropped_total",
+                "Packets not recorded because the writer fell behind",
+                &self.recording_packets_dropped,
+            ),
+        ] {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+                value.load(Ordering::Relaxed)
+            ));
+        }
+        out
+    }
+}
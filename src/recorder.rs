@@ -0,0 +1,257 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use str0m::format::Codec;
+
+use crate::{
+    metrics::Metrics,
+    tasks::{lip_sync::LipSync, payload::is_keyframe_start, TrackMedia},
+};
+
+use self::{
+    depacketizer::{Depacketizer, Frame},
+    webm::{codec_id, is_matroska, WebmTrack, WebmWriter},
+};
+
+mod depacketizer;
+mod webm;
+
+/// Packets queued for the writer thread before new ones are dropped, a few seconds of HD video.
+const QUEUE_SIZE: usize = 4096;
+/// A recording without video after this long of audio is audio only.
+const AUDIO_ONLY_WAIT: Duration = Duration::from_secs(2);
+/// The recorded simulcast layer is replaced when it has no packets for this long.
+const LAYER_TIMEOUT: Duration = Duration::from_secs(1);
+const VIDEO_TRACK: u64 = 1;
+const AUDIO_TRACK: u64 = 2;
+
+/// Recording of a published channel to a WebM file, or Matroska with H264, owned by the
+/// publisher task.
+///
+/// Packets are handed to a dedicated thread which depacketizes and writes them, so the media
+/// loop never waits on the disk. The file is finalized when the recording is dropped.
+pub struct Recording {
+    path: PathBuf,
+    sender: Sender<TrackMedia>,
+    metrics: Arc<Metrics>,
+}
+
+impl Recording {
+    pub fn start(dir: &Path, channel: &str, metrics: Arc<Metrics>) -> Recording {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        //the extension is set by the writer once the codecs are known
+        let path = dir.join(format!("{}-{millis}", file_name(channel)));
+        log::info!("Recording channel {channel} to {}", path.display());
+        let (sender, receiver) = crossbeam::channel::bounded(QUEUE_SIZE);
+        let writer = RecordingWriter::new(path.clone());
+        std::thread::spawn(move || writer.run(receiver));
+        Recording {
+            path,
+            sender,
+            metrics,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn push(&self, media: &TrackMedia) {
+        //a disconnected writer already logged its error
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(media.clone()) {
+            Metrics::inc(&self.metrics.recording_packets_dropped);
+        }
+    }
+}
+
+/// Keep channel names from escaping the recording directory.
+fn file_name(channel: &str) -> String {
+    channel
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Writer thread side of a [`Recording`].
+///
+/// The file is created on the first video keyframe, or after [`AUDIO_ONLY_WAIT`] of audio
+/// without any video. Only the highest simulcast layer is recorded.
+struct RecordingWriter {
+    path: PathBuf,
+    audio: Option<Depacketizer>,
+    video: Option<Depacketizer>,
+    /// Recorded simulcast layer and its last packet arrival.
+    layer: Option<(u8, Instant)>,
+    first_audio: Option<Instant>,
+    lip_sync: LipSync,
+    start: Option<Instant>,
+    webm: Option<WebmWriter<BufWriter<File>>>,
+    tracks: Vec<u64>,
+}
+
+impl RecordingWriter {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            audio: None,
+            video: None,
+            layer: None,
+            first_audio: None,
+            lip_sync: LipSync::default(),
+            start: None,
+            webm: None,
+            tracks: Vec::new(),
+        }
+    }
+
+    fn run(mut self, receiver: Receiver<TrackMedia>) {
+        //the sender is dropped when the recording stops or the publisher ends
+        while let Ok(media) = receiver.recv() {
+            if let Err(e) = self.on_media(media) {
+                log::error!("Recording {} failed: {e}", self.path.display());
+                return;
+            }
+        }
+        match self.webm.take().map(|webm| webm.finish()) {
+            Some(Ok(_)) => log::info!("Recording {} finalized", self.path.display()),
+            Some(Err(e)) => log::error!("Recording {} finalize failed: {e}", self.path.display()),
+            None => log::warn!("Recording {} stopped without media", self.path.display()),
+        }
+    }
+
+    fn on_media(&mut self, media: TrackMedia) -> io::Result<()> {
+        if codec_id(media.codec).is_none() {
+            return Ok(());
+        }
+        if media.codec.is_video() {
+            if !self.accept_layer(&media) {
+                return Ok(());
+            }
+            let video = self
+                .video
+                .get_or_insert_with(|| Depacketizer::new(media.codec));
+            if let Some(frame) = video.push(&media) {
+                self.write(VIDEO_TRACK, frame)?;
+            }
+        } else {
+            self.first_audio.get_or_insert(media.timestamp);
+            let audio = self
+                .audio
+                .get_or_insert_with(|| Depacketizer::new(media.codec));
+            if let Some(frame) = audio.push(&media) {
+                self.write(AUDIO_TRACK, frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Follow the highest simulcast layer, switching on keyframes.
+    fn accept_layer(&mut self, media: &TrackMedia) -> bool {
+        let index = match media.simulcast {
+            Some(layer) => layer.index,
+            None => return true,
+        };
+        let now = media.timestamp;
+        let switch = match &mut self.layer {
+            Some((current, last)) if *current == index => {
+                *last = now;
+                return true;
+            }
+            Some((current, last)) => index > *current || now - *last >= LAYER_TIMEOUT,
+            None => true,
+        };
+        if !switch || !is_keyframe_start(media.codec, &media.payload) {
+            return false;
+        }
+        log::info!(
+            "Recording {} switched to simulcast layer {index}",
+            self.path.display()
+        );
+        self.layer = Some((index, now));
+        if let Some(video) = &mut self.video {
+            video.reset();
+        }
+        true
+    }
+
+    fn write(&mut self, track: u64, frame: Frame) -> io::Result<()> {
+        if self.webm.is_none() {
+            let ready = if track == VIDEO_TRACK {
+                frame.keyframe && self.video.as_ref().is_some_and(|video| video.ready())
+            } else {
+                self.video.is_none()
+                    && self
+                        .first_audio
+                        .is_some_and(|first| frame.received - first >= AUDIO_ONLY_WAIT)
+            };
+            if !ready {
+                return Ok(());
+            }
+            self.open()?;
+        }
+        if !self.tracks.contains(&track) {
+            return Ok(());
+        }
+
+        let clock_rate = if track == VIDEO_TRACK { 90000 } else { 48000 };
+        let wallclock = self.lip_sync.wallclock(
+            frame.sender_report,
+            frame.rtp_time,
+            clock_rate,
+            frame.received,
+        );
+        let start = *self.start.get_or_insert(wallclock);
+        let time = wallclock.saturating_duration_since(start).as_millis() as u64;
+        if let Some(webm) = &mut self.webm {
+            webm.write_frame(track, time, frame.keyframe, &frame.data)?;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        //audio may start after the first video keyframe, Opus is the only audio codec
+        if self.video.is_some() && self.audio.is_none() {
+            self.audio = Some(Depacketizer::new(Codec::Opus));
+        }
+        let mut tracks = Vec::new();
+        for (number, depacketizer) in [(VIDEO_TRACK, &self.video), (AUDIO_TRACK, &self.audio)] {
+            if let Some(depacketizer) = depacketizer {
+                tracks.push(WebmTrack {
+                    number,
+                    codec: depacketizer.codec(),
+                    codec_private: depacketizer.codec_private(),
+                    dimensions: depacketizer.dimensions(),
+                });
+            }
+        }
+        self.path
+            .set_extension(if is_matroska(&tracks) { "mkv" } else { "webm" });
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = BufWriter::new(File::create(&self.path)?);
+        self.tracks = tracks.iter().map(|track| track.number).collect();
+        log::info!(
+            "Recording {} started with tracks {:?}",
+            self.path.display(),
+            self.tracks
+        );
+        self.webm = Some(WebmWriter::new(file, &tracks)?);
+        Ok(())
+    }
+}
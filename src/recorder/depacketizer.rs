@@ -0,0 +1,542 @@
+use std::time::Instant;
+
+use bytes::Bytes;
+use str0m::format::Codec;
+
+use crate::tasks::{
+    lip_sync::SenderReport,
+    payload::{is_keyframe_start, vp8_descriptor_len, vp9_descriptor_len},
+    TrackMedia,
+};
+
+/// A complete frame rebuilt from the RTP packets of one timestamp.
+pub struct Frame {
+    pub data: Vec<u8>,
+    pub keyframe: bool,
+    pub rtp_time: u32,
+    /// Arrival time of the first packet.
+    pub received: Instant,
+    pub sender_report: Option<SenderReport>,
+}
+
+struct PendingFrame {
+    rtp_time: u32,
+    received: Instant,
+    sender_report: Option<SenderReport>,
+    keyframe: bool,
+    packets: Vec<Bytes>,
+}
+
+/// Rebuild the frames of a track from its RTP packets, in the format stored in Matroska.
+///
+/// There is no jitter buffer: a lost or reordered video packet drops everything until the next
+/// keyframe, which the publisher sends periodically or on the viewers requests.
+pub struct Depacketizer {
+    codec: Codec,
+    pending: Option<PendingFrame>,
+    last_seq: Option<u64>,
+    waiting_keyframe: bool,
+    codec_private: Option<Vec<u8>>,
+    dimensions: Option<(u16, u16)>,
+}
+
+impl Depacketizer {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            pending: None,
+            last_seq: None,
+            waiting_keyframe: true,
+            codec_private: (codec == Codec::Opus).then(opus_head),
+            dimensions: None,
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Decoder configuration, taken from the last keyframe for H264.
+    pub fn codec_private(&self) -> Option<&[u8]> {
+        self.codec_private.as_deref()
+    }
+
+    pub fn dimensions(&self) -> Option<(u16, u16)> {
+        self.dimensions
+    }
+
+    /// The decoder configuration is known, when the codec needs one in the track header.
+    pub fn ready(&self) -> bool {
+        self.codec != Codec::H264 || self.codec_private.is_some()
+    }
+
+    /// Restart on the next keyframe, e.g. after a simulcast layer switch.
+    pub fn reset(&mut self) {
+        self.pending = None;
+        self.last_seq = None;
+        self.waiting_keyframe = true;
+    }
+
+    pub fn push(&mut self, media: &TrackMedia) -> Option<Frame> {
+        if !self.codec.is_video() {
+            return Some(Frame {
+                data: media.payload.to_vec(),
+                keyframe: true,
+                rtp_time: media.header.timestamp,
+                received: media.timestamp,
+                sender_report: media.sender_report,
+            });
+        }
+
+        let seq = *media.seq_no;
+        if self
+            .last_seq
+            .is_some_and(|last| seq != last.wrapping_add(1))
+        {
+            if !self.waiting_keyframe {
+                log::debug!("Depacketizer lost packets before {seq}, waiting for a keyframe");
+            }
+            self.pending = None;
+            self.waiting_keyframe = true;
+        }
+        self.last_seq = Some(seq);
+
+        //the marker of the previous frame was lost
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.rtp_time != media.header.timestamp)
+        {
+            self.pending = None;
+            self.waiting_keyframe = true;
+        }
+
+        if self.pending.is_none() {
+            let keyframe = is_keyframe_start(self.codec, &media.payload);
+            if self.waiting_keyframe && !keyframe {
+                return None;
+            }
+            self.waiting_keyframe = false;
+            self.pending = Some(PendingFrame {
+                rtp_time: media.header.timestamp,
+                received: media.timestamp,
+                sender_report: media.sender_report,
+                keyframe,
+                packets: Vec::new(),
+            });
+        }
+        let pending = self.pending.as_mut()?;
+        pending.packets.push(media.payload.clone());
+        if !media.header.marker {
+            return None;
+        }
+
+        let pending = self.pending.take()?;
+        let data = match self.codec {
+            Codec::Vp8 => {
+                let data = vp8_frame(&pending.packets)?;
+                if pending.keyframe {
+                    self.dimensions = vp8_dimensions(&data).or(self.dimensions);
+                }
+                data
+            }
+            Codec::Vp9 => {
+                let frames = vp9_frames(&pending.packets)?;
+                if pending.keyframe {
+                    //the last frame is the highest spatial layer
+                    self.dimensions = frames
+                        .last()
+                        .and_then(|frame| vp9_dimensions(frame))
+                        .or(self.dimensions);
+                }
+                vp9_superframe(frames)
+            }
+            Codec::H264 => {
+                let data = h264_frame(&pending.packets)?;
+                if pending.keyframe {
+                    self.codec_private = h264_avcc(&data).or(self.codec_private.take());
+                }
+                data
+            }
+            _ => return None,
+        };
+        Some(Frame {
+            data,
+            keyframe: pending.keyframe,
+            rtp_time: pending.rtp_time,
+            received: pending.received,
+            sender_report: pending.sender_report,
+        })
+    }
+}
+
+/// Opus identification header, stored as codec private data.
+fn opus_head() -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    //version, channels
+    head.extend_from_slice(&[1, 2]);
+    //pre-skip
+    head.extend_from_slice(&0u16.to_le_bytes());
+    head.extend_from_slice(&48000u32.to_le_bytes());
+    //output gain, mapping family
+    head.extend_from_slice(&[0, 0, 0]);
+    head
+}
+
+fn vp8_frame(packets: &[Bytes]) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    for packet in packets {
+        data.extend_from_slice(&packet[vp8_descriptor_len(packet)?..]);
+    }
+    Some(data)
+}
+
+fn vp8_dimensions(frame: &[u8]) -> Option<(u16, u16)> {
+    //3 bytes frame tag, 3 bytes start code, then 14 bits width and height
+    if frame.get(3..6)? != [0x9d, 0x01, 0x2a] {
+        return None;
+    }
+    let width = u16::from_le_bytes([*frame.get(6)?, *frame.get(7)?]) & 0x3fff;
+    let height = u16::from_le_bytes([*frame.get(8)?, *frame.get(9)?]) & 0x3fff;
+    Some((width, height))
+}
+
+/// Frames of a VP9 picture, one per spatial layer.
+fn vp9_frames(packets: &[Bytes]) -> Option<Vec<Vec<u8>>> {
+    let mut frames: Vec<Vec<u8>> = Vec::new();
+    for packet in packets {
+        let offset = vp9_descriptor_len(packet)?;
+        //B bit: beginning of a frame
+        if packet[0] & 0x08 != 0 || frames.is_empty() {
+            frames.push(Vec::new());
+        }
+        frames.last_mut()?.extend_from_slice(&packet[offset..]);
+    }
+    Some(frames)
+}
+
+/// Join the frames of a spatial SVC picture in a superframe, see VP9 spec Annex B.
+fn vp9_superframe(mut frames: Vec<Vec<u8>>) -> Vec<u8> {
+    if frames.len() == 1 {
+        return frames.remove(0);
+    }
+    let max_size = frames.iter().map(|frame| frame.len()).max().unwrap_or(0);
+    let size_bytes = match max_size {
+        0..=0xff => 1,
+        0x100..=0xffff => 2,
+        0x10000..=0xffffff => 3,
+        _ => 4,
+    };
+    let marker = 0xc0 | ((size_bytes - 1) << 3) as u8 | (frames.len() - 1) as u8;
+    let mut data = frames.concat();
+    data.push(marker);
+    for frame in &frames {
+        data.extend_from_slice(&(frame.len() as u32).to_le_bytes()[..size_bytes]);
+    }
+    data.push(marker);
+    data
+}
+
+fn vp9_dimensions(frame: &[u8]) -> Option<(u16, u16)> {
+    let mut reader = BitReader::new(frame);
+    if reader.read(2)? != 2 {
+        return None;
+    }
+    let profile_low = reader.read(1)?;
+    let profile = (reader.read(1)? << 1) | profile_low;
+    if profile == 3 {
+        reader.read(1)?;
+    }
+    //show_existing_frame, then frame_type 0 is a keyframe
+    if reader.read(1)? != 0 || reader.read(1)? != 0 {
+        return None;
+    }
+    //show_frame, error_resilient_mode
+    reader.read(2)?;
+    if reader.read(24)? != 0x498342 {
+        return None;
+    }
+    if profile >= 2 {
+        reader.read(1)?;
+    }
+    let color_space = reader.read(3)?;
+    if color_space != 7 {
+        reader.read(1)?;
+        if profile == 1 || profile == 3 {
+            reader.read(3)?;
+        }
+    } else if profile == 1 || profile == 3 {
+        reader.read(1)?;
+    }
+    let width = reader.read(16)? + 1;
+    let height = reader.read(16)? + 1;
+    Some((width as u16, height as u16))
+}
+
+/// Rebuild the NAL units of an H264 frame, 4 bytes length prefixed as Matroska expects.
+fn h264_frame(packets: &[Bytes]) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut fragment: Option<Vec<u8>> = None;
+    for packet in packets {
+        match packet.first()? & 0x1f {
+            1..=23 => push_nalu(&mut data, packet),
+            //STAP-A
+            24 => {
+                let mut offset = 1;
+                while offset + 2 <= packet.len() {
+                    let size = u16::from_be_bytes([packet[offset], packet[offset + 1]]) as usize;
+                    push_nalu(&mut data, packet.get(offset + 2..offset + 2 + size)?);
+                    offset += 2 + size;
+                }
+            }
+            //FU-A, the NAL header is rebuilt from the FU indicator and header
+            28 => {
+                let header = *packet.get(1)?;
+                if header & 0x80 != 0 {
+                    fragment = Some(vec![(packet[0] & 0xe0) | (header & 0x1f)]);
+                }
+                if let Some(nalu) = &mut fragment {
+                    nalu.extend_from_slice(&packet[2..]);
+                }
+                if header & 0x40 != 0 {
+                    if let Some(nalu) = fragment.take() {
+                        push_nalu(&mut data, &nalu);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(data)
+}
+
+fn push_nalu(data: &mut Vec<u8>, nalu: &[u8]) {
+    data.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+    data.extend_from_slice(nalu);
+}
+
+/// AVCDecoderConfigurationRecord from the SPS and PPS of a keyframe.
+fn h264_avcc(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut sps = None;
+    let mut pps = None;
+    let mut offset = 0;
+    while offset + 4 <= frame.len() {
+        let size = u32::from_be_bytes(frame[offset..offset + 4].try_into().ok()?) as usize;
+        let nalu = frame.get(offset + 4..offset + 4 + size)?;
+        match nalu.first().map(|header| header & 0x1f) {
+            Some(7) => sps = Some(nalu),
+            Some(8) => pps = Some(nalu),
+            _ => {}
+        }
+        offset += 4 + size;
+    }
+    let (sps, pps) = (sps?, pps?);
+    //version, profile, compatibility, level, 4 bytes lengths, 1 SPS
+    let mut avcc = vec![1, *sps.get(1)?, *sps.get(2)?, *sps.get(3)?, 0xff, 0xe1];
+    avcc.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(sps);
+    avcc.push(1);
+    avcc.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(pps);
+    Some(avcc)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    /// Read up to 32 bits, most significant first.
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..bits {
+            let byte = *self.data.get(self.position / 8)?;
+            let bit = (byte >> (7 - self.position % 8)) & 0x01;
+            value = (value << 1) | bit as u32;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use bytes::Bytes;
+    use str0m::{format::Codec, media::MediaTime, rtp::RtpHeader};
+
+    use crate::tasks::TrackMedia;
+
+    use super::Depacketizer;
+
+    fn media(codec: Codec, seq: u64, rtp_time: u32, marker: bool, payload: &[u8]) -> TrackMedia {
+        TrackMedia {
+            track_id: 1,
+            codec,
+            simulcast: None,
+            seq_no: seq.into(),
+            time: MediaTime::new(rtp_time as i64, 90000),
+            header: RtpHeader {
+                marker,
+                sequence_number: seq as u16,
+                timestamp: rtp_time,
+                ..Default::default()
+            },
+            payload: Bytes::copy_from_slice(payload),
+            timestamp: Instant::now(),
+            sender_report: None,
+        }
+    }
+
+    /// VP8 keyframe of 320x240: frame tag, start code, then width and height.
+    const VP8_KEYFRAME: [u8; 10] = [0x00, 0x00, 0x00, 0x9d, 0x01, 0x2a, 0x40, 0x01, 0xf0, 0x00];
+
+    #[test]
+    fn vp8_frame_from_packets() {
+        let mut depacketizer = Depacketizer::new(Codec::Vp8);
+        assert!(depacketizer.ready());
+        let first = [&[0x10], &VP8_KEYFRAME[..5]].concat();
+        let second = [&[0x00], &VP8_KEYFRAME[5..]].concat();
+        assert!(depacketizer
+            .push(&media(Codec::Vp8, 1, 3000, false, &first))
+            .is_none());
+        let frame = depacketizer
+            .push(&media(Codec::Vp8, 2, 3000, true, &second))
+            .expect("Should have a frame");
+        assert_eq!(frame.data, VP8_KEYFRAME);
+        assert!(frame.keyframe);
+        assert_eq!(frame.rtp_time, 3000);
+        assert_eq!(depacketizer.dimensions(), Some((320, 240)));
+    }
+
+    #[test]
+    fn wait_for_keyframe() {
+        let mut depacketizer = Depacketizer::new(Codec::Vp8);
+        //P bit set: interframe
+        assert!(depacketizer
+            .push(&media(Codec::Vp8, 1, 3000, true, &[0x10, 0x01, 0xaa]))
+            .is_none());
+        assert!(depacketizer
+            .push(&media(Codec::Vp8, 2, 6000, true, &[0x10, 0x00, 0xbb]))
+            .is_some());
+        let frame = depacketizer
+            .push(&media(Codec::Vp8, 3, 9000, true, &[0x10, 0x01, 0xcc]))
+            .expect("Should have a frame");
+        assert!(!frame.keyframe);
+        assert_eq!(frame.data, [0x01, 0xcc]);
+    }
+
+    #[test]
+    fn loss_waits_for_next_keyframe() {
+        let mut depacketizer = Depacketizer::new(Codec::Vp8);
+        assert!(depacketizer
+            .push(&media(Codec::Vp8, 1, 3000, true, &[0x10, 0x00, 0xaa]))
+            .is_some());
+        //seq 2 is lost
+        assert!(depacketizer
+            .push(&media(Codec::Vp8, 3, 6000, true, &[0x10, 0x01, 0xbb]))
+            .is_none());
+        assert!(depacketizer
+            .push(&media(Codec::Vp8, 4, 9000, true, &[0x10, 0x01, 0xcc]))
+            .is_none());
+        assert!(depacketizer
+            .push(&media(Codec::Vp8, 5, 12000, true, &[0x10, 0x00, 0xdd]))
+            .is_some());
+    }
+
+    #[test]
+    fn lost_marker_drops_frame() {
+        let mut depacketizer = Depacketizer::new(Codec::Vp8);
+        assert!(depacketizer
+            .push(&media(Codec::Vp8, 1, 3000, false, &[0x10, 0x00, 0xaa]))
+            .is_none());
+        //next timestamp without the marker of the previous frame, and not a keyframe
+        assert!(depacketizer
+            .push(&media(Codec::Vp8, 2, 6000, true, &[0x10, 0x01, 0xbb]))
+            .is_none());
+    }
+
+    #[test]
+    fn h264_frame_and_avcc() {
+        let sps = [0x67, 0x42, 0x00, 0x1f];
+        let pps = [0x68, 0xce];
+        let mut depacketizer = Depacketizer::new(Codec::H264);
+        assert!(!depacketizer.ready());
+
+        //STAP-A with SPS and PPS, then an IDR in two FU-A
+        let stap_a = [&[0x78, 0x00, 0x04][..], &sps, &[0x00, 0x02], &pps].concat();
+        assert!(depacketizer
+            .push(&media(Codec::H264, 1, 3000, false, &stap_a))
+            .is_none());
+        assert!(depacketizer
+            .push(&media(
+                Codec::H264,
+                2,
+                3000,
+                false,
+                &[0x7c, 0x85, 0x88, 0x84]
+            ))
+            .is_none());
+        let frame = depacketizer
+            .push(&media(
+                Codec::H264,
+                3,
+                3000,
+                true,
+                &[0x7c, 0x45, 0x21, 0xa0],
+            ))
+            .expect("Should have a frame");
+
+        let expected = [
+            &[0, 0, 0, 4][..],
+            &sps,
+            &[0, 0, 0, 2],
+            &pps,
+            &[0, 0, 0, 5, 0x65, 0x88, 0x84, 0x21, 0xa0],
+        ]
+        .concat();
+        assert_eq!(frame.data, expected);
+        assert!(frame.keyframe);
+        assert!(depacketizer.ready());
+        let avcc = [
+            &[1, 0x42, 0x00, 0x1f, 0xff, 0xe1, 0x00, 0x04][..],
+            &sps,
+            &[1, 0x00, 0x02],
+            &pps,
+        ]
+        .concat();
+        assert_eq!(depacketizer.codec_private(), Some(avcc.as_slice()));
+    }
+
+    #[test]
+    fn vp9_spatial_layers_in_superframe() {
+        let mut depacketizer = Depacketizer::new(Codec::Vp9);
+        //B and E bits: each packet is a whole frame of one spatial layer
+        assert!(depacketizer
+            .push(&media(Codec::Vp9, 1, 3000, false, &[0x0c, 0xaa, 0xbb]))
+            .is_none());
+        let frame = depacketizer
+            .push(&media(Codec::Vp9, 2, 3000, true, &[0x0c, 0xcc]))
+            .expect("Should have a frame");
+        assert_eq!(frame.data, [0xaa, 0xbb, 0xcc, 0xc1, 0x02, 0x01, 0xc1]);
+    }
+
+    #[test]
+    fn audio_passthrough() {
+        let mut depacketizer = Depacketizer::new(Codec::Opus);
+        assert!(depacketizer
+            .codec_private()
+            .is_some_and(|head| head.starts_with(b"OpusHead")));
+        let frame = depacketizer
+            .push(&media(Codec::Opus, 1, 960, false, &[0xfc, 0x01]))
+            .expect("Should have a frame");
+        assert_eq!(frame.data, [0xfc, 0x01]);
+        assert!(frame.keyframe);
+    }
+}
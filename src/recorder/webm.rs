@@ -0,0 +1,542 @@
+use std::{
+    collections::HashMap,
+    io::{self, Seek, SeekFrom, Write},
+};
+
+use str0m::format::Codec;
+
+const EBML: u32 = 0x1a45dfa3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42f7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42f2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42f3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const VOID: u32 = 0xec;
+const SEGMENT: u32 = 0x18538067;
+const SEEK_HEAD: u32 = 0x114d9b74;
+const SEEK: u32 = 0x4dbb;
+const SEEK_ID: u32 = 0x53ab;
+const SEEK_POSITION: u32 = 0x53ac;
+const INFO: u32 = 0x1549a966;
+const TIMESTAMP_SCALE: u32 = 0x2ad7b1;
+const DURATION: u32 = 0x4489;
+const MUXING_APP: u32 = 0x4d80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654ae6b;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_NUMBER: u32 = 0xd7;
+const TRACK_UID: u32 = 0x73c5;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63a2;
+const SEEK_PRE_ROLL: u32 = 0x56bb;
+const VIDEO: u32 = 0xe0;
+const PIXEL_WIDTH: u32 = 0xb0;
+const PIXEL_HEIGHT: u32 = 0xba;
+const AUDIO: u32 = 0xe1;
+const SAMPLING_FREQUENCY: u32 = 0xb5;
+const CHANNELS: u32 = 0x9f;
+const CLUSTER: u32 = 0x1f43b675;
+const TIMESTAMP: u32 = 0xe7;
+const SIMPLE_BLOCK: u32 = 0xa3;
+const CUES: u32 = 0x1c53bb6b;
+const CUE_POINT: u32 = 0xbb;
+const CUE_TIME: u32 = 0xb3;
+const CUE_TRACK_POSITIONS: u32 = 0xb7;
+const CUE_TRACK: u32 = 0xf7;
+const CUE_CLUSTER_POSITION: u32 = 0xf1;
+
+/// 8 bytes element size which is patched when the element is closed.
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+/// Size of a seek head entry with an 8 bytes position.
+const SEEK_ENTRY_LEN: usize = 21;
+/// Keyframes closer than this to the cluster start stay in the same cluster.
+const MIN_CLUSTER_MS: u64 = 1000;
+
+/// Matroska codec id of a codec, None if it can't be recorded.
+pub fn codec_id(codec: Codec) -> Option<&'static str> {
+    match codec {
+        Codec::Opus => Some("A_OPUS"),
+        Codec::Vp8 => Some("V_VP8"),
+        Codec::Vp9 => Some("V_VP9"),
+        Codec::H264 => Some("V_MPEG4/ISO/AVC"),
+        _ => None,
+    }
+}
+
+/// Whether a file with these tracks must be written as Matroska: WebM only allows VP8, VP9,
+/// AV1, Opus and Vorbis, so H264 files are plain Matroska (`.mkv`).
+pub fn is_matroska(tracks: &[WebmTrack]) -> bool {
+    tracks.iter().any(|track| track.codec == Codec::H264)
+}
+
+pub struct WebmTrack<'a> {
+    pub number: u64,
+    pub codec: Codec,
+    pub codec_private: Option<&'a [u8]>,
+    /// Pixel size of a video track, when it could be read from the first keyframe.
+    pub dimensions: Option<(u16, u16)>,
+}
+
+struct Cluster {
+    size_pos: u64,
+    time: u64,
+}
+
+/// Minimal WebM muxer writing millisecond timestamped frames, with the Matroska DocType when
+/// [`is_matroska`].
+///
+/// A cluster starts on every keyframe of the first track, which gets a cue point. Element
+/// sizes, duration and cues are written by [`WebmWriter::finish`]; until then the file is
+/// still readable as a live stream with unknown sizes.
+pub struct WebmWriter<W: Write + Seek> {
+    out: W,
+    segment_size_pos: u64,
+    segment_data_start: u64,
+    duration_pos: u64,
+    /// Position of the Cues entry of the seek head, replaced by a void element without cues.
+    cues_seek_pos: u64,
+    cue_track: u64,
+    cluster: Option<Cluster>,
+    /// (time, cluster position in the segment)
+    cues: Vec<(u64, u64)>,
+    last_times: HashMap<u64, u64>,
+    duration: u64,
+}
+
+impl<W: Write + Seek> WebmWriter<W> {
+    pub fn new(mut out: W, tracks: &[WebmTrack]) -> io::Result<Self> {
+        let mut ebml = Vec::new();
+        put_uint(&mut ebml, EBML_VERSION, 1);
+        put_uint(&mut ebml, EBML_READ_VERSION, 1);
+        put_uint(&mut ebml, EBML_MAX_ID_LENGTH, 4);
+        put_uint(&mut ebml, EBML_MAX_SIZE_LENGTH, 8);
+        let doc_type: &[u8] = if is_matroska(tracks) {
+            b"matroska"
+        } else {
+            b"webm"
+        };
+        put_element(&mut ebml, DOC_TYPE, doc_type);
+        put_uint(&mut ebml, DOC_TYPE_VERSION, 4);
+        put_uint(&mut ebml, DOC_TYPE_READ_VERSION, 2);
+        let mut header = Vec::new();
+        put_element(&mut header, EBML, &ebml);
+        header.extend_from_slice(&id_bytes(SEGMENT));
+        out.write_all(&header)?;
+        let segment_size_pos = out.stream_position()?;
+        out.write_all(&UNKNOWN_SIZE)?;
+        let segment_data_start = out.stream_position()?;
+
+        //duration first so its position is known
+        let mut info_body = Vec::new();
+        put_float(&mut info_body, DURATION, 0.0);
+        put_uint(&mut info_body, TIMESTAMP_SCALE, 1_000_000);
+        put_element(&mut info_body, MUXING_APP, b"tiny-media-server");
+        put_element(&mut info_body, WRITING_APP, b"tiny-media-server");
+        let mut info = Vec::new();
+        put_element(&mut info, INFO, &info_body);
+        let duration_offset = info.len() - info_body.len() + 3;
+
+        let mut tracks_body = Vec::new();
+        for track in tracks {
+            put_element(&mut tracks_body, TRACK_ENTRY, &track_entry(track));
+        }
+        let mut tracks_element = Vec::new();
+        put_element(&mut tracks_element, TRACKS, &tracks_body);
+
+        //seek positions are 8 bytes so that the seek head size doesn't depend on them
+        let seek = |id: u32, position: u64| {
+            let mut body = Vec::new();
+            put_element(&mut body, SEEK_ID, &id_bytes(id));
+            put_element(&mut body, SEEK_POSITION, &position.to_be_bytes());
+            let mut seek = Vec::new();
+            put_element(&mut seek, SEEK, &body);
+            seek
+        };
+        let mut seek_head = Vec::new();
+        put_header(&mut seek_head, SEEK_HEAD, 3 * SEEK_ENTRY_LEN as u64);
+        let info_position = (seek_head.len() + 3 * SEEK_ENTRY_LEN) as u64;
+        seek_head.extend(seek(INFO, info_position));
+        seek_head.extend(seek(TRACKS, info_position + info.len() as u64));
+        let cues_seek_pos = segment_data_start + seek_head.len() as u64;
+        seek_head.extend(seek(CUES, 0));
+        let duration_pos = segment_data_start + info_position + duration_offset as u64;
+        out.write_all(&seek_head)?;
+        out.write_all(&info)?;
+        out.write_all(&tracks_element)?;
+
+        Ok(Self {
+            out,
+            segment_size_pos,
+            segment_data_start,
+            duration_pos,
+            cues_seek_pos,
+            cue_track: tracks.first().map_or(1, |track| track.number),
+            cluster: None,
+            cues: Vec::new(),
+            last_times: HashMap::new(),
+            duration: 0,
+        })
+    }
+
+    /// Write a frame of a track, `time` is in milliseconds from the start of the file.
+    pub fn write_frame(
+        &mut self,
+        track: u64,
+        time: u64,
+        keyframe: bool,
+        data: &[u8],
+    ) -> io::Result<()> {
+        //blocks of a track must not go back in time
+        let last_time = self.last_times.entry(track).or_insert(time);
+        let time = time.max(*last_time);
+        *last_time = time;
+
+        let cue = keyframe && track == self.cue_track;
+        let start_cluster = match &self.cluster {
+            None => true,
+            Some(cluster) => {
+                time > cluster.time + i16::MAX as u64
+                    || (cue && time >= cluster.time + MIN_CLUSTER_MS)
+            }
+        };
+        if start_cluster {
+            self.start_cluster(time, cue)?;
+        }
+
+        let cluster_time = self.cluster.as_ref().map_or(time, |cluster| cluster.time);
+        let relative =
+            (time as i64 - cluster_time as i64).clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+        let mut header = Vec::new();
+        put_header(&mut header, SIMPLE_BLOCK, data.len() as u64 + 4);
+        //track number as a 1 byte size, relative time, flags
+        header.push(0x80 | track as u8);
+        header.extend_from_slice(&relative.to_be_bytes());
+        header.push(if keyframe { 0x80 } else { 0 });
+        self.out.write_all(&header)?;
+        self.out.write_all(data)?;
+        self.duration = self.duration.max(time);
+        Ok(())
+    }
+
+    /// Write the cues and patch the sizes and duration.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.close_cluster()?;
+        let cues_position = self.out.stream_position()? - self.segment_data_start;
+        if self.cues.is_empty() {
+            //void element of the same size as the Cues seek entry
+            let mut void = Vec::new();
+            put_element(&mut void, VOID, &[0; SEEK_ENTRY_LEN - 2]);
+            self.out.seek(SeekFrom::Start(self.cues_seek_pos))?;
+            self.out.write_all(&void)?;
+        } else {
+            let mut cues_body = Vec::new();
+            for (time, position) in &self.cues {
+                let mut positions = Vec::new();
+                put_uint(&mut positions, CUE_TRACK, self.cue_track);
+                put_uint(&mut positions, CUE_CLUSTER_POSITION, *position);
+                let mut point = Vec::new();
+                put_uint(&mut point, CUE_TIME, *time);
+                put_element(&mut point, CUE_TRACK_POSITIONS, &positions);
+                put_element(&mut cues_body, CUE_POINT, &point);
+            }
+            let mut cues = Vec::new();
+            put_element(&mut cues, CUES, &cues_body);
+            self.out.write_all(&cues)?;
+            //the position is the last 8 bytes of the seek entry
+            self.out.seek(SeekFrom::Start(
+                self.cues_seek_pos + SEEK_ENTRY_LEN as u64 - 8,
+            ))?;
+            self.out.write_all(&cues_position.to_be_bytes())?;
+        }
+
+        self.out.seek(SeekFrom::Start(self.duration_pos))?;
+        self.out.write_all(&(self.duration as f64).to_be_bytes())?;
+        let end = self.out.seek(SeekFrom::End(0))?;
+        self.patch_size(self.segment_size_pos, end - self.segment_data_start)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn start_cluster(&mut self, time: u64, cue: bool) -> io::Result<()> {
+        self.close_cluster()?;
+        let position = self.out.stream_position()?;
+        self.out.write_all(&id_bytes(CLUSTER))?;
+        let size_pos = self.out.stream_position()?;
+        self.out.write_all(&UNKNOWN_SIZE)?;
+        let mut timestamp = Vec::new();
+        put_uint(&mut timestamp, TIMESTAMP, time);
+        self.out.write_all(&timestamp)?;
+        if cue {
+            self.cues.push((time, position - self.segment_data_start));
+        }
+        self.cluster = Some(Cluster { size_pos, time });
+        Ok(())
+    }
+
+    fn close_cluster(&mut self) -> io::Result<()> {
+        if let Some(cluster) = self.cluster.take() {
+            let end = self.out.stream_position()?;
+            self.patch_size(cluster.size_pos, end - cluster.size_pos - 8)?;
+        }
+        Ok(())
+    }
+
+    /// Replace an unknown size with the real one and go back to the end of the file.
+    fn patch_size(&mut self, size_pos: u64, size: u64) -> io::Result<()> {
+        let mut bytes = size.to_be_bytes();
+        bytes[0] = 0x01;
+        self.out.seek(SeekFrom::Start(size_pos))?;
+        self.out.write_all(&bytes)?;
+        self.out.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+fn track_entry(track: &WebmTrack) -> Vec<u8> {
+    let mut entry = Vec::new();
+    put_uint(&mut entry, TRACK_NUMBER, track.number);
+    put_uint(&mut entry, TRACK_UID, track.number);
+    put_uint(
+        &mut entry,
+        TRACK_TYPE,
+        if track.codec.is_video() { 1 } else { 2 },
+    );
+    put_element(
+        &mut entry,
+        CODEC_ID,
+        codec_id(track.codec).unwrap_or_default().as_bytes(),
+    );
+    if let Some(codec_private) = track.codec_private {
+        put_element(&mut entry, CODEC_PRIVATE, codec_private);
+    }
+    if track.codec.is_video() {
+        if let Some((width, height)) = track.dimensions {
+            let mut video = Vec::new();
+            put_uint(&mut video, PIXEL_WIDTH, width as u64);
+            put_uint(&mut video, PIXEL_HEIGHT, height as u64);
+            put_element(&mut entry, VIDEO, &video);
+        }
+    } else {
+        //Opus needs 80ms of pre-roll after a seek
+        put_uint(&mut entry, SEEK_PRE_ROLL, 80_000_000);
+        let mut audio = Vec::new();
+        put_float(&mut audio, SAMPLING_FREQUENCY, 48000.0);
+        put_uint(&mut audio, CHANNELS, 2);
+        put_element(&mut entry, AUDIO, &audio);
+    }
+    entry
+}
+
+fn id_bytes(id: u32) -> Vec<u8> {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+fn put_header(out: &mut Vec<u8>, id: u32, size: u64) {
+    out.extend_from_slice(&id_bytes(id));
+    //shortest size encoding, all ones is reserved for unknown
+    let len = (1..8).find(|len| size < (1 << (7 * len)) - 1).unwrap_or(8);
+    let marked = size | (1 << (7 * len));
+    out.extend_from_slice(&marked.to_be_bytes()[8 - len..]);
+}
+
+fn put_element(out: &mut Vec<u8>, id: u32, body: &[u8]) {
+    put_header(out, id, body.len() as u64);
+    out.extend_from_slice(body);
+}
+
+fn put_uint(out: &mut Vec<u8>, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count().min(7);
+    put_element(out, id, &bytes[skip..]);
+}
+
+fn put_float(out: &mut Vec<u8>, id: u32, value: f64) {
+    put_element(out, id, &value.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use str0m::format::Codec;
+
+    use super::*;
+
+    /// Read an EBML variable size integer, with or without its length marker.
+    fn vint(data: &[u8], keep_marker: bool) -> (u64, usize) {
+        let len = data[0].leading_zeros() as usize + 1;
+        let mut value = data[0] as u64;
+        if !keep_marker {
+            value &= 0xff >> len;
+        }
+        for byte in &data[1..len] {
+            value = (value << 8) | *byte as u64;
+        }
+        (value, len)
+    }
+
+    /// Top level (id, body) of the elements in `data`.
+    fn elements(mut data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut elements = Vec::new();
+        while !data.is_empty() {
+            let (id, id_len) = vint(data, true);
+            let (size, size_len) = vint(&data[id_len..], false);
+            let start = id_len + size_len;
+            let end = start + size as usize;
+            elements.push((id as u32, &data[start..end]));
+            data = &data[end..];
+        }
+        elements
+    }
+
+    fn child(body: &[u8], id: u32) -> &[u8] {
+        elements(body)
+            .into_iter()
+            .find(|(child, _)| *child == id)
+            .map(|(_, body)| body)
+            .expect("Should have the element")
+    }
+
+    fn ids(body: &[u8]) -> Vec<u32> {
+        elements(body).into_iter().map(|(id, _)| id).collect()
+    }
+
+    fn tracks() -> [WebmTrack<'static>; 2] {
+        [
+            WebmTrack {
+                number: 1,
+                codec: Codec::Vp8,
+                codec_private: None,
+                dimensions: Some((320, 240)),
+            },
+            WebmTrack {
+                number: 2,
+                codec: Codec::Opus,
+                codec_private: Some(b"OpusHead"),
+                dimensions: None,
+            },
+        ]
+    }
+
+    fn finished(write: impl FnOnce(&mut WebmWriter<Cursor<Vec<u8>>>)) -> Vec<u8> {
+        let mut writer = WebmWriter::new(Cursor::new(Vec::new()), &tracks()).unwrap();
+        write(&mut writer);
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn sizes_and_duration_are_patched() {
+        let file = finished(|writer| {
+            writer.write_frame(1, 0, true, &[0xaa; 10]).unwrap();
+            writer.write_frame(2, 20, true, &[0xbb; 4]).unwrap();
+            writer.write_frame(1, 40, false, &[0xcc; 6]).unwrap();
+        });
+        let top = elements(&file);
+        assert_eq!(
+            top.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            [EBML, SEGMENT]
+        );
+        assert_eq!(child(top[0].1, DOC_TYPE), b"webm");
+
+        let segment = top[1].1;
+        assert_eq!(ids(segment), [SEEK_HEAD, INFO, TRACKS, CLUSTER, CUES]);
+        let duration = child(child(segment, INFO), DURATION);
+        assert_eq!(f64::from_be_bytes(duration.try_into().unwrap()), 40.0);
+
+        let cluster = child(segment, CLUSTER);
+        assert_eq!(
+            ids(cluster),
+            [TIMESTAMP, SIMPLE_BLOCK, SIMPLE_BLOCK, SIMPLE_BLOCK]
+        );
+        //track 2, 20ms after the cluster, keyframe
+        let blocks = elements(cluster);
+        assert_eq!(blocks[2].1[..4], [0x82, 0x00, 20, 0x80]);
+        assert_eq!(blocks[2].1[4..], [0xbb; 4]);
+    }
+
+    #[test]
+    fn h264_is_matroska() {
+        let h264 = [WebmTrack {
+            number: 1,
+            codec: Codec::H264,
+            codec_private: Some(&[0x01, 0x42, 0xc0, 0x1f]),
+            dimensions: Some((640, 360)),
+        }];
+        assert!(is_matroska(&h264));
+        assert!(!is_matroska(&tracks()));
+        let file = WebmWriter::new(Cursor::new(Vec::new()), &h264)
+            .unwrap()
+            .finish()
+            .unwrap()
+            .into_inner();
+        let top = elements(&file);
+        assert_eq!(child(top[0].1, DOC_TYPE), b"matroska");
+        let video = elements(child(top[1].1, TRACKS))[0].1;
+        assert_eq!(child(video, CODEC_ID), b"V_MPEG4/ISO/AVC");
+    }
+
+    #[test]
+    fn track_entries() {
+        let file = finished(|_| {});
+        let segment = elements(&file)[1].1;
+        let entries = elements(child(segment, TRACKS));
+        assert_eq!(entries.len(), 2);
+        let video = entries[0].1;
+        assert_eq!(child(video, CODEC_ID), b"V_VP8");
+        assert_eq!(child(child(video, VIDEO), PIXEL_WIDTH), [0x01, 0x40]);
+        let audio = entries[1].1;
+        assert_eq!(child(audio, CODEC_ID), b"A_OPUS");
+        assert_eq!(child(audio, CODEC_PRIVATE), b"OpusHead");
+    }
+
+    #[test]
+    fn cluster_and_cue_per_keyframe() {
+        let file = finished(|writer| {
+            writer.write_frame(1, 0, true, &[0xaa]).unwrap();
+            //too close to the cluster start
+            writer.write_frame(1, 500, true, &[0xaa]).unwrap();
+            writer.write_frame(1, 1500, true, &[0xaa]).unwrap();
+            //audio keyframes don't start clusters
+            writer.write_frame(2, 3000, true, &[0xbb]).unwrap();
+        });
+        let segment = elements(&file)[1].1;
+        assert_eq!(
+            ids(segment),
+            [SEEK_HEAD, INFO, TRACKS, CLUSTER, CLUSTER, CUES]
+        );
+        let cues = elements(child(segment, CUES));
+        assert_eq!(cues.len(), 2);
+        assert_eq!(child(cues[1].1, CUE_TIME), [0x05, 0xdc]);
+
+        //the seek head points to the cues
+        let seek_head = elements(child(segment, SEEK_HEAD));
+        let position = child(seek_head[2].1, SEEK_POSITION);
+        let position = u64::from_be_bytes(position.try_into().unwrap()) as usize;
+        let (id, _) = vint(&segment[position..], true);
+        assert_eq!(id as u32, CUES);
+    }
+
+    #[test]
+    fn no_cues_without_frames() {
+        let file = finished(|_| {});
+        let segment = elements(&file)[1].1;
+        assert_eq!(ids(segment), [SEEK_HEAD, INFO, TRACKS]);
+        assert_eq!(ids(child(segment, SEEK_HEAD)), [SEEK, SEEK, VOID]);
+    }
+
+    #[test]
+    fn block_times_never_go_back() {
+        let file = finished(|writer| {
+            writer.write_frame(1, 100, true, &[0xaa]).unwrap();
+            writer.write_frame(1, 50, false, &[0xaa]).unwrap();
+        });
+        let segment = elements(&file)[1].1;
+        let blocks = elements(child(segment, CLUSTER));
+        assert_eq!(blocks[2].1[1..3], [0x00, 0x00]);
+    }
+}
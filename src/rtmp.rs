@@ -0,0 +1,416 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, BufWriter},
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam::channel::{Receiver, Sender};
+use str0m::{format::Codec, media::MediaKind};
+
+use crate::io::HttpRequest;
+
+use self::{
+    amf0::Amf0Value,
+    chunk::{ChunkReader, ChunkWriter, Message},
+    flv::FlvDemuxer,
+};
+
+mod amf0;
+mod chunk;
+mod flv;
+
+/// Frames queued for the publisher task, the connection stops reading when it is full.
+const QUEUE_SIZE: usize = 1024;
+/// Publish requests waiting for a worker.
+const PUBLISH_QUEUE_SIZE: usize = 64;
+/// Max wait for the answer of the worker to a publish request.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+/// A connection which sends nothing for this long is closed.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const HANDSHAKE_SIZE: usize = 1536;
+/// Window and peer bandwidth announced to clients.
+const WINDOW_SIZE: u32 = 2_500_000;
+const CHUNK_SIZE: usize = 4096;
+/// Chunk stream of the protocol control messages and of the command messages.
+const CONTROL_CSID: u8 = 2;
+const COMMAND_CSID: u8 = 3;
+/// Message stream created for the publisher by `createStream`.
+const PUBLISH_STREAM_ID: u32 = 1;
+
+/// Media of an RTMP publisher, sent by its connection to the publisher task.
+pub enum RtmpMedia {
+    /// Codec of a track, sent before its first frame and again when it changes.
+    Config {
+        kind: MediaKind,
+        codec: Codec,
+        /// avcC of an H264 track.
+        codec_private: Option<Vec<u8>>,
+    },
+    Frame {
+        kind: MediaKind,
+        /// H264 as length prefixed NAL units, VP9 and Opus as is.
+        data: Vec<u8>,
+        pts: Duration,
+    },
+}
+
+/// An RTMP client asked to publish a stream, handed to a worker which creates the task.
+pub struct RtmpPublish {
+    /// Request built for the authorizer and the admission webhook: `POST /rtmp/{channel}`,
+    /// with the `token` query of the stream key as bearer token.
+    pub req: HttpRequest,
+    /// Stream key without its query, used as channel.
+    pub channel: String,
+    pub media: Receiver<RtmpMedia>,
+    /// Answer of the worker, an error rejects the publish.
+    pub result: Sender<Result<(), String>>,
+}
+
+impl RtmpPublish {
+    pub fn reject(self, reason: String) {
+        //the connection may already be closed
+        let _ = self.result.send(Err(reason));
+    }
+}
+
+/// RTMP ingest: accept publish connections from encoders such as OBS or ffmpeg.
+///
+/// Every connection runs on its own thread: it does the handshake and the command exchange,
+/// then sends the publish request to the controller and the demuxed frames to the task
+/// created by a worker. Playing over RTMP is not supported.
+pub struct RtmpServer;
+
+impl RtmpServer {
+    pub fn start(addr: SocketAddr) -> io::Result<Receiver<RtmpPublish>> {
+        let listener = TcpListener::bind(addr)?;
+        log::info!("RTMP ingest listening at {addr}");
+        let (sender, receiver) = crossbeam::channel::bounded(PUBLISH_QUEUE_SIZE);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::warn!("RTMP accept failed: {e}");
+                        continue;
+                    }
+                };
+                let sender = sender.clone();
+                std::thread::spawn(move || {
+                    let remote = stream.peer_addr().ok();
+                    match RtmpConnection::new(stream, sender).and_then(|conn| conn.run()) {
+                        Ok(()) => log::info!("RTMP connection {remote:?} closed"),
+                        Err(e) => log::warn!("RTMP connection {remote:?} failed: {e}"),
+                    }
+                });
+            }
+        });
+        Ok(receiver)
+    }
+}
+
+struct RtmpConnection {
+    remote: Option<SocketAddr>,
+    reader: ChunkReader<BufReader<TcpStream>>,
+    writer: ChunkWriter<BufWriter<TcpStream>>,
+    publishes: Sender<RtmpPublish>,
+    /// Acknowledgement window set by the client, 0 until then.
+    ack_window: u64,
+    last_ack: u64,
+    flv: FlvDemuxer,
+    media: Option<Sender<RtmpMedia>>,
+}
+
+impl RtmpConnection {
+    fn new(stream: TcpStream, publishes: Sender<RtmpPublish>) -> io::Result<Self> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            remote: stream.peer_addr().ok(),
+            reader: ChunkReader::new(BufReader::new(stream.try_clone()?)),
+            writer: ChunkWriter::new(BufWriter::new(stream)),
+            publishes,
+            ack_window: 0,
+            last_ack: 0,
+            flv: FlvDemuxer::default(),
+            media: None,
+        })
+    }
+
+    fn run(mut self) -> io::Result<()> {
+        self.handshake()?;
+        loop {
+            let message = self.reader.read_message()?;
+            self.acknowledge()?;
+            match message.type_id {
+                chunk::WINDOW_ACK_SIZE if message.payload.len() >= 4 => {
+                    self.ack_window =
+                        u32::from_be_bytes(message.payload[..4].try_into().unwrap()) as u64;
+                }
+                chunk::AMF0_COMMAND => {
+                    if !self.on_command(amf0::decode_all(&message.payload))? {
+                        return Ok(());
+                    }
+                }
+                //AMF3 commands start with a format byte, the values are still AMF0
+                chunk::AMF3_COMMAND if !message.payload.is_empty() => {
+                    if !self.on_command(amf0::decode_all(&message.payload[1..]))? {
+                        return Ok(());
+                    }
+                }
+                chunk::AUDIO | chunk::VIDEO => {
+                    if !self.on_media(message) {
+                        log::info!("RTMP publisher {:?} ended by the server", self.remote);
+                        return Ok(());
+                    }
+                }
+                //metadata and the other control messages are not needed
+                _ => {}
+            }
+        }
+    }
+
+    /// Plain handshake, see RTMP section 5.2.
+    fn handshake(&mut self) -> io::Result<()> {
+        let mut c0c1 = [0; 1 + HANDSHAKE_SIZE];
+        self.reader.read_exact(&mut c0c1)?;
+        if c0c1[0] != 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported RTMP version {}", c0c1[0]),
+            ));
+        }
+        let mut s0s1s2 = Vec::with_capacity(1 + 2 * HANDSHAKE_SIZE);
+        s0s1s2.push(3);
+        //S1: time, zero, random bytes
+        s0s1s2.extend_from_slice(&[0; 8]);
+        let mut seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64
+            | 1;
+        for _ in 8..HANDSHAKE_SIZE {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            s0s1s2.push(seed as u8);
+        }
+        //S2 echoes C1
+        s0s1s2.extend_from_slice(&c0c1[1..]);
+        self.writer.write_all(&s0s1s2)?;
+        let mut c2 = [0; HANDSHAKE_SIZE];
+        self.reader.read_exact(&mut c2)
+    }
+
+    fn acknowledge(&mut self) -> io::Result<()> {
+        let bytes_read = self.reader.bytes_read();
+        if self.ack_window > 0 && bytes_read - self.last_ack >= self.ack_window {
+            self.last_ack = bytes_read;
+            self.writer.write_message(
+                CONTROL_CSID,
+                chunk::ACKNOWLEDGEMENT,
+                0,
+                &(bytes_read as u32).to_be_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Handle a command, return false when the connection should be closed.
+    fn on_command(&mut self, values: Vec<Amf0Value>) -> io::Result<bool> {
+        let name = values.first().and_then(|v| v.as_str()).unwrap_or_default();
+        let transaction = values.get(1).and_then(|v| v.as_number()).unwrap_or(0.0);
+        log::debug!("RTMP {:?} command {name}", self.remote);
+        match name {
+            "connect" => {
+                self.writer.write_message(
+                    CONTROL_CSID,
+                    chunk::WINDOW_ACK_SIZE,
+                    0,
+                    &WINDOW_SIZE.to_be_bytes(),
+                )?;
+                let mut bandwidth = WINDOW_SIZE.to_be_bytes().to_vec();
+                //dynamic limit
+                bandwidth.push(2);
+                self.writer.write_message(
+                    CONTROL_CSID,
+                    chunk::SET_PEER_BANDWIDTH,
+                    0,
+                    &bandwidth,
+                )?;
+                self.writer.set_chunk_size(CHUNK_SIZE)?;
+                self.send_command(
+                    0,
+                    &[
+                        string("_result"),
+                        Amf0Value::Number(transaction),
+                        Amf0Value::Object(vec![
+                            ("fmsVer".to_string(), string("FMS/3,0,1,123")),
+                            ("capabilities".to_string(), Amf0Value::Number(31.0)),
+                        ]),
+                        status(
+                            "status",
+                            "NetConnection.Connect.Success",
+                            "Connection succeeded.",
+                        ),
+                    ],
+                )?;
+            }
+            "releaseStream" | "FCPublish" => {
+                self.send_command(
+                    0,
+                    &[
+                        string("_result"),
+                        Amf0Value::Number(transaction),
+                        Amf0Value::Null,
+                        Amf0Value::Undefined,
+                    ],
+                )?;
+            }
+            "createStream" => {
+                self.send_command(
+                    0,
+                    &[
+                        string("_result"),
+                        Amf0Value::Number(transaction),
+                        Amf0Value::Null,
+                        Amf0Value::Number(PUBLISH_STREAM_ID as f64),
+                    ],
+                )?;
+            }
+            "publish" => {
+                let stream_key = values.get(3).and_then(|v| v.as_str()).unwrap_or_default();
+                return self.on_publish(stream_key.to_string());
+            }
+            "FCUnpublish" | "deleteStream" | "closeStream" => {
+                log::info!("RTMP publisher {:?} unpublished", self.remote);
+                return Ok(false);
+            }
+            "play" => {
+                self.send_status(
+                    "error",
+                    "NetStream.Play.Failed",
+                    "playing is not supported, use WHEP",
+                )?;
+                return Ok(false);
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn on_publish(&mut self, stream_key: String) -> io::Result<bool> {
+        if self.media.is_some() {
+            self.send_status("error", "NetStream.Publish.BadName", "already publishing")?;
+            return Ok(false);
+        }
+        let (channel, query) = stream_key
+            .split_once('?')
+            .unwrap_or((stream_key.as_str(), ""));
+        if channel.is_empty() || channel.contains('/') {
+            self.send_status("error", "NetStream.Publish.BadName", "invalid stream key")?;
+            return Ok(false);
+        }
+        let mut headers = HashMap::new();
+        if let Some(token) = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("token="))
+        {
+            headers.insert("Authorization".to_string(), format!("Bearer {token}"));
+        }
+        let (media_send, media_recv) = crossbeam::channel::bounded(QUEUE_SIZE);
+        let (result_send, result_recv) = crossbeam::channel::bounded(1);
+        let publish = RtmpPublish {
+            req: HttpRequest {
+                //assigned by the main loop
+                req_id: 0,
+                remote: self.remote,
+                method: "POST".to_string(),
+                path: format!("/rtmp/{channel}"),
+                headers,
+                body: Vec::new(),
+            },
+            channel: channel.to_string(),
+            media: media_recv,
+            result: result_send,
+        };
+        if self.publishes.try_send(publish).is_err() {
+            self.send_status("error", "NetStream.Publish.Failed", "server is busy")?;
+            return Ok(false);
+        }
+        match result_recv.recv_timeout(PUBLISH_TIMEOUT) {
+            Ok(Ok(())) => {
+                log::info!("RTMP {:?} publishing to channel {channel}", self.remote);
+                self.media = Some(media_send);
+                self.send_status("status", "NetStream.Publish.Start", "publishing")?;
+                Ok(true)
+            }
+            Ok(Err(reason)) => {
+                log::warn!(
+                    "RTMP {:?} publish to channel {channel} rejected: {reason}",
+                    self.remote
+                );
+                self.send_status("error", "NetStream.Publish.BadName", &reason)?;
+                Ok(false)
+            }
+            Err(e) => {
+                log::warn!(
+                    "RTMP {:?} publish to channel {channel} failed: {e}",
+                    self.remote
+                );
+                self.send_status("error", "NetStream.Publish.Failed", "no answer")?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Forward a media message, return false when the publisher task ended.
+    fn on_media(&mut self, message: Message) -> bool {
+        let media = match &self.media {
+            Some(media) => media,
+            None => return true,
+        };
+        let frame = if message.type_id == chunk::VIDEO {
+            self.flv.video(&message.payload, message.timestamp)
+        } else {
+            self.flv.audio(&message.payload, message.timestamp)
+        };
+        match frame {
+            Some(frame) => media.send(frame).is_ok(),
+            None => true,
+        }
+    }
+
+    fn send_status(&mut self, level: &str, code: &str, description: &str) -> io::Result<()> {
+        self.send_command(
+            PUBLISH_STREAM_ID,
+            &[
+                string("onStatus"),
+                Amf0Value::Number(0.0),
+                Amf0Value::Null,
+                status(level, code, description),
+            ],
+        )
+    }
+
+    fn send_command(&mut self, stream_id: u32, values: &[Amf0Value]) -> io::Result<()> {
+        let mut payload = Vec::new();
+        for value in values {
+            amf0::encode(&mut payload, value);
+        }
+        self.writer
+            .write_message(COMMAND_CSID, chunk::AMF0_COMMAND, stream_id, &payload)
+    }
+}
+
+fn string(value: &str) -> Amf0Value {
+    Amf0Value::String(value.to_string())
+}
+
+fn status(level: &str, code: &str, description: &str) -> Amf0Value {
+    Amf0Value::Object(vec![
+        ("level".to_string(), string(level)),
+        ("code".to_string(), string(code)),
+        ("description".to_string(), string(description)),
+    ])
+}
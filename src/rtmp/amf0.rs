@@ -0,0 +1,268 @@
+/// AMF0 value, the encoding of RTMP commands, see the AMF0 specification.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Amf0Value {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    /// Object or ECMA array, properties in order.
+    Object(Vec<(String, Amf0Value)>),
+    Null,
+    Undefined,
+    StrictArray(Vec<Amf0Value>),
+}
+
+const NUMBER: u8 = 0x00;
+const BOOLEAN: u8 = 0x01;
+const STRING: u8 = 0x02;
+const OBJECT: u8 = 0x03;
+const NULL: u8 = 0x05;
+const UNDEFINED: u8 = 0x06;
+const ECMA_ARRAY: u8 = 0x08;
+const OBJECT_END: u8 = 0x09;
+const STRICT_ARRAY: u8 = 0x0a;
+const DATE: u8 = 0x0b;
+const LONG_STRING: u8 = 0x0c;
+/// Nesting of objects and arrays above this is rejected, commands only use a few levels.
+const MAX_DEPTH: usize = 32;
+
+impl Amf0Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Amf0Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Amf0Value::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+/// Decode the values of a command message, stopping at the first unsupported one.
+pub fn decode_all(mut data: &[u8]) -> Vec<Amf0Value> {
+    let mut values = Vec::new();
+    while !data.is_empty() {
+        match decode(&mut data) {
+            Some(value) => values.push(value),
+            None => break,
+        }
+    }
+    values
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if data.len() < len {
+        return None;
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Some(taken)
+}
+
+fn decode_string(data: &mut &[u8], len_bytes: usize) -> Option<String> {
+    let len = take(data, len_bytes)?
+        .iter()
+        .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+    Some(String::from_utf8_lossy(take(data, len)?).into_owned())
+}
+
+fn decode_properties(data: &mut &[u8], depth: usize) -> Option<Vec<(String, Amf0Value)>> {
+    let mut properties = Vec::new();
+    loop {
+        let key = decode_string(data, 2)?;
+        if key.is_empty() && data.first() == Some(&OBJECT_END) {
+            *data = &data[1..];
+            return Some(properties);
+        }
+        properties.push((key, decode_nested(data, depth)?));
+    }
+}
+
+pub fn decode(data: &mut &[u8]) -> Option<Amf0Value> {
+    decode_nested(data, 0)
+}
+
+fn decode_nested(data: &mut &[u8], depth: usize) -> Option<Amf0Value> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let marker = *take(data, 1)?.first()?;
+    match marker {
+        NUMBER => Some(Amf0Value::Number(f64::from_be_bytes(
+            take(data, 8)?.try_into().ok()?,
+        ))),
+        BOOLEAN => Some(Amf0Value::Boolean(*take(data, 1)?.first()? != 0)),
+        STRING => Some(Amf0Value::String(decode_string(data, 2)?)),
+        LONG_STRING => Some(Amf0Value::String(decode_string(data, 4)?)),
+        OBJECT => Some(Amf0Value::Object(decode_properties(data, depth + 1)?)),
+        ECMA_ARRAY => {
+            //the count is a hint, the properties end like an object
+            take(data, 4)?;
+            Some(Amf0Value::Object(decode_properties(data, depth + 1)?))
+        }
+        STRICT_ARRAY => {
+            let count = u32::from_be_bytes(take(data, 4)?.try_into().ok()?);
+            let mut values = Vec::new();
+            for _ in 0..count {
+                values.push(decode_nested(data, depth + 1)?);
+            }
+            Some(Amf0Value::StrictArray(values))
+        }
+        DATE => {
+            //milliseconds then a time zone
+            let millis = f64::from_be_bytes(take(data, 8)?.try_into().ok()?);
+            take(data, 2)?;
+            Some(Amf0Value::Number(millis))
+        }
+        NULL => Some(Amf0Value::Null),
+        UNDEFINED => Some(Amf0Value::Undefined),
+        _ => None,
+    }
+}
+
+fn encode_string(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+pub fn encode(out: &mut Vec<u8>, value: &Amf0Value) {
+    match value {
+        Amf0Value::Number(value) => {
+            out.push(NUMBER);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        Amf0Value::Boolean(value) => out.extend_from_slice(&[BOOLEAN, *value as u8]),
+        Amf0Value::String(value) if value.len() > u16::MAX as usize => {
+            out.push(LONG_STRING);
+            out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            out.extend_from_slice(value.as_bytes());
+        }
+        Amf0Value::String(value) => {
+            out.push(STRING);
+            encode_string(out, value);
+        }
+        Amf0Value::Object(properties) => {
+            out.push(OBJECT);
+            for (key, value) in properties {
+                encode_string(out, key);
+                encode(out, value);
+            }
+            out.extend_from_slice(&[0, 0, OBJECT_END]);
+        }
+        Amf0Value::Null => out.push(NULL),
+        Amf0Value::Undefined => out.push(UNDEFINED),
+        Amf0Value::StrictArray(values) => {
+            out.push(STRICT_ARRAY);
+            out.extend_from_slice(&(values.len() as u32).to_be_bytes());
+            for value in values {
+                encode(out, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_all, encode, Amf0Value, MAX_DEPTH};
+
+    fn connect() -> Vec<Amf0Value> {
+        vec![
+            Amf0Value::String("connect".to_string()),
+            Amf0Value::Number(1.0),
+            Amf0Value::Object(vec![
+                ("app".to_string(), Amf0Value::String("live".to_string())),
+                ("fpad".to_string(), Amf0Value::Boolean(false)),
+                ("capabilities".to_string(), Amf0Value::Number(15.0)),
+            ]),
+            Amf0Value::Null,
+            Amf0Value::StrictArray(vec![Amf0Value::Undefined, Amf0Value::Number(2.0)]),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut data = Vec::new();
+        for value in connect() {
+            encode(&mut data, &value);
+        }
+        assert_eq!(decode_all(&data), connect());
+    }
+
+    #[test]
+    fn decode_ecma_array_and_date() {
+        let mut data = vec![0x08, 0, 0, 0, 1];
+        data.extend_from_slice(&[0, 8]);
+        data.extend_from_slice(b"duration");
+        data.push(0x00);
+        data.extend_from_slice(&10.5f64.to_be_bytes());
+        data.extend_from_slice(&[0, 0, 0x09]);
+        data.push(0x0b);
+        data.extend_from_slice(&1000.0f64.to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        assert_eq!(
+            decode_all(&data),
+            [
+                Amf0Value::Object(vec![("duration".to_string(), Amf0Value::Number(10.5))]),
+                Amf0Value::Number(1000.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_string() {
+        let value = Amf0Value::String("a".repeat(70000));
+        let mut data = Vec::new();
+        encode(&mut data, &value);
+        assert_eq!(data[0], 0x0c);
+        assert_eq!(decode(&mut data.as_slice()), Some(value));
+    }
+
+    #[test]
+    fn nested_values() {
+        let nested = |depth: usize| {
+            let mut data = Vec::new();
+            for _ in 0..depth {
+                //object with a single "a" property
+                data.extend_from_slice(&[0x03, 0, 1, b'a']);
+            }
+            data.push(0x05);
+            for _ in 0..depth {
+                data.extend_from_slice(&[0, 0, 0x09]);
+            }
+            data
+        };
+        assert!(decode(&mut nested(MAX_DEPTH).as_slice()).is_some());
+        assert_eq!(decode(&mut nested(MAX_DEPTH + 1).as_slice()), None);
+        //deep enough to overflow the stack without the limit
+        assert_eq!(decode(&mut nested(100_000).as_slice()), None);
+
+        let mut arrays = Vec::new();
+        for _ in 0..100_000 {
+            arrays.extend_from_slice(&[0x0a, 0, 0, 0, 1]);
+        }
+        arrays.push(0x05);
+        assert_eq!(decode(&mut arrays.as_slice()), None);
+    }
+
+    #[test]
+    fn truncated_values() {
+        let mut data = Vec::new();
+        for value in connect() {
+            encode(&mut data, &value);
+        }
+        for len in 0..data.len() {
+            let values = decode_all(&data[..len]);
+            assert!(values.len() < connect().len());
+            assert_eq!(values[..], connect()[..values.len()]);
+        }
+        //a strict array announcing more values than it has
+        assert_eq!(
+            decode(&mut [0x0a, 0xff, 0xff, 0xff, 0xff, 0x05].as_slice()),
+            None
+        );
+        assert_eq!(decode_all(&[0x02, 0x00, 0x05, b'a']), []);
+    }
+}
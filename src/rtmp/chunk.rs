@@ -0,0 +1,391 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+};
+
+/// Chunk size of both directions until a Set Chunk Size message.
+pub const DEFAULT_CHUNK_SIZE: usize = 128;
+/// Chunk streams a client may open, a few are enough for commands, audio and video.
+const MAX_CHUNK_STREAMS: usize = 64;
+/// Bytes of incomplete messages a connection may buffer over all its chunk streams, well
+/// above the size of a keyframe.
+const MAX_BUFFERED_BYTES: usize = 8 * 1024 * 1024;
+
+pub const SET_CHUNK_SIZE: u8 = 1;
+pub const ABORT: u8 = 2;
+pub const ACKNOWLEDGEMENT: u8 = 3;
+pub const WINDOW_ACK_SIZE: u8 = 5;
+pub const SET_PEER_BANDWIDTH: u8 = 6;
+pub const AUDIO: u8 = 8;
+pub const VIDEO: u8 = 9;
+pub const AMF3_COMMAND: u8 = 17;
+pub const AMF0_COMMAND: u8 = 20;
+
+/// A complete RTMP message.
+pub struct Message {
+    pub type_id: u8,
+    /// Milliseconds, from the chunk timestamps.
+    pub timestamp: u32,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct ChunkStream {
+    timestamp: u32,
+    delta: u32,
+    length: usize,
+    type_id: u8,
+    /// The last header had an extended timestamp, which fmt 3 chunks repeat.
+    extended: bool,
+    payload: Vec<u8>,
+}
+
+/// Reassemble the messages of the chunk streams of a connection, see RTMP section 5.3.
+pub struct ChunkReader<R: Read> {
+    reader: R,
+    chunk_size: usize,
+    streams: HashMap<u32, ChunkStream>,
+    bytes_read: u64,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            streams: HashMap::new(),
+            bytes_read: 0,
+        }
+    }
+
+    /// Bytes received on the connection, for acknowledgements.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.reader.read_exact(buf)?;
+        self.bytes_read += buf.len() as u64;
+        Ok(())
+    }
+
+    fn read_uint(&mut self, len: usize) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        self.read_exact(&mut buf[..len])?;
+        Ok(buf[..len]
+            .iter()
+            .fold(0, |value, byte| (value << 8) | *byte as u32))
+    }
+
+    /// Read chunks until a message is complete. Protocol control messages which only affect
+    /// the chunk layer are applied and returned as well.
+    pub fn read_message(&mut self) -> io::Result<Message> {
+        loop {
+            let first = self.read_uint(1)?;
+            let fmt = first >> 6;
+            let csid = match first & 0x3f {
+                0 => 64 + self.read_uint(1)?,
+                1 => {
+                    let low = self.read_uint(1)?;
+                    64 + low + (self.read_uint(1)? << 8)
+                }
+                csid => csid,
+            };
+            if !self.streams.contains_key(&csid) && self.streams.len() >= MAX_CHUNK_STREAMS {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "too many chunk streams",
+                ));
+            }
+
+            let mut stream = self.streams.remove(&csid).unwrap_or_default();
+            let message = self.read_chunk(fmt, &mut stream)?;
+            self.streams.insert(csid, stream);
+            if let Some(message) = message {
+                match message.type_id {
+                    SET_CHUNK_SIZE if message.payload.len() >= 4 => {
+                        let size = u32::from_be_bytes(message.payload[..4].try_into().unwrap());
+                        self.chunk_size = (size & 0x7fff_ffff).max(1) as usize;
+                    }
+                    ABORT if message.payload.len() >= 4 => {
+                        let csid = u32::from_be_bytes(message.payload[..4].try_into().unwrap());
+                        if let Some(stream) = self.streams.get_mut(&csid) {
+                            stream.payload.clear();
+                        }
+                    }
+                    _ => {}
+                }
+                return Ok(message);
+            }
+        }
+    }
+
+    fn read_chunk(&mut self, fmt: u32, stream: &mut ChunkStream) -> io::Result<Option<Message>> {
+        let starting = stream.payload.is_empty();
+        if fmt <= 2 {
+            let timestamp = self.read_uint(3)?;
+            if fmt <= 1 {
+                stream.length = self.read_uint(3)? as usize;
+                stream.type_id = self.read_uint(1)? as u8;
+            }
+            if fmt == 0 {
+                //the message stream id, a connection publishes a single stream
+                self.read_uint(4)?;
+            }
+            stream.extended = timestamp == 0xff_ffff;
+            let timestamp = if stream.extended {
+                self.read_uint(4)?
+            } else {
+                timestamp
+            };
+            //a fmt 0 timestamp is absolute, it is also the delta of following fmt 3 messages
+            stream.delta = timestamp;
+            stream.timestamp = if fmt == 0 {
+                timestamp
+            } else {
+                stream.timestamp.wrapping_add(timestamp)
+            };
+        } else {
+            if stream.extended {
+                self.read_uint(4)?;
+            }
+            if starting {
+                stream.timestamp = stream.timestamp.wrapping_add(stream.delta);
+            }
+        }
+
+        if stream.length < stream.payload.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "message length below the received payload",
+            ));
+        }
+        //the other streams are out of the map while their chunk is read
+        let buffered: usize = self.streams.values().map(|s| s.payload.len()).sum();
+        if buffered + stream.length > MAX_BUFFERED_BYTES {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "too many buffered message bytes",
+            ));
+        }
+
+        let len = (stream.length - stream.payload.len()).min(self.chunk_size);
+        let offset = stream.payload.len();
+        stream.payload.resize(offset + len, 0);
+        self.read_exact(&mut stream.payload[offset..])?;
+        if stream.payload.len() < stream.length {
+            return Ok(None);
+        }
+        Ok(Some(Message {
+            type_id: stream.type_id,
+            timestamp: stream.timestamp,
+            payload: std::mem::take(&mut stream.payload),
+        }))
+    }
+}
+
+/// Split the messages sent by the server into chunks.
+pub struct ChunkWriter<W: Write> {
+    writer: W,
+    chunk_size: usize,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data)?;
+        self.writer.flush()
+    }
+
+    /// Send a Set Chunk Size message, then use that size.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> io::Result<()> {
+        self.write_message(2, SET_CHUNK_SIZE, 0, &(chunk_size as u32).to_be_bytes())?;
+        self.chunk_size = chunk_size;
+        Ok(())
+    }
+
+    pub fn write_message(
+        &mut self,
+        csid: u8,
+        type_id: u8,
+        stream_id: u32,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let mut data = Vec::with_capacity(payload.len() + 12 + payload.len() / self.chunk_size);
+        //fmt 0 header with a zero timestamp, csid below 64
+        data.push(csid & 0x3f);
+        data.extend_from_slice(&[0, 0, 0]);
+        data.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        data.push(type_id);
+        data.extend_from_slice(&stream_id.to_le_bytes());
+        for (index, chunk) in payload.chunks(self.chunk_size).enumerate() {
+            if index > 0 {
+                //fmt 3 continuation
+                data.push(0xc0 | (csid & 0x3f));
+            }
+            data.extend_from_slice(chunk);
+        }
+        self.write_all(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ChunkReader, ChunkWriter, Message, AMF0_COMMAND, MAX_BUFFERED_BYTES, SET_CHUNK_SIZE, VIDEO,
+    };
+    use std::io::{Cursor, ErrorKind};
+
+    fn read_all(data: Vec<u8>) -> (Vec<Message>, ErrorKind) {
+        let mut reader = ChunkReader::new(Cursor::new(data));
+        let mut messages = Vec::new();
+        loop {
+            match reader.read_message() {
+                Ok(message) => messages.push(message),
+                Err(e) => return (messages, e.kind()),
+            }
+        }
+    }
+
+    /// fmt 0 header of chunk stream 4.
+    fn header(timestamp: u32, length: usize, type_id: u8) -> Vec<u8> {
+        let mut data = vec![0x04];
+        data.extend_from_slice(&timestamp.to_be_bytes()[1..]);
+        data.extend_from_slice(&(length as u32).to_be_bytes()[1..]);
+        data.push(type_id);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn single_chunk() {
+        let mut data = header(1000, 3, VIDEO);
+        data.extend_from_slice(&[1, 2, 3]);
+        let (messages, error) = read_all(data);
+        assert_eq!(error, ErrorKind::UnexpectedEof);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].type_id, VIDEO);
+        assert_eq!(messages[0].timestamp, 1000);
+        assert_eq!(messages[0].payload, [1, 2, 3]);
+    }
+
+    #[test]
+    fn continuation_chunks() {
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut data = header(1000, payload.len(), VIDEO);
+        data.extend_from_slice(&payload[..128]);
+        data.push(0xc4);
+        data.extend_from_slice(&payload[128..256]);
+        data.push(0xc4);
+        data.extend_from_slice(&payload[256..]);
+        //fmt 3 starting a message repeats the delta of the fmt 0 timestamp
+        for chunk in payload.chunks(128) {
+            data.push(0xc4);
+            data.extend_from_slice(chunk);
+        }
+        let (messages, _) = read_all(data);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].payload, payload);
+        assert_eq!(messages[1].payload, payload);
+        assert_eq!(messages[1].timestamp, 2000);
+    }
+
+    #[test]
+    fn fmt_1_and_2_deltas() {
+        let mut data = header(1000, 1, VIDEO);
+        data.push(0);
+        //fmt 1 with a delta of 40 and a new length
+        data.extend_from_slice(&[0x44, 0, 0, 40, 0, 0, 2, VIDEO, 1, 2]);
+        //fmt 2 with a delta of 20
+        data.extend_from_slice(&[0x84, 0, 0, 20, 3, 4]);
+        let (messages, _) = read_all(data);
+        let timestamps: Vec<_> = messages.iter().map(|m| m.timestamp).collect();
+        assert_eq!(timestamps, [1000, 1040, 1060]);
+        assert_eq!(messages[2].payload, [3, 4]);
+    }
+
+    #[test]
+    fn set_chunk_size() {
+        let mut data = Vec::new();
+        let mut writer = ChunkWriter::new(&mut data);
+        writer.set_chunk_size(4096).unwrap();
+        writer
+            .write_message(3, AMF0_COMMAND, 0, &[7; 1000])
+            .unwrap();
+        //a single chunk once the size is applied
+        assert_eq!(data.len(), 12 + 4 + 12 + 1000);
+
+        let (messages, _) = read_all(data);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].type_id, SET_CHUNK_SIZE);
+        assert_eq!(messages[1].type_id, AMF0_COMMAND);
+        assert_eq!(messages[1].payload, [7; 1000]);
+    }
+
+    #[test]
+    fn writer_splits_chunks() {
+        let mut data = Vec::new();
+        ChunkWriter::new(&mut data)
+            .write_message(3, VIDEO, 1, &[5; 300])
+            .unwrap();
+        assert_eq!(data.len(), 12 + 300 + 2);
+        assert_eq!(data[12 + 128], 0xc3);
+        let (messages, _) = read_all(data);
+        assert_eq!(messages[0].payload, [5; 300]);
+    }
+
+    #[test]
+    fn reject_shrinking_length() {
+        let mut data = header(0, 200, VIDEO);
+        data.extend_from_slice(&[0; 128]);
+        //fmt 1 inside the message, with a length below the received bytes
+        data.extend_from_slice(&[0x44, 0, 0, 0, 0, 0, 10, VIDEO]);
+        let (messages, error) = read_all(data);
+        assert!(messages.is_empty());
+        assert_eq!(error, ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reject_too_many_streams() {
+        let mut data = Vec::new();
+        //incomplete messages keep the streams open
+        for csid in 3..63 {
+            data.extend_from_slice(&[csid, 0, 0, 0, 0, 0, 200, VIDEO, 0, 0, 0, 0]);
+            data.extend_from_slice(&[0; 128]);
+        }
+        for csid in 0..10 {
+            data.extend_from_slice(&[0, csid, 0, 0, 0, 0, 0, 200, VIDEO, 0, 0, 0, 0]);
+            data.extend_from_slice(&[0; 128]);
+        }
+        let (messages, error) = read_all(data);
+        assert!(messages.is_empty());
+        assert_eq!(error, ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reject_too_many_buffered_bytes() {
+        let (_, error) = read_all(header(0, MAX_BUFFERED_BYTES + 1, VIDEO));
+        assert_eq!(error, ErrorKind::InvalidData);
+
+        //an incomplete message on a second stream
+        let chunk_size = MAX_BUFFERED_BYTES / 2;
+        let mut data = vec![0x02, 0, 0, 0, 0, 0, 4, SET_CHUNK_SIZE, 0, 0, 0, 0];
+        data.extend_from_slice(&(chunk_size as u32).to_be_bytes());
+        for csid in 3..5 {
+            data.push(csid);
+            data.extend_from_slice(&[0, 0, 0]);
+            data.extend_from_slice(&(chunk_size as u32 + 1).to_be_bytes()[1..]);
+            data.extend_from_slice(&[VIDEO, 0, 0, 0, 0]);
+            data.resize(data.len() + chunk_size, 0);
+        }
+        let (messages, error) = read_all(data);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(error, ErrorKind::InvalidData);
+    }
+}
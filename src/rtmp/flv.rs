@@ -0,0 +1,248 @@
+use std::time::Duration;
+
+use str0m::{format::Codec, media::MediaKind};
+
+use super::RtmpMedia;
+
+/// FLV legacy video codec id of AVC.
+const CODEC_AVC: u8 = 7;
+/// FLV sound format which announces an enhanced RTMP audio header.
+const SOUND_FORMAT_EX_HEADER: u8 = 9;
+/// Video frame type of command frames, which carry no media.
+const FRAME_TYPE_COMMAND: u8 = 5;
+
+/// Turn the audio and video message bodies of a publisher into frames, see the FLV
+/// specification and enhanced RTMP. H264 (legacy or `avc1`), VP9 (`vp09`) and Opus (`Opus`)
+/// are supported, other codecs such as AAC are dropped.
+#[derive(Default)]
+pub struct FlvDemuxer {
+    opus_configured: bool,
+    unsupported_audio_logged: bool,
+    unsupported_video_logged: bool,
+}
+
+impl FlvDemuxer {
+    pub fn video(&mut self, data: &[u8], timestamp: u32) -> Option<RtmpMedia> {
+        let first = *data.first()?;
+        if (first >> 4) & 0x07 == FRAME_TYPE_COMMAND {
+            return None;
+        }
+        //(codec, packet type, composition time, body)
+        let (codec, packet_type, composition_time, body) = if first & 0x80 != 0 {
+            let codec = match data.get(1..5)? {
+                b"avc1" => Codec::H264,
+                b"vp09" => Codec::Vp9,
+                fourcc => {
+                    self.unsupported_video(&String::from_utf8_lossy(fourcc));
+                    return None;
+                }
+            };
+            match first & 0x0f {
+                //coded frames with a composition time, only for AVC and HEVC
+                1 if codec == Codec::H264 => (codec, 1, read_si24(data.get(5..8)?), &data[8..]),
+                //coded frames without composition time
+                1 | 3 => (codec, 1, 0, &data[5..]),
+                0 => (codec, 0, 0, &data[5..]),
+                _ => return None,
+            }
+        } else {
+            if first & 0x0f != CODEC_AVC {
+                self.unsupported_video(&format!("flv codec {}", first & 0x0f));
+                return None;
+            }
+            let packet_type = *data.get(1)?;
+            (
+                Codec::H264,
+                packet_type,
+                read_si24(data.get(2..5)?),
+                &data[5..],
+            )
+        };
+
+        match packet_type {
+            //sequence header: avcC for H264, vpcC for VP9
+            0 => Some(RtmpMedia::Config {
+                kind: MediaKind::Video,
+                codec,
+                codec_private: (codec == Codec::H264).then(|| body.to_vec()),
+            }),
+            1 if !body.is_empty() => Some(RtmpMedia::Frame {
+                kind: MediaKind::Video,
+                data: body.to_vec(),
+                pts: millis(timestamp as i64 + composition_time as i64),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn audio(&mut self, data: &[u8], timestamp: u32) -> Option<RtmpMedia> {
+        let first = *data.first()?;
+        let sound_format = first >> 4;
+        if sound_format != SOUND_FORMAT_EX_HEADER {
+            //AAC (10) is what most encoders send, it would need a transcoder
+            if !self.unsupported_audio_logged {
+                self.unsupported_audio_logged = true;
+                log::warn!("RTMP audio format {sound_format} is not supported, audio is dropped");
+            }
+            return None;
+        }
+        if data.get(1..5)? != b"Opus" {
+            if !self.unsupported_audio_logged {
+                self.unsupported_audio_logged = true;
+                log::warn!(
+                    "RTMP audio codec {} is not supported, audio is dropped",
+                    String::from_utf8_lossy(&data[1..5])
+                );
+            }
+            return None;
+        }
+        match first & 0x0f {
+            //the Opus sequence start is optional, the first frame stands for it when missing
+            0 => self.configure_opus(),
+            1 if !self.opus_configured => self.configure_opus(),
+            1 if data.len() > 5 => Some(RtmpMedia::Frame {
+                kind: MediaKind::Audio,
+                data: data[5..].to_vec(),
+                pts: millis(timestamp as i64),
+            }),
+            _ => None,
+        }
+    }
+
+    fn configure_opus(&mut self) -> Option<RtmpMedia> {
+        self.opus_configured = true;
+        Some(RtmpMedia::Config {
+            kind: MediaKind::Audio,
+            codec: Codec::Opus,
+            codec_private: None,
+        })
+    }
+
+    fn unsupported_video(&mut self, codec: &str) {
+        if !self.unsupported_video_logged {
+            self.unsupported_video_logged = true;
+            log::warn!("RTMP video codec {codec} is not supported, video is dropped");
+        }
+    }
+}
+
+fn read_si24(data: &[u8]) -> i32 {
+    //sign extend from 24 bits
+    i32::from_be_bytes([data[0], data[1], data[2], 0]) >> 8
+}
+
+fn millis(value: i64) -> Duration {
+    Duration::from_millis(value.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use str0m::{format::Codec, media::MediaKind};
+
+    use super::{FlvDemuxer, RtmpMedia};
+
+    fn frame(media: Option<RtmpMedia>) -> (MediaKind, Vec<u8>, Duration) {
+        match media {
+            Some(RtmpMedia::Frame { kind, data, pts }) => (kind, data, pts),
+            _ => panic!("not a frame"),
+        }
+    }
+
+    fn config(media: Option<RtmpMedia>) -> (MediaKind, Codec, Option<Vec<u8>>) {
+        match media {
+            Some(RtmpMedia::Config {
+                kind,
+                codec,
+                codec_private,
+            }) => (kind, codec, codec_private),
+            _ => panic!("not a config"),
+        }
+    }
+
+    #[test]
+    fn legacy_avc() {
+        let mut demuxer = FlvDemuxer::default();
+        //keyframe, AVC, sequence header
+        let (kind, codec, avcc) = config(demuxer.video(&[0x17, 0, 0, 0, 0, 1, 0x64], 0));
+        assert_eq!((kind, codec), (MediaKind::Video, Codec::H264));
+        assert_eq!(avcc, Some(vec![1, 0x64]));
+
+        //NALU with a composition time of 40 ms
+        let (kind, data, pts) = frame(demuxer.video(&[0x27, 1, 0, 0, 40, 0, 0, 0, 1, 9], 1000));
+        assert_eq!(kind, MediaKind::Video);
+        assert_eq!(data, [0, 0, 0, 1, 9]);
+        assert_eq!(pts, Duration::from_millis(1040));
+
+        //a negative composition time can't go below zero
+        let (_, _, pts) = frame(demuxer.video(&[0x27, 1, 0xff, 0xff, 0xd8, 9], 10));
+        assert_eq!(pts, Duration::ZERO);
+
+        //end of sequence and empty frames carry no media
+        assert!(demuxer.video(&[0x17, 2, 0, 0, 0], 0).is_none());
+        assert!(demuxer.video(&[0x17, 1, 0, 0, 0], 0).is_none());
+    }
+
+    #[test]
+    fn enhanced_video() {
+        let mut demuxer = FlvDemuxer::default();
+        //keyframe, sequence start of VP9, no avcC
+        let (_, codec, codec_private) = config(demuxer.video(b"\x90vp09\x01", 0));
+        assert_eq!(codec, Codec::Vp9);
+        assert_eq!(codec_private, None);
+
+        //coded frames without composition time
+        let (_, data, pts) = frame(demuxer.video(b"\x91vp09\x82\x49", 500));
+        assert_eq!(data, [0x82, 0x49]);
+        assert_eq!(pts, Duration::from_millis(500));
+
+        //AVC coded frames have a composition time, the X variant has not
+        let (_, data, pts) = frame(demuxer.video(b"\x91avc1\x00\x00\x14\x05", 500));
+        assert_eq!((data, pts), (vec![5], Duration::from_millis(520)));
+        let (_, data, pts) = frame(demuxer.video(b"\x93avc1\x05", 500));
+        assert_eq!((data, pts), (vec![5], Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn drop_unsupported_video() {
+        let mut demuxer = FlvDemuxer::default();
+        //legacy VP6 and enhanced HEVC
+        assert!(demuxer.video(&[0x14, 0, 0, 0, 0, 1], 0).is_none());
+        assert!(demuxer.video(b"\x91hvc1\x00\x00\x00\x01", 0).is_none());
+        //command frame
+        assert!(demuxer.video(&[0x57, 0, 0], 0).is_none());
+        //truncated headers
+        assert!(demuxer.video(&[], 0).is_none());
+        assert!(demuxer.video(b"\x91av", 0).is_none());
+        assert!(demuxer.video(b"\x91avc1\x00", 0).is_none());
+    }
+
+    #[test]
+    fn enhanced_opus() {
+        let mut demuxer = FlvDemuxer::default();
+        let (kind, codec, _) = config(demuxer.audio(b"\x90Opus", 0));
+        assert_eq!((kind, codec), (MediaKind::Audio, Codec::Opus));
+        let (kind, data, pts) = frame(demuxer.audio(b"\x91Opus\xfc\x01", 20));
+        assert_eq!(kind, MediaKind::Audio);
+        assert_eq!(data, [0xfc, 1]);
+        assert_eq!(pts, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn opus_without_sequence_start() {
+        let mut demuxer = FlvDemuxer::default();
+        let (_, codec, _) = config(demuxer.audio(b"\x91Opus\xfc\x01", 0));
+        assert_eq!(codec, Codec::Opus);
+        assert!(demuxer.audio(b"\x91Opus\xfc\x02", 20).is_some());
+    }
+
+    #[test]
+    fn drop_unsupported_audio() {
+        let mut demuxer = FlvDemuxer::default();
+        //AAC, enhanced FLAC and a truncated header
+        assert!(demuxer.audio(&[0xaf, 1, 0x21], 0).is_none());
+        assert!(demuxer.audio(b"\x91fLaC\x00", 0).is_none());
+        assert!(demuxer.audio(b"\x91Op", 0).is_none());
+    }
+}
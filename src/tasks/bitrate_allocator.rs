@@ -0,0 +1,155 @@
+use std::time::{Duration, Instant};
+
+use str0m::bwe::Bitrate;
+
+/// Window used to measure the bitrate of each incoming layer.
+const MEASURE_WINDOW: Duration = Duration::from_secs(1);
+/// The estimate must stay above the next layer bitrate for this long before switching up.
+const UPGRADE_HOLD: Duration = Duration::from_secs(3);
+/// Headroom required over a layer bitrate before switching up to it.
+const UPGRADE_HEADROOM: f64 = 1.25;
+/// Below this estimate video is paused and only audio is sent.
+const PAUSE_BITRATE: u64 = 80_000;
+/// Video resumes only once the estimate is back above this.
+const RESUME_BITRATE: u64 = 150_000;
+
+/// Outcome of a bitrate estimate, applied by the viewer task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitrateAllocation {
+    /// Highest layer allowed, None when there is no layer information yet.
+    pub layer_limit: Option<u8>,
+    pub video_paused: bool,
+}
+
+#[derive(Default, Clone, Copy)]
+struct LayerRate {
+    bytes: u64,
+    window_start: Option<Instant>,
+    bitrate: u64,
+}
+
+/// Turn the egress bandwidth estimate of a viewer into a layer limit, with hysteresis so a noisy
+/// estimate does not make the viewer flap between layers.
+pub struct BitrateAllocator {
+    estimate: Option<u64>,
+    layers: Vec<LayerRate>,
+    allocation: BitrateAllocation,
+    upgrade_since: Option<Instant>,
+}
+
+impl Default for BitrateAllocator {
+    fn default() -> Self {
+        Self {
+            estimate: None,
+            layers: Vec::new(),
+            allocation: BitrateAllocation {
+                layer_limit: None,
+                video_paused: false,
+            },
+            upgrade_since: None,
+        }
+    }
+}
+
+impl BitrateAllocator {
+    pub fn estimate(&self) -> Option<u64> {
+        self.estimate
+    }
+
+    pub fn allocation(&self) -> BitrateAllocation {
+        self.allocation
+    }
+
+    /// Measured bitrate of a layer, or of the whole video without simulcast.
+    pub fn layer_bitrate(&self, layer: u8) -> Option<u64> {
+        self.layers
+            .get(layer as usize)
+            .map(|rate| rate.bitrate)
+            .filter(|bitrate| *bitrate > 0)
+    }
+
+    /// Bitrate needed to send the highest layer, used as BWE probing target.
+    pub fn desired_bitrate(&self) -> Option<Bitrate> {
+        self.layers
+            .iter()
+            .map(|rate| rate.bitrate)
+            .max()
+            .filter(|bitrate| *bitrate > 0)
+            .map(|bitrate| Bitrate::bps((bitrate as f64 * UPGRADE_HEADROOM) as u64))
+    }
+
+    /// Account an incoming video packet of the given layer, 0 without simulcast.
+    pub fn on_video_packet(&mut self, layer: u8, len: usize, now: Instant) {
+        if self.layers.len() <= layer as usize {
+            self.layers.resize(layer as usize + 1, LayerRate::default());
+        }
+        let rate = &mut self.layers[layer as usize];
+        let window_start = *rate.window_start.get_or_insert(now);
+        let elapsed = now - window_start;
+        if elapsed >= MEASURE_WINDOW {
+            rate.bitrate = rate.bytes * 8 * 1000 / elapsed.as_millis().max(1) as u64;
+            rate.bytes = 0;
+            rate.window_start = Some(now);
+        }
+        rate.bytes += len as u64;
+    }
+
+    /// Apply a new estimate, returns the allocation if it changed.
+    pub fn on_estimate(&mut self, estimate: Bitrate, now: Instant) -> Option<BitrateAllocation> {
+        let estimate = estimate.as_u64();
+        self.estimate = Some(estimate);
+
+        let prev = self.allocation;
+        let video_paused = if prev.video_paused {
+            estimate < RESUME_BITRATE
+        } else {
+            estimate < PAUSE_BITRATE
+        };
+
+        //highest layer which fits in the estimate, going down is immediate
+        let fitting = (0..self.layers.len() as u8)
+            .rev()
+            .find(|layer| {
+                self.layer_bitrate(*layer)
+                    .is_some_and(|bitrate| bitrate <= estimate)
+            })
+            .or((!self.layers.is_empty()).then_some(0));
+        let layer_limit = match (prev.layer_limit, fitting) {
+            (Some(current), Some(fitting)) if fitting > current => {
+                //going up needs headroom held for a while
+                let next = current + 1;
+                let headroom = self
+                    .layer_bitrate(next)
+                    .is_some_and(|bitrate| estimate as f64 >= bitrate as f64 * UPGRADE_HEADROOM);
+                if !headroom {
+                    self.upgrade_since = None;
+                    Some(current)
+                } else if now - *self.upgrade_since.get_or_insert(now) >= UPGRADE_HOLD {
+                    self.upgrade_since = None;
+                    Some(next)
+                } else {
+                    Some(current)
+                }
+            }
+            (_, fitting) => {
+                self.upgrade_since = None;
+                fitting
+            }
+        };
+
+        self.allocation = BitrateAllocation {
+            layer_limit,
+            video_paused,
+        };
+        if self.allocation != prev {
+            log::info!(
+                "BitrateAllocator estimate {} bps => {:?}",
+                estimate,
+                self.allocation
+            );
+            Some(self.allocation)
+        } else {
+            None
+        }
+    }
+}
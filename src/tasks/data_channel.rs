@@ -0,0 +1,62 @@
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+};
+
+use str0m::{channel::ChannelId, Rtc};
+
+/// A data channel message relayed between the publisher and the viewers of a channel.
+#[derive(Debug, Clone)]
+pub struct ChannelMessage {
+    /// Bus channel of the data, see [`data_track_id`].
+    pub track_id: u64,
+    /// Messages are relayed between data channels with the same label.
+    pub label: String,
+    pub binary: bool,
+    pub data: Vec<u8>,
+    /// Sent by a viewer to the publisher.
+    pub upstream: bool,
+}
+
+/// Bus channel id of the data channels of a channel, next to its audio and video tracks.
+pub fn data_track_id(channel: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    channel.hash(&mut hasher);
+    "data".hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Open data channels of a session by label.
+///
+/// Each side writes with the ordering and reliability it negotiated for its own channel.
+#[derive(Default)]
+pub struct DataChannels {
+    labels: HashMap<ChannelId, String>,
+}
+
+impl DataChannels {
+    pub fn on_open(&mut self, id: ChannelId, label: String) {
+        log::info!("Data channel {:?} opened: {}", id, label);
+        self.labels.insert(id, label);
+    }
+
+    pub fn on_close(&mut self, id: ChannelId) {
+        log::info!("Data channel {:?} closed", id);
+        self.labels.remove(&id);
+    }
+
+    pub fn label(&self, id: ChannelId) -> Option<&str> {
+        self.labels.get(&id).map(|label| label.as_str())
+    }
+
+    /// Write a relayed message on the channel with the same label, if any.
+    pub fn write(&self, rtc: &mut Rtc, msg: &ChannelMessage) {
+        for (id, _) in self.labels.iter().filter(|(_, label)| **label == msg.label) {
+            if let Some(mut channel) = rtc.channel(*id) {
+                if let Err(e) = channel.write(msg.binary, &msg.data) {
+                    log::warn!("Error writing data channel {}: {}", msg.label, e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+/// A layer which didn't send anything for this long is considered stopped by the publisher.
+const LAYER_TIMEOUT: Duration = Duration::from_secs(2);
+/// Minimum interval between keyframe requests while waiting to switch layer.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+pub struct LayerDecision {
+    pub forward: bool,
+    /// Set when we are waiting for a keyframe of the target layer.
+    pub request_keyframe: bool,
+}
+
+/// Choose which simulcast layer of a track is forwarded to a viewer.
+///
+/// Exactly one layer is forwarded at a time, and switching only happens on a keyframe of the
+/// target layer so the viewer decoder never sees a broken reference.
+#[derive(Default)]
+pub struct LayerSelector {
+    /// Layer chosen by the viewer, None for automatic.
+    manual: Option<u8>,
+    /// Upper limit chosen by the server, e.g. from bandwidth estimation.
+    limit: Option<u8>,
+    current: Option<u8>,
+    last_seen: Vec<Option<Instant>>,
+    last_keyframe_request: Option<Instant>,
+}
+
+impl LayerSelector {
+    /// Select a layer, or None to go back to automatic selection.
+    pub fn select(&mut self, layer: Option<u8>) {
+        log::info!("LayerSelector select {:?} => {:?}", self.manual, layer);
+        self.manual = layer;
+    }
+
+    /// Limit the automatically selected layer.
+    pub fn set_limit(&mut self, limit: Option<u8>) {
+        self.limit = limit;
+    }
+
+    pub fn current(&self) -> Option<u8> {
+        self.current
+    }
+
+    /// Highest layer which is currently sent by the publisher.
+    pub fn highest_active(&self, now: Instant) -> Option<u8> {
+        self.last_seen
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, seen)| seen.is_some_and(|seen| now - seen < LAYER_TIMEOUT))
+            .map(|(index, _)| index as u8)
+    }
+
+    /// Layer we want to forward: the manual choice, else the highest active layer under the limit,
+    /// in both cases clamped to the active layers.
+    pub fn target(&self, now: Instant) -> Option<u8> {
+        let highest = self.highest_active(now)?;
+        let wanted = self
+            .manual
+            .unwrap_or_else(|| self.limit.map_or(highest, |limit| limit.min(highest)))
+            .min(highest);
+        //fall back to a lower active layer if the wanted one is stopped
+        (0..=wanted).rev().find(|layer| {
+            self.last_seen
+                .get(*layer as usize)
+                .copied()
+                .flatten()
+                .is_some_and(|seen| now - seen < LAYER_TIMEOUT)
+        })
+    }
+
+    pub fn on_packet(&mut self, layer: u8, keyframe: bool, now: Instant) -> LayerDecision {
+        if self.last_seen.len() <= layer as usize {
+            self.last_seen.resize(layer as usize + 1, None);
+        }
+        self.last_seen[layer as usize] = Some(now);
+
+        let target = self.target(now);
+        if target == Some(layer) && self.current != Some(layer) && keyframe {
+            log::info!("LayerSelector switch layer {:?} => {}", self.current, layer);
+            self.current = Some(layer);
+        }
+
+        let request_keyframe = target.is_some()
+            && target != self.current
+            && self
+                .last_keyframe_request
+                .is_none_or(|last| now - last >= KEYFRAME_REQUEST_INTERVAL);
+        if request_keyframe {
+            self.last_keyframe_request = Some(now);
+        }
+
+        LayerDecision {
+            forward: self.current == Some(layer),
+            request_keyframe,
+        }
+    }
+}
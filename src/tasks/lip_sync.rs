@@ -0,0 +1,68 @@
+use std::time::{Duration, Instant};
+
+/// NTP/RTP mapping of the last RTCP sender report of a publisher stream.
+#[derive(Debug, Clone, Copy)]
+pub struct SenderReport {
+    pub ntp_time: Instant,
+    pub rtp_time: u32,
+}
+
+/// Mapped times further than this from the arrival time mean the publisher clock changed.
+const MAX_DRIFT: Duration = Duration::from_secs(5);
+
+/// Derive the wallclock of forwarded packets from the publisher sender reports, so that the
+/// viewer sender reports keep the publisher audio/video sync instead of the arrival timing.
+///
+/// One instance is shared by all tracks of a publisher: the offset between the publisher NTP
+/// clock and ours is taken once, which keeps the relative timing of the tracks.
+#[derive(Default)]
+pub struct LipSync {
+    /// Our time minus the publisher NTP time, in nanoseconds.
+    offset: Option<i128>,
+}
+
+impl LipSync {
+    pub fn wallclock(
+        &mut self,
+        sr: Option<SenderReport>,
+        rtp_time: u32,
+        clock_rate: u32,
+        received: Instant,
+    ) -> Instant {
+        let sr = match sr {
+            Some(sr) => sr,
+            None => return received,
+        };
+        let elapsed_rtp = rtp_time.wrapping_sub(sr.rtp_time) as i32 as i128;
+        let capture = shift(
+            sr.ntp_time,
+            elapsed_rtp * 1_000_000_000 / clock_rate as i128,
+        );
+        let offset = *self.offset.get_or_insert_with(|| diff(received, capture));
+        let wallclock = shift(capture, offset);
+        if diff(received, wallclock).unsigned_abs() > MAX_DRIFT.as_nanos() {
+            log::info!("LipSync publisher clock changed, resetting offset");
+            self.offset = Some(diff(received, capture));
+            return received;
+        }
+        wallclock
+    }
+}
+
+fn diff(a: Instant, b: Instant) -> i128 {
+    if a >= b {
+        (a - b).as_nanos() as i128
+    } else {
+        -((b - a).as_nanos() as i128)
+    }
+}
+
+fn shift(t: Instant, nanos: i128) -> Instant {
+    let delta = Duration::from_nanos(nanos.unsigned_abs() as u64);
+    let shifted = if nanos >= 0 {
+        t.checked_add(delta)
+    } else {
+        t.checked_sub(delta)
+    };
+    shifted.unwrap_or(t)
+}
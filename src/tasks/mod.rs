@@ -5,18 +5,50 @@ use std::{
 
 use bytes::Bytes;
 use str0m::{
-    media::{KeyframeRequestKind, MediaKind, MediaTime},
+    change::SdpOffer,
+    format::Codec,
+    media::{KeyframeRequestKind, MediaKind, MediaTime, Mid, Pt, Rid},
     rtp::{RtpHeader, RtpPacket, SeqNo},
+    Rtc, RtcConfig,
 };
 
-use crate::io::{IoAction, IoEvent};
+use crate::{
+    capture::RtpCapture,
+    http::get_http_header,
+    io::{HttpRequest, IoAction, IoEvent},
+};
 
+pub mod bitrate_allocator;
+pub mod data_channel;
+pub mod layer_selector;
+pub mod lip_sync;
+pub mod packetizer;
+pub mod payload;
+pub mod playback;
+pub mod room;
+pub mod rtmp;
+pub mod rtp_rewriter;
+pub mod speaker;
+pub mod svc_filter;
+pub mod trickle_ice;
 pub mod whep;
 pub mod whip;
 
+/// Simulcast encoding a packet belongs to.
+#[derive(Debug, Clone, Copy)]
+pub struct SimulcastLayer {
+    pub rid: Rid,
+    /// Position of the rid in the publisher `a=simulcast` list, 0 is the lowest quality.
+    pub index: u8,
+}
+
 #[derive(Debug, Clone)]
 pub struct TrackMedia {
     pub track_id: u64,
+    /// Codec of the payload, payload types are only meaningful inside the publisher session.
+    pub codec: Codec,
+    /// Set when the publisher sends simulcast, each layer has its own ssrc and sequence.
+    pub simulcast: Option<SimulcastLayer>,
     /// Extended sequence number to avoid having to deal with ROC.
     pub seq_no: SeqNo,
 
@@ -38,25 +70,184 @@ pub struct TrackMedia {
     /// the packet was first handed over to str0m and enqueued in the outgoing send buffers.
     /// For incoming packets it's the time we received the network packet.
     pub timestamp: Instant,
+
+    /// Last sender report of the publisher stream, to keep its A/V sync on viewers.
+    pub sender_report: Option<lip_sync::SenderReport>,
 }
 
 impl TrackMedia {
-    pub fn from_raw(track_id: u64, rtp: RtpPacket) -> Self {
+    pub fn from_raw(track_id: u64, codec: Codec, rtp: RtpPacket) -> Self {
         let header = rtp.header;
         let payload = rtp.payload;
         let time = rtp.time;
         let timestamp = rtp.timestamp;
         let seq_no = rtp.seq_no;
+        let sender_report = rtp.last_sender_info.map(|info| lip_sync::SenderReport {
+            ntp_time: info.ntp_time,
+            rtp_time: info.rtp_time,
+        });
 
         Self {
             track_id,
+            codec,
+            simulcast: None,
             seq_no,
             time,
             header,
             payload,
             timestamp,
+            sender_report,
+        }
+    }
+}
+
+/// Reasons a session can't be created from an incoming offer.
+#[derive(Debug)]
+pub enum CreateTaskError {
+    /// Request body is not `application/sdp`.
+    UnsupportedContentType(String),
+    /// Request body can't be parsed as an SDP offer.
+    InvalidOffer(String),
+    /// Offer was parsed but can't be negotiated, e.g. no supported codecs.
+    NotAcceptable(String),
+    /// Playback request body is invalid or its file can't be played.
+    InvalidSource(String),
+}
+
+impl CreateTaskError {
+    pub fn status(&self) -> u16 {
+        match self {
+            CreateTaskError::UnsupportedContentType(_) => 415,
+            CreateTaskError::InvalidOffer(_) => 400,
+            CreateTaskError::NotAcceptable(_) => 406,
+            CreateTaskError::InvalidSource(_) => 400,
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            CreateTaskError::UnsupportedContentType(_) => "Unsupported Media Type",
+            CreateTaskError::InvalidOffer(_) => "Invalid SDP offer",
+            CreateTaskError::NotAcceptable(_) => "Offer not acceptable",
+            CreateTaskError::InvalidSource(_) => "Invalid playback source",
         }
     }
+
+    pub fn detail(&self) -> &str {
+        match self {
+            CreateTaskError::UnsupportedContentType(detail)
+            | CreateTaskError::InvalidOffer(detail)
+            | CreateTaskError::NotAcceptable(detail)
+            | CreateTaskError::InvalidSource(detail) => detail,
+        }
+    }
+}
+
+/// Check the request content type and parse the SDP offer in its body.
+pub fn parse_sdp_offer(req: &HttpRequest) -> Result<SdpOffer, CreateTaskError> {
+    let content_type = get_http_header(req, "Content-Type").unwrap_or_default();
+    if !content_type.starts_with("application/sdp") {
+        return Err(CreateTaskError::UnsupportedContentType(format!(
+            "expected application/sdp, got '{content_type}'"
+        )));
+    }
+    let sdp = std::str::from_utf8(&req.body)
+        .map_err(|e| CreateTaskError::InvalidOffer(format!("offer is not utf8: {e}")))?;
+    SdpOffer::from_sdp_string(sdp).map_err(|e| CreateTaskError::InvalidOffer(e.to_string()))
+}
+
+/// Codecs the server can forward, in default preference order.
+pub const SUPPORTED_CODECS: [Codec; 4] = [Codec::Opus, Codec::Vp8, Codec::Vp9, Codec::H264];
+
+/// Map a codec name as used in SDP rtpmap lines or in config to a supported codec.
+pub fn codec_from_name(name: &str) -> Option<Codec> {
+    match name.to_ascii_lowercase().as_str() {
+        "opus" => Some(Codec::Opus),
+        "vp8" => Some(Codec::Vp8),
+        "vp9" => Some(Codec::Vp9),
+        "h264" => Some(Codec::H264),
+        _ => None,
+    }
+}
+
+/// Restrict the codecs the rtc can negotiate.
+pub fn configure_codecs(rtc_config: RtcConfig, codecs: &[Codec]) -> RtcConfig {
+    rtc_config
+        .clear_codecs()
+        .enable_opus(codecs.contains(&Codec::Opus))
+        .enable_vp8(codecs.contains(&Codec::Vp8))
+        .enable_vp9(codecs.contains(&Codec::Vp9))
+        .enable_h264(codecs.contains(&Codec::H264))
+}
+
+/// Check that every audio and video section of the offer contains at least one of `codecs`.
+pub fn check_offer_codecs(sdp: &str, codecs: &[Codec]) -> Result<(), CreateTaskError> {
+    //(kind, offered codec names, has a compatible codec)
+    let mut sections: Vec<(&str, Vec<&str>, bool)> = Vec::new();
+    for line in sdp.lines().map(|l| l.trim()) {
+        if let Some(media) = line.strip_prefix("m=") {
+            let kind = media.split(' ').next().unwrap_or_default();
+            sections.push((kind, Vec::new(), false));
+        } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+            let name = rtpmap
+                .split(' ')
+                .nth(1)
+                .and_then(|format| format.split('/').next())
+                .unwrap_or_default();
+            if let Some((_, names, compatible)) = sections.last_mut() {
+                names.push(name);
+                if codec_from_name(name).is_some_and(|codec| codecs.contains(&codec)) {
+                    *compatible = true;
+                }
+            }
+        }
+    }
+
+    for (kind, names, compatible) in sections {
+        if (kind == "audio" || kind == "video") && !compatible {
+            return Err(CreateTaskError::NotAcceptable(format!(
+                "no acceptable {kind} codec, offered {names:?}, accepted {codecs:?}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Parse the rids of the first `a=simulcast:{direction}` line, in listed order.
+/// Paused rids (prefixed with `~`) are kept so that layer indexes stay stable.
+pub fn parse_simulcast_rids(sdp: &str, direction: &str) -> Vec<Rid> {
+    let prefix = format!("a=simulcast:{direction} ");
+    sdp.lines()
+        .map(|l| l.trim())
+        .find_map(|line| line.strip_prefix(prefix.as_str()))
+        .map(|list| {
+            list.split(';')
+                .filter_map(|alternatives| alternatives.split(',').next())
+                .map(|rid| Rid::from(rid.trim_start_matches('~')))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Payload types negotiated for a media in the session, with their codec.
+pub fn negotiated_codecs(rtc: &Rtc, mid: Mid) -> Vec<(Pt, Codec)> {
+    let remote_pts = rtc
+        .media(mid)
+        .map(|media| media.remote_pts().to_vec())
+        .unwrap_or_default();
+    rtc.codec_config()
+        .params()
+        .iter()
+        .filter(|params| remote_pts.contains(&params.pt()))
+        .map(|params| (params.pt(), params.spec().codec))
+        .collect()
+}
+
+/// Membership change of a room, delivered to every other participant.
+#[derive(Debug, Clone)]
+pub enum RoomEvent {
+    Joined(String),
+    Left(String),
 }
 
 pub enum WebrtcTaskInput<'a> {
@@ -66,6 +257,12 @@ pub enum WebrtcTaskInput<'a> {
         track_id: u64,
         kind: KeyframeRequestKind,
     },
+    RoomEvent(RoomEvent),
+    ChannelData(data_channel::ChannelMessage),
+    /// Start or stop capturing the RTP packets of the session.
+    Capture(Option<RtpCapture>),
+    /// Close the session, the task must answer with `WebrtcTaskOutput::TaskEnded`.
+    EndTask,
 }
 
 impl<'a> From<IoEvent<'a>> for WebrtcTaskInput<'a> {
@@ -78,12 +275,23 @@ pub enum WebrtcTaskOutput {
     Io(IoAction),
     TrackMedia(TrackMedia),
     TaskEnded,
+    /// Codecs negotiated by a publisher, recorded as channel metadata when it connects.
+    PublishCodecs(Vec<Codec>),
+    /// Local ice credentials changed after an ICE restart.
+    IceRestart {
+        old_ufrag: String,
+        new_ufrag: String,
+    },
     PublishTrack {
         track_id: u64,
     },
     SubscribeTrack {
         track_id: u64,
     },
+    UnsubscribeTrack {
+        track_id: u64,
+    },
+    ChannelData(data_channel::ChannelMessage),
     RequestKeyframeTrack {
         track_id: u64,
         kind: KeyframeRequestKind,
@@ -108,6 +316,24 @@ pub trait WebrtcTask {
 pub enum ComposeTask {
     Whip(whip::WhipServerTask),
     Whep(whep::WhepServerTask),
+    Room(room::RoomTask),
+    Playback(playback::PlaybackTask),
+    Rtmp(rtmp::RtmpTask),
+}
+
+impl ComposeTask {
+    /// First segment of the resource path of the session, see [`build_resource_path`].
+    ///
+    /// [`build_resource_path`]: crate::http::build_resource_path
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ComposeTask::Whip(_) => "whip",
+            ComposeTask::Whep(_) => "whep",
+            ComposeTask::Room(_) => "room",
+            ComposeTask::Playback(_) => "playback",
+            ComposeTask::Rtmp(_) => "rtmp",
+        }
+    }
 }
 
 impl WebrtcTask for ComposeTask {
@@ -115,6 +341,9 @@ impl WebrtcTask for ComposeTask {
         match self {
             ComposeTask::Whip(task) => task.ufrag(),
             ComposeTask::Whep(task) => task.ufrag(),
+            ComposeTask::Room(task) => task.ufrag(),
+            ComposeTask::Playback(task) => task.ufrag(),
+            ComposeTask::Rtmp(task) => task.ufrag(),
         }
     }
 
@@ -122,6 +351,9 @@ impl WebrtcTask for ComposeTask {
         match self {
             ComposeTask::Whip(task) => task.tick(instant),
             ComposeTask::Whep(task) => task.tick(instant),
+            ComposeTask::Room(task) => task.tick(instant),
+            ComposeTask::Playback(task) => task.tick(instant),
+            ComposeTask::Rtmp(task) => task.tick(instant),
         }
     }
 
@@ -129,6 +361,9 @@ impl WebrtcTask for ComposeTask {
         match self {
             ComposeTask::Whip(task) => task.input(now, event),
             ComposeTask::Whep(task) => task.input(now, event),
+            ComposeTask::Room(task) => task.input(now, event),
+            ComposeTask::Playback(task) => task.input(now, event),
+            ComposeTask::Rtmp(task) => task.input(now, event),
         }
     }
 
@@ -136,6 +371,9 @@ impl WebrtcTask for ComposeTask {
         match self {
             ComposeTask::Whip(task) => task.pop_action(now),
             ComposeTask::Whep(task) => task.pop_action(now),
+            ComposeTask::Room(task) => task.pop_action(now),
+            ComposeTask::Playback(task) => task.pop_action(now),
+            ComposeTask::Rtmp(task) => task.pop_action(now),
         }
     }
 }
@@ -0,0 +1,231 @@
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use str0m::{
+    format::Codec,
+    media::MediaTime,
+    rtp::{RtpHeader, Ssrc},
+};
+
+use crate::tasks::TrackMedia;
+
+/// Max RTP payload size, leaving room for the headers and SRTP in a 1280 bytes MTU.
+const MAX_PAYLOAD: usize = 1100;
+
+/// Split the frames of a file or RTMP track into RTP payloads, as a WebRTC publisher would.
+pub struct Packetizer {
+    track_id: u64,
+    codec: Codec,
+    clock_rate: u64,
+    ssrc: Ssrc,
+    seq_no: u64,
+    picture_id: u16,
+    /// Length of the H264 NAL unit size fields.
+    nalu_length_size: usize,
+    /// H264 SPS and PPS from the avcC, sent before each keyframe.
+    parameter_sets: Vec<Vec<u8>>,
+}
+
+impl Packetizer {
+    pub fn new(track_id: u64, codec: Codec, codec_private: Option<&[u8]>, ssrc: u32) -> Self {
+        let (nalu_length_size, parameter_sets) = codec_private
+            .filter(|_| codec == Codec::H264)
+            .and_then(parse_avcc)
+            .unwrap_or((4, Vec::new()));
+        Self {
+            track_id,
+            codec,
+            clock_rate: if codec.is_video() { 90000 } else { 48000 },
+            ssrc: ssrc.into(),
+            seq_no: 0,
+            picture_id: 0,
+            nalu_length_size,
+            parameter_sets,
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Replace the H264 avcC, e.g. after a resolution change, keeping the RTP sequence.
+    pub fn set_codec_private(&mut self, codec_private: &[u8]) {
+        if self.codec != Codec::H264 {
+            return;
+        }
+        if let Some((nalu_length_size, parameter_sets)) = parse_avcc(codec_private) {
+            self.nalu_length_size = nalu_length_size;
+            self.parameter_sets = parameter_sets;
+        }
+    }
+
+    pub fn packetize(&mut self, frame: &[u8], pts: Duration, now: Instant) -> Vec<TrackMedia> {
+        let payloads = match self.codec {
+            Codec::Vp8 => self.vp8_payloads(frame),
+            Codec::Vp9 => self.vp9_payloads(frame),
+            Codec::H264 => self.h264_payloads(frame),
+            _ => vec![frame.to_vec()],
+        };
+        self.picture_id = (self.picture_id + 1) & 0x7fff;
+
+        let rtp_time = (pts.as_nanos() * self.clock_rate as u128 / 1_000_000_000) as u64;
+        let count = payloads.len();
+        payloads
+            .into_iter()
+            .enumerate()
+            .map(|(index, payload)| {
+                let seq_no = self.seq_no;
+                self.seq_no += 1;
+                TrackMedia {
+                    track_id: self.track_id,
+                    codec: self.codec,
+                    simulcast: None,
+                    seq_no: seq_no.into(),
+                    time: MediaTime::new(rtp_time as i64, self.clock_rate as i64),
+                    header: RtpHeader {
+                        //audio has no marker, video marks the last packet of a frame
+                        marker: self.codec.is_video() && index + 1 == count,
+                        sequence_number: seq_no as u16,
+                        timestamp: rtp_time as u32,
+                        ssrc: self.ssrc,
+                        ..Default::default()
+                    },
+                    payload: Bytes::from(payload),
+                    timestamp: now,
+                    sender_report: None,
+                }
+            })
+            .collect()
+    }
+
+    /// VP8 payloads with a 15 bits picture id, see RFC 7741 section 4.2.
+    fn vp8_payloads(&self, frame: &[u8]) -> Vec<Vec<u8>> {
+        frame
+            .chunks(MAX_PAYLOAD)
+            .enumerate()
+            .map(|(index, chunk)| {
+                //X, S on the first packet of the frame, then I
+                let first = if index == 0 { 0x90 } else { 0x80 };
+                let mut payload = vec![
+                    first,
+                    0x80,
+                    0x80 | (self.picture_id >> 8) as u8,
+                    self.picture_id as u8,
+                ];
+                payload.extend_from_slice(chunk);
+                payload
+            })
+            .collect()
+    }
+
+    /// VP9 payloads in non-flexible mode without layers, see RFC 9628 section 4.2.
+    /// Superframes are sent as one frame, decoders split them.
+    fn vp9_payloads(&self, frame: &[u8]) -> Vec<Vec<u8>> {
+        let inter = !vp9_is_keyframe(frame);
+        let chunks: Vec<&[u8]> = frame.chunks(MAX_PAYLOAD).collect();
+        let count = chunks.len();
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                //I|P|L|F|B|E|V|Z
+                let mut first = 0x80;
+                if inter {
+                    first |= 0x40;
+                }
+                if index == 0 {
+                    first |= 0x08;
+                }
+                if index + 1 == count {
+                    first |= 0x04;
+                }
+                let mut payload = vec![
+                    first,
+                    0x80 | (self.picture_id >> 8) as u8,
+                    self.picture_id as u8,
+                ];
+                payload.extend_from_slice(chunk);
+                payload
+            })
+            .collect()
+    }
+
+    /// H264 single NAL unit and FU-A payloads, see RFC 6184 section 5.
+    fn h264_payloads(&self, frame: &[u8]) -> Vec<Vec<u8>> {
+        let mut nalus = Vec::new();
+        let mut offset = 0;
+        while offset + self.nalu_length_size <= frame.len() {
+            let len = frame[offset..offset + self.nalu_length_size]
+                .iter()
+                .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+            offset += self.nalu_length_size;
+            if len == 0 || offset + len > frame.len() {
+                break;
+            }
+            nalus.push(&frame[offset..offset + len]);
+            offset += len;
+        }
+        let keyframe = nalus.iter().any(|nalu| nalu[0] & 0x1f == 5);
+        let has_parameter_sets = nalus.iter().any(|nalu| nalu[0] & 0x1f == 7);
+        if keyframe && !has_parameter_sets {
+            let parameter_sets = self.parameter_sets.iter().map(|nalu| nalu.as_slice());
+            nalus.splice(0..0, parameter_sets);
+        }
+
+        let mut payloads = Vec::new();
+        for nalu in nalus {
+            if nalu.len() <= MAX_PAYLOAD {
+                payloads.push(nalu.to_vec());
+                continue;
+            }
+            let indicator = (nalu[0] & 0xe0) | 28;
+            let nalu_type = nalu[0] & 0x1f;
+            let chunks: Vec<&[u8]> = nalu[1..].chunks(MAX_PAYLOAD - 2).collect();
+            let count = chunks.len();
+            for (index, chunk) in chunks.into_iter().enumerate() {
+                let mut header = nalu_type;
+                if index == 0 {
+                    header |= 0x80;
+                }
+                if index + 1 == count {
+                    header |= 0x40;
+                }
+                let mut payload = vec![indicator, header];
+                payload.extend_from_slice(chunk);
+                payloads.push(payload);
+            }
+        }
+        payloads
+    }
+}
+
+/// Check the frame_type of the VP9 uncompressed header of the first frame.
+fn vp9_is_keyframe(frame: &[u8]) -> bool {
+    let first = match frame.first() {
+        Some(first) => *first,
+        None => return false,
+    };
+    //frame_marker(2) profile_low(1) profile_high(1) [reserved(1)] show_existing(1) frame_type(1)
+    let profile = ((first >> 5) & 0x01) | ((first >> 3) & 0x02);
+    let show_existing_bit = if profile == 3 { 2 } else { 3 };
+    first & (1 << show_existing_bit) == 0 && first & (1 << (show_existing_bit - 1)) == 0
+}
+
+/// NAL unit length size and parameter sets of an avcC, see ISO/IEC 14496-15 5.2.4.1.
+fn parse_avcc(avcc: &[u8]) -> Option<(usize, Vec<Vec<u8>>)> {
+    let nalu_length_size = (*avcc.get(4)? & 0x03) as usize + 1;
+    let mut parameter_sets = Vec::new();
+    let mut offset = 5;
+    //SPS count in the low 5 bits, then the PPS count
+    for mask in [0x1f, 0xff] {
+        let count = *avcc.get(offset)? & mask;
+        offset += 1;
+        for _ in 0..count {
+            let len = u16::from_be_bytes([*avcc.get(offset)?, *avcc.get(offset + 1)?]) as usize;
+            offset += 2;
+            parameter_sets.push(avcc.get(offset..offset + len)?.to_vec());
+            offset += len;
+        }
+    }
+    Some((nalu_length_size, parameter_sets))
+}
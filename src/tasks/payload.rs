@@ -0,0 +1,273 @@
+use str0m::format::Codec;
+
+/// Check if the RTP payload is the first packet of a keyframe.
+pub fn is_keyframe_start(codec: Codec, payload: &[u8]) -> bool {
+    match codec {
+        Codec::Vp8 => vp8_is_keyframe_start(payload),
+        Codec::Vp9 => vp9_is_keyframe_start(payload),
+        Codec::H264 => h264_is_keyframe_start(payload),
+        _ => false,
+    }
+}
+
+fn vp8_is_keyframe_start(payload: &[u8]) -> bool {
+    let first = match payload.first() {
+        Some(first) => *first,
+        None => return false,
+    };
+    //S bit set and partition index 0
+    if first & 0x10 == 0 || first & 0x07 != 0 {
+        return false;
+    }
+    //P bit of the VP8 payload header is 0 for keyframes
+    vp8_descriptor_len(payload)
+        .and_then(|offset| payload.get(offset))
+        .is_some_and(|header| header & 0x01 == 0)
+}
+
+/// Length of the VP8 payload descriptor, see RFC 7741 section 4.2.
+pub fn vp8_descriptor_len(payload: &[u8]) -> Option<usize> {
+    let first = *payload.first()?;
+    let mut offset = 1;
+    if first & 0x80 != 0 {
+        let ext = *payload.get(offset)?;
+        offset += 1;
+        if ext & 0x80 != 0 {
+            //picture id, 2 bytes if M bit is set
+            offset += if *payload.get(offset)? & 0x80 != 0 {
+                2
+            } else {
+                1
+            };
+        }
+        if ext & 0x40 != 0 {
+            offset += 1;
+        }
+        if ext & 0x30 != 0 {
+            offset += 1;
+        }
+    }
+    (offset <= payload.len()).then_some(offset)
+}
+
+fn vp9_is_keyframe_start(payload: &[u8]) -> bool {
+    //I|P|L|F|B|E|V|Z: not inter-picture predicted and beginning of a frame
+    payload
+        .first()
+        .is_some_and(|first| first & 0x40 == 0 && first & 0x08 != 0)
+}
+
+/// Length of the VP9 payload descriptor, see RFC 9628 section 4.2.
+pub fn vp9_descriptor_len(payload: &[u8]) -> Option<usize> {
+    let first = *payload.first()?;
+    let mut offset = 1;
+    if first & 0x80 != 0 {
+        offset += if *payload.get(offset)? & 0x80 != 0 {
+            2
+        } else {
+            1
+        };
+    }
+    if first & 0x20 != 0 {
+        //layer indices, followed by TL0PICIDX in non-flexible mode
+        offset += if first & 0x10 == 0 { 2 } else { 1 };
+    }
+    if first & 0x10 != 0 && first & 0x40 != 0 {
+        //reference indices, N bit set when another one follows
+        loop {
+            let p_diff = *payload.get(offset)?;
+            offset += 1;
+            if p_diff & 0x01 == 0 {
+                break;
+            }
+        }
+    }
+    if first & 0x02 != 0 {
+        //scalability structure
+        let ss = *payload.get(offset)?;
+        offset += 1;
+        if ss & 0x10 != 0 {
+            offset += 4 * ((ss >> 5) as usize + 1);
+        }
+        if ss & 0x08 != 0 {
+            let pictures = *payload.get(offset)?;
+            offset += 1;
+            for _ in 0..pictures {
+                let picture = *payload.get(offset)?;
+                offset += 1 + ((picture >> 2) & 0x03) as usize;
+            }
+        }
+    }
+    (offset <= payload.len()).then_some(offset)
+}
+
+fn h264_is_keyframe_start(payload: &[u8]) -> bool {
+    let nalu_type = match payload.first() {
+        Some(first) => first & 0x1f,
+        None => return false,
+    };
+    match nalu_type {
+        //IDR or SPS
+        5 | 7 => true,
+        //STAP-A: check every aggregated NALU
+        24 => {
+            let mut offset = 1;
+            while offset + 2 < payload.len() {
+                let size = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+                if matches!(payload[offset + 2] & 0x1f, 5 | 7) {
+                    return true;
+                }
+                offset += 2 + size;
+            }
+            false
+        }
+        //FU-A: start of a fragmented IDR
+        28 => payload
+            .get(1)
+            .is_some_and(|header| header & 0x80 != 0 && header & 0x1f == 5),
+        _ => false,
+    }
+}
+
+/// Layer information of a VP9 payload descriptor, see RFC 9628 section 4.2.
+#[derive(Debug, Clone, Copy)]
+pub struct Vp9Layer {
+    pub spatial: u8,
+    pub temporal: u8,
+    /// U bit: the decoder can switch up to this temporal layer here.
+    pub switching_up: bool,
+    pub begin_frame: bool,
+    pub end_frame: bool,
+    /// Not inter-picture predicted.
+    pub intra: bool,
+}
+
+/// Parse the layer indices of a VP9 payload, None if the packet carries no layer indices.
+pub fn parse_vp9_layer(payload: &[u8]) -> Option<Vp9Layer> {
+    let first = *payload.first()?;
+    //L bit: layer indices present
+    if first & 0x20 == 0 {
+        return None;
+    }
+    let mut offset = 1;
+    if first & 0x80 != 0 {
+        //picture id, 2 bytes if M bit is set
+        offset += if payload.get(offset)? & 0x80 != 0 {
+            2
+        } else {
+            1
+        };
+    }
+    let layer = *payload.get(offset)?;
+    Some(Vp9Layer {
+        spatial: (layer >> 1) & 0x07,
+        temporal: layer >> 5,
+        switching_up: layer & 0x10 != 0,
+        begin_frame: first & 0x08 != 0,
+        end_frame: first & 0x04 != 0,
+        intra: first & 0x40 == 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use str0m::format::Codec;
+
+    use super::{is_keyframe_start, parse_vp9_layer, vp8_descriptor_len, vp9_descriptor_len};
+
+    #[test]
+    fn vp8_keyframe_start() {
+        assert!(is_keyframe_start(Codec::Vp8, &[0x10, 0x00, 0xaa]));
+        //interframe
+        assert!(!is_keyframe_start(Codec::Vp8, &[0x10, 0x01, 0xaa]));
+        //not the start of a partition 0
+        assert!(!is_keyframe_start(Codec::Vp8, &[0x00, 0x00, 0xaa]));
+        assert!(!is_keyframe_start(Codec::Vp8, &[0x11, 0x00, 0xaa]));
+        //extended descriptor with a 15 bits picture id
+        assert!(is_keyframe_start(
+            Codec::Vp8,
+            &[0x90, 0x80, 0x81, 0x23, 0x00]
+        ));
+        assert!(!is_keyframe_start(Codec::Vp8, &[]));
+    }
+
+    #[test]
+    fn vp8_descriptor() {
+        assert_eq!(vp8_descriptor_len(&[0x10, 0x00]), Some(1));
+        //picture id with M bit, TL0PICIDX, TID/KEYIDX
+        assert_eq!(
+            vp8_descriptor_len(&[0x90, 0xf0, 0x81, 0x23, 0x05, 0x40, 0x00]),
+            Some(6)
+        );
+        assert_eq!(vp8_descriptor_len(&[0x90, 0x80]), None);
+    }
+
+    #[test]
+    fn vp9_keyframe_start() {
+        assert!(is_keyframe_start(Codec::Vp9, &[0x08, 0xaa]));
+        //inter-picture predicted
+        assert!(!is_keyframe_start(Codec::Vp9, &[0x48, 0xaa]));
+        //not the beginning of a frame
+        assert!(!is_keyframe_start(Codec::Vp9, &[0x04, 0xaa]));
+    }
+
+    #[test]
+    fn vp9_descriptor() {
+        assert_eq!(vp9_descriptor_len(&[0x0c, 0xaa]), Some(1));
+        //15 bits picture id, layer indices and TL0PICIDX in non-flexible mode
+        assert_eq!(vp9_descriptor_len(&[0xa8, 0x81, 0x23, 0x02, 0x07]), Some(5));
+        //flexible mode, two reference indices
+        assert_eq!(vp9_descriptor_len(&[0x78, 0x02, 0x03, 0x04, 0xaa]), Some(4));
+        //scalability structure with one spatial layer size and no picture group
+        assert_eq!(
+            vp9_descriptor_len(&[0x0a, 0x10, 0x01, 0x40, 0x00, 0xf0, 0xaa]),
+            Some(6)
+        );
+        assert_eq!(vp9_descriptor_len(&[0x80]), None);
+    }
+
+    #[test]
+    fn vp9_layer() {
+        let layer = parse_vp9_layer(&[0x2c, 0x32, 0x00]).expect("Should have layer indices");
+        assert_eq!(layer.spatial, 1);
+        assert_eq!(layer.temporal, 1);
+        assert!(layer.switching_up);
+        assert!(layer.begin_frame);
+        assert!(layer.end_frame);
+        assert!(layer.intra);
+
+        let layer = parse_vp9_layer(&[0xe0, 0x81, 0x23, 0x44]).expect("Should have layer indices");
+        assert_eq!(layer.spatial, 2);
+        assert_eq!(layer.temporal, 2);
+        assert!(!layer.switching_up);
+        assert!(!layer.intra);
+
+        assert!(parse_vp9_layer(&[0x0c, 0x32]).is_none());
+    }
+
+    #[test]
+    fn h264_keyframe_start() {
+        //IDR and SPS
+        assert!(is_keyframe_start(Codec::H264, &[0x65, 0x88]));
+        assert!(is_keyframe_start(Codec::H264, &[0x67, 0x42]));
+        assert!(!is_keyframe_start(Codec::H264, &[0x41, 0x9a]));
+        //STAP-A with PPS then SPS
+        assert!(is_keyframe_start(
+            Codec::H264,
+            &[0x78, 0x00, 0x02, 0x68, 0xce, 0x00, 0x02, 0x67, 0x42]
+        ));
+        assert!(!is_keyframe_start(
+            Codec::H264,
+            &[0x78, 0x00, 0x02, 0x68, 0xce]
+        ));
+        //FU-A start, middle of an IDR, start of a non-IDR slice
+        assert!(is_keyframe_start(Codec::H264, &[0x7c, 0x85, 0x88]));
+        assert!(!is_keyframe_start(Codec::H264, &[0x7c, 0x05, 0x88]));
+        assert!(!is_keyframe_start(Codec::H264, &[0x7c, 0x81, 0x9a]));
+    }
+
+    #[test]
+    fn audio_is_never_keyframe() {
+        assert!(!is_keyframe_start(Codec::Opus, &[0x08]));
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam::channel::{Receiver, Sender, TryRecvError};
+use serde::Deserialize;
+use str0m::{format::Codec, media::MediaKind};
+
+use crate::{
+    http::get_resource_sub_path,
+    io::{HttpRequest, HttpResponse, IoAction, IoEvent},
+};
+
+use self::demuxer::{Demuxer, SourceFrame};
+
+use super::{
+    packetizer::Packetizer, track_id_builder, CreateTaskError, WebrtcTask, WebrtcTaskInput,
+    WebrtcTaskOutput,
+};
+
+mod demuxer;
+
+/// Frames read ahead of their play time.
+const QUEUE_SIZE: usize = 256;
+/// Pause between the end of the file and its next loop.
+const LOOP_GAP: Duration = Duration::from_millis(20);
+
+/// Body of a playback request.
+#[derive(Debug, Deserialize)]
+struct PlaybackRequest {
+    /// File path relative to the playback directory.
+    file: String,
+    #[serde(rename = "loop", default)]
+    looping: bool,
+}
+
+/// Publish a media file to a channel in real time, as if it came from a WHIP publisher.
+///
+/// The file is read and demuxed on a dedicated thread. The task sends the frames when their
+/// presentation time is reached, so viewers and the GOP cache see a normal live channel.
+/// Keyframe requests are ignored, viewers wait for the next keyframe of the file.
+pub struct PlaybackTask {
+    resource_path: String,
+    frames: Receiver<SourceFrame>,
+    next: Option<SourceFrame>,
+    started: Instant,
+    packetizers: HashMap<MediaKind, Packetizer>,
+    outputs: VecDeque<WebrtcTaskOutput>,
+    ended: bool,
+}
+
+impl PlaybackTask {
+    pub fn new(
+        req: HttpRequest,
+        channel: String,
+        resource_path: String,
+        dir: &Path,
+        codecs: &[Codec],
+    ) -> Result<PlaybackTask, CreateTaskError> {
+        let request: PlaybackRequest = serde_json::from_slice(&req.body)
+            .map_err(|e| CreateTaskError::InvalidSource(e.to_string()))?;
+        //keep requests from reading files outside of the playback directory
+        let relative = Path::new(&request.file);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(CreateTaskError::InvalidSource(format!(
+                "file {} must be relative to the playback directory",
+                request.file
+            )));
+        }
+        let path = dir.join(relative);
+        let demuxer = Demuxer::open(&path).map_err(|e| {
+            CreateTaskError::InvalidSource(format!("can't play {}: {e}", request.file))
+        })?;
+
+        let tracks = demuxer.tracks();
+        if let Some(track) = tracks.iter().find(|track| !codecs.contains(&track.codec)) {
+            return Err(CreateTaskError::NotAcceptable(format!(
+                "codec {:?} of {} is not allowed, accepted {codecs:?}",
+                track.codec, request.file
+            )));
+        }
+
+        //a new ssrc per playback, so viewers see a source switch when it is restarted
+        let ssrc_seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let mut outputs: VecDeque<WebrtcTaskOutput> =
+            VecDeque::from(vec![IoAction::HttpResponse(HttpResponse {
+                req_id: req.req_id,
+                status: 201,
+                headers: HashMap::from([("Location".to_string(), resource_path.clone())]),
+                body: Vec::new(),
+            })
+            .into()]);
+        let mut publish_codecs: Vec<Codec> = tracks.iter().map(|track| track.codec).collect();
+        //viewers offering audio would be rejected without an audio codec, they get silence
+        if !tracks.iter().any(|track| track.kind == MediaKind::Audio)
+            && codecs.contains(&Codec::Opus)
+        {
+            publish_codecs.push(Codec::Opus);
+        }
+        outputs.push_back(WebrtcTaskOutput::PublishCodecs(publish_codecs));
+        let mut packetizers = HashMap::new();
+        for track in &tracks {
+            let track_id = track_id_builder(&channel, track.kind);
+            outputs.push_back(WebrtcTaskOutput::PublishTrack { track_id });
+            packetizers.insert(
+                track.kind,
+                Packetizer::new(
+                    track_id,
+                    track.codec,
+                    track.codec_private.as_deref(),
+                    track_id as u32 ^ ssrc_seed,
+                ),
+            );
+        }
+
+        log::info!(
+            "Playing {} to channel {channel}, tracks {:?}, loop {}",
+            path.display(),
+            tracks.iter().map(|track| track.codec).collect::<Vec<_>>(),
+            request.looping
+        );
+        let (sender, frames) = crossbeam::channel::bounded(QUEUE_SIZE);
+        std::thread::spawn(move || read_frames(path, demuxer, request.looping, sender));
+
+        Ok(PlaybackTask {
+            resource_path,
+            frames,
+            next: None,
+            started: Instant::now(),
+            packetizers,
+            outputs,
+            ended: false,
+        })
+    }
+
+    fn end(&mut self) {
+        if !self.ended {
+            self.ended = true;
+            self.outputs.push_back(WebrtcTaskOutput::TaskEnded);
+        }
+    }
+}
+
+/// Reader thread of a [`PlaybackTask`], blocked by the bounded queue until frames are played.
+fn read_frames(path: PathBuf, mut demuxer: Demuxer, looping: bool, sender: Sender<SourceFrame>) {
+    let mut offset = Duration::ZERO;
+    let mut end = Duration::ZERO;
+    let mut loop_frames = 0;
+    loop {
+        match demuxer.next_frame() {
+            Ok(Some(mut frame)) => {
+                frame.pts += offset;
+                end = end.max(frame.pts);
+                loop_frames += 1;
+                //the task ended
+                if sender.send(frame).is_err() {
+                    return;
+                }
+            }
+            Ok(None) if looping && loop_frames > 0 => {
+                log::debug!("Playback {} loops", path.display());
+                offset = end + LOOP_GAP;
+                loop_frames = 0;
+                demuxer = match Demuxer::open(&path) {
+                    Ok(demuxer) => demuxer,
+                    Err(e) => {
+                        log::error!("Playback {} can't reopen file: {e}", path.display());
+                        return;
+                    }
+                };
+            }
+            Ok(None) => {
+                log::info!("Playback {} reached the end of the file", path.display());
+                return;
+            }
+            Err(e) => {
+                log::error!("Playback {} read failed: {e}", path.display());
+                return;
+            }
+        }
+    }
+}
+
+impl WebrtcTask for PlaybackTask {
+    fn ufrag(&self) -> String {
+        //not an ICE session, the resource path can't collide with an ICE ufrag
+        self.resource_path.clone()
+    }
+
+    fn tick(&mut self, now: Instant) -> bool {
+        if self.ended {
+            return false;
+        }
+        let elapsed = now.saturating_duration_since(self.started);
+        loop {
+            if self.next.is_none() {
+                match self.frames.try_recv() {
+                    Ok(frame) => self.next = Some(frame),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.end();
+                        break;
+                    }
+                }
+            }
+            match self.next.take() {
+                Some(frame) if frame.pts <= elapsed => {
+                    if let Some(packetizer) = self.packetizers.get_mut(&frame.kind) {
+                        for media in packetizer.packetize(&frame.data, frame.pts, now) {
+                            self.outputs.push_back(WebrtcTaskOutput::TrackMedia(media));
+                        }
+                    }
+                }
+                next => {
+                    self.next = next;
+                    break;
+                }
+            }
+        }
+        !self.outputs.is_empty()
+    }
+
+    fn input<'b>(&mut self, _now: Instant, event: WebrtcTaskInput<'b>) -> bool {
+        match event {
+            WebrtcTaskInput::Io(IoEvent::HttpRequest(req)) => {
+                let status = if !get_resource_sub_path(&req.path).is_empty() {
+                    404
+                } else if req.method == "DELETE" {
+                    log::info!("PlaybackTask received delete request, stopping");
+                    self.end();
+                    200
+                } else {
+                    405
+                };
+                self.outputs.push_front(
+                    IoAction::HttpResponse(HttpResponse {
+                        req_id: req.req_id,
+                        status,
+                        headers: Default::default(),
+                        body: Vec::new(),
+                    })
+                    .into(),
+                );
+                true
+            }
+            WebrtcTaskInput::EndTask => {
+                log::info!("PlaybackTask ending by request");
+                self.end();
+                true
+            }
+            //nothing to capture and no encoder to ask for a keyframe
+            WebrtcTaskInput::RequestKeyframeTrack { .. } | WebrtcTaskInput::Capture(_) => false,
+            //no WebRTC peer, so no socket, room or data channel events
+            _ => false,
+        }
+    }
+
+    fn pop_action(&mut self, _now: Instant) -> Option<WebrtcTaskOutput> {
+        self.outputs.pop_front()
+    }
+}
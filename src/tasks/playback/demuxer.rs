@@ -0,0 +1,646 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read},
+    path::Path,
+    time::Duration,
+};
+
+use str0m::{format::Codec, media::MediaKind};
+
+const SEGMENT: u32 = 0x18538067;
+const INFO: u32 = 0x1549a966;
+const TIMESTAMP_SCALE: u32 = 0x2ad7b1;
+const TRACKS: u32 = 0x1654ae6b;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_NUMBER: u32 = 0xd7;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63a2;
+const CLUSTER: u32 = 0x1f43b675;
+const TIMESTAMP: u32 = 0xe7;
+const BLOCK_GROUP: u32 = 0xa0;
+const BLOCK: u32 = 0xa1;
+const SIMPLE_BLOCK: u32 = 0xa3;
+/// Elements bigger than this are not read in memory.
+const MAX_ELEMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A track of the played file.
+#[derive(Debug, Clone)]
+pub struct SourceTrack {
+    pub kind: MediaKind,
+    pub codec: Codec,
+    /// avcC of an H264 track, for the parameter sets sent before keyframes.
+    pub codec_private: Option<Vec<u8>>,
+}
+
+/// A frame of the played file, in the format of the container.
+pub struct SourceFrame {
+    pub kind: MediaKind,
+    pub data: Vec<u8>,
+    /// Presentation time since the start of the file.
+    pub pts: Duration,
+}
+
+/// Reader of the media files which can be played, chosen from the file extension.
+pub enum Demuxer {
+    Ivf(IvfReader),
+    Ogg(OggReader),
+    Webm(WebmReader),
+}
+
+impl Demuxer {
+    pub fn open(path: &Path) -> io::Result<Demuxer> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let file = BufReader::new(File::open(path)?);
+        match extension.as_str() {
+            "ivf" => Ok(Demuxer::Ivf(IvfReader::new(file)?)),
+            "ogg" | "opus" => Ok(Demuxer::Ogg(OggReader::new(file)?)),
+            "webm" | "mkv" => Ok(Demuxer::Webm(WebmReader::new(file)?)),
+            _ => Err(invalid(format!("unsupported file extension '{extension}'"))),
+        }
+    }
+
+    pub fn tracks(&self) -> Vec<SourceTrack> {
+        match self {
+            Demuxer::Ivf(reader) => vec![SourceTrack {
+                kind: MediaKind::Video,
+                codec: reader.codec,
+                codec_private: None,
+            }],
+            Demuxer::Ogg(_) => vec![SourceTrack {
+                kind: MediaKind::Audio,
+                codec: Codec::Opus,
+                codec_private: None,
+            }],
+            Demuxer::Webm(reader) => reader
+                .tracks
+                .iter()
+                .map(|track| track.source.clone())
+                .collect(),
+        }
+    }
+
+    /// Next frame in file order, None at the end of the file.
+    pub fn next_frame(&mut self) -> io::Result<Option<SourceFrame>> {
+        match self {
+            Demuxer::Ivf(reader) => reader.next_frame(),
+            Demuxer::Ogg(reader) => reader.next_frame(),
+            Demuxer::Webm(reader) => reader.next_frame(),
+        }
+    }
+}
+
+fn invalid(detail: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, detail)
+}
+
+/// Read exactly `buf.len()` bytes, false on a clean end of file.
+fn read_or_eof(file: &mut BufReader<File>, buf: &mut [u8]) -> io::Result<bool> {
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_vec(file: &mut BufReader<File>, len: u64) -> io::Result<Vec<u8>> {
+    if len > MAX_ELEMENT_SIZE {
+        return Err(invalid(format!("element of {len} bytes is too big")));
+    }
+    let mut data = vec![0; len as usize];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// IVF file of VP8 or VP9 frames, as written by libvpx and ffmpeg.
+pub struct IvfReader {
+    file: BufReader<File>,
+    codec: Codec,
+    /// Time base of the frame timestamps, numerator / denominator seconds.
+    timebase: (u64, u64),
+}
+
+impl IvfReader {
+    fn new(mut file: BufReader<File>) -> io::Result<Self> {
+        let mut header = [0; 32];
+        file.read_exact(&mut header)?;
+        if &header[0..4] != b"DKIF" {
+            return Err(invalid("not an IVF file".to_string()));
+        }
+        let header_len = u16::from_le_bytes([header[6], header[7]]) as u64;
+        let codec = match &header[8..12] {
+            b"VP80" => Codec::Vp8,
+            b"VP90" => Codec::Vp9,
+            fourcc => {
+                return Err(invalid(format!(
+                    "unsupported IVF codec {}",
+                    String::from_utf8_lossy(fourcc)
+                )))
+            }
+        };
+        let denominator = u32::from_le_bytes([header[16], header[17], header[18], header[19]]);
+        let numerator = u32::from_le_bytes([header[20], header[21], header[22], header[23]]);
+        if denominator == 0 || numerator == 0 {
+            return Err(invalid("invalid IVF time base".to_string()));
+        }
+        if header_len > 32 {
+            read_vec(&mut file, header_len - 32)?;
+        }
+        Ok(Self {
+            file,
+            codec,
+            timebase: (numerator as u64, denominator as u64),
+        })
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<SourceFrame>> {
+        let mut header = [0; 12];
+        if !read_or_eof(&mut self.file, &mut header)? {
+            return Ok(None);
+        }
+        let size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let pts = u64::from_le_bytes(header[4..12].try_into().expect("Should be 8 bytes"));
+        let data = read_vec(&mut self.file, size as u64)?;
+        let (numerator, denominator) = self.timebase;
+        let nanos = pts as u128 * numerator as u128 * 1_000_000_000 / denominator as u128;
+        Ok(Some(SourceFrame {
+            kind: MediaKind::Video,
+            data,
+            pts: Duration::from_nanos(nanos as u64),
+        }))
+    }
+}
+
+/// Ogg Opus file, only the first logical stream is played.
+pub struct OggReader {
+    file: BufReader<File>,
+    serial: Option<u32>,
+    packets: VecDeque<Vec<u8>>,
+    /// Packet continued on the next page.
+    partial: Vec<u8>,
+    pts: Duration,
+}
+
+impl OggReader {
+    fn new(file: BufReader<File>) -> io::Result<Self> {
+        let mut reader = Self {
+            file,
+            serial: None,
+            packets: VecDeque::new(),
+            partial: Vec::new(),
+            pts: Duration::ZERO,
+        };
+        let head = reader.next_packet()?.unwrap_or_default();
+        if !head.starts_with(b"OpusHead") {
+            return Err(invalid("not an Ogg Opus file".to_string()));
+        }
+        //comment header
+        reader.next_packet()?;
+        Ok(reader)
+    }
+
+    fn next_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while self.packets.is_empty() {
+            let mut header = [0; 27];
+            if !read_or_eof(&mut self.file, &mut header)? {
+                return Ok(None);
+            }
+            if &header[0..4] != b"OggS" {
+                return Err(invalid("lost Ogg page sync".to_string()));
+            }
+            let serial = u32::from_le_bytes([header[14], header[15], header[16], header[17]]);
+            let mut lacing = vec![0; header[26] as usize];
+            self.file.read_exact(&mut lacing)?;
+            let body = read_vec(&mut self.file, lacing.iter().map(|len| *len as u64).sum())?;
+            if *self.serial.get_or_insert(serial) != serial {
+                continue;
+            }
+            let mut offset = 0;
+            for len in lacing {
+                self.partial
+                    .extend_from_slice(&body[offset..offset + len as usize]);
+                offset += len as usize;
+                //a segment shorter than 255 bytes ends the packet
+                if len < 255 {
+                    self.packets.push_back(std::mem::take(&mut self.partial));
+                }
+            }
+        }
+        Ok(self.packets.pop_front())
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<SourceFrame>> {
+        let data = match self.next_packet()? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let pts = self.pts;
+        self.pts += opus_duration(&data);
+        Ok(Some(SourceFrame {
+            kind: MediaKind::Audio,
+            data,
+            pts,
+        }))
+    }
+}
+
+/// Duration of an Opus packet from its TOC byte, see RFC 6716 section 3.1.
+fn opus_duration(packet: &[u8]) -> Duration {
+    let toc = match packet.first() {
+        Some(toc) => *toc,
+        None => return Duration::ZERO,
+    };
+    let config = (toc >> 3) as usize;
+    let frame_us = match config {
+        0..=11 => [10_000, 20_000, 40_000, 60_000][config % 4],
+        12..=15 => [10_000, 20_000][config % 2],
+        _ => [2_500, 5_000, 10_000, 20_000][config % 4],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => packet.get(1).map_or(1, |count| (count & 0x3f) as u64),
+    };
+    Duration::from_micros(frame_us * frames)
+}
+
+struct WebmTrackInfo {
+    number: u64,
+    source: SourceTrack,
+}
+
+/// WebM or Matroska file, the first audio and the first video track with a supported codec
+/// are played. Laced blocks are skipped, WebM muxers only lace audio in practice.
+pub struct WebmReader {
+    file: BufReader<File>,
+    tracks: Vec<WebmTrackInfo>,
+    /// Nanoseconds per timestamp unit.
+    timestamp_scale: u64,
+    cluster_time: u64,
+}
+
+impl WebmReader {
+    fn new(file: BufReader<File>) -> io::Result<Self> {
+        let mut reader = Self {
+            file,
+            tracks: Vec::new(),
+            timestamp_scale: 1_000_000,
+            cluster_time: 0,
+        };
+        //(number, codec id, codec private) of the track entries
+        let mut entries: Vec<(u64, String, Option<Vec<u8>>)> = Vec::new();
+        while let Some((id, size)) = reader.read_element_header()? {
+            match id {
+                //the first cluster ends the headers, its children are read by next_frame
+                CLUSTER => break,
+                SEGMENT | INFO | TRACKS => {}
+                TRACK_ENTRY => entries.push((0, String::new(), None)),
+                TIMESTAMP_SCALE => reader.timestamp_scale = reader.read_uint(size)?,
+                TRACK_NUMBER => {
+                    let number = reader.read_uint(size)?;
+                    if let Some(entry) = entries.last_mut() {
+                        entry.0 = number;
+                    }
+                }
+                CODEC_ID => {
+                    let codec_id = read_vec(&mut reader.file, size)?;
+                    if let Some(entry) = entries.last_mut() {
+                        entry.1 = String::from_utf8_lossy(&codec_id)
+                            .trim_end_matches('\0')
+                            .to_string();
+                    }
+                }
+                CODEC_PRIVATE => {
+                    let codec_private = read_vec(&mut reader.file, size)?;
+                    if let Some(entry) = entries.last_mut() {
+                        entry.2 = Some(codec_private);
+                    }
+                }
+                _ => reader.skip(size)?,
+            }
+        }
+
+        for (number, codec_id, codec_private) in entries {
+            let (kind, codec) = match codec_id.as_str() {
+                "A_OPUS" => (MediaKind::Audio, Codec::Opus),
+                "V_VP8" => (MediaKind::Video, Codec::Vp8),
+                "V_VP9" => (MediaKind::Video, Codec::Vp9),
+                "V_MPEG4/ISO/AVC" => (MediaKind::Video, Codec::H264),
+                _ => {
+                    log::warn!("Playback skips WebM track {number} with codec {codec_id}");
+                    continue;
+                }
+            };
+            if reader.tracks.iter().any(|track| track.source.kind == kind) {
+                continue;
+            }
+            reader.tracks.push(WebmTrackInfo {
+                number,
+                source: SourceTrack {
+                    kind,
+                    codec,
+                    codec_private,
+                },
+            });
+        }
+        if reader.tracks.is_empty() {
+            return Err(invalid("no playable track in WebM file".to_string()));
+        }
+        Ok(reader)
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<SourceFrame>> {
+        loop {
+            let (id, size) = match self.read_element_header()? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            match id {
+                SEGMENT | CLUSTER | BLOCK_GROUP => {}
+                TIMESTAMP => self.cluster_time = self.read_uint(size)?,
+                SIMPLE_BLOCK | BLOCK => {
+                    let block = read_vec(&mut self.file, size)?;
+                    if let Some(frame) = self.parse_block(&block) {
+                        return Ok(Some(frame));
+                    }
+                }
+                _ => self.skip(size)?,
+            }
+        }
+    }
+
+    fn parse_block(&self, block: &[u8]) -> Option<SourceFrame> {
+        let (number, len) = read_vint(block)?;
+        let track = self.tracks.iter().find(|track| track.number == number)?;
+        let header = block.get(len..len + 3)?;
+        let relative = i16::from_be_bytes([header[0], header[1]]) as i64;
+        if header[2] & 0x06 != 0 {
+            log::debug!("Playback skips laced block of track {number}");
+            return None;
+        }
+        let time = (self.cluster_time as i64 + relative).max(0) as u64;
+        Some(SourceFrame {
+            kind: track.source.kind,
+            data: block[len + 3..].to_vec(),
+            pts: Duration::from_nanos(time.saturating_mul(self.timestamp_scale)),
+        })
+    }
+
+    /// Read an element id and size, the size is u64::MAX when unknown.
+    fn read_element_header(&mut self) -> io::Result<Option<(u32, u64)>> {
+        let mut first = [0; 1];
+        if !read_or_eof(&mut self.file, &mut first)? {
+            return Ok(None);
+        }
+        let id_len = first[0].leading_zeros() as usize + 1;
+        if id_len > 4 {
+            return Err(invalid("invalid EBML element id".to_string()));
+        }
+        let mut id = first[0] as u32;
+        for _ in 1..id_len {
+            self.file.read_exact(&mut first)?;
+            id = (id << 8) | first[0] as u32;
+        }
+
+        self.file.read_exact(&mut first)?;
+        let size_len = first[0].leading_zeros() as usize + 1;
+        if size_len > 8 {
+            return Err(invalid("invalid EBML element size".to_string()));
+        }
+        let mut size = (first[0] as u64) & (0xff >> size_len);
+        let mut unknown = size == (0xff >> size_len);
+        for _ in 1..size_len {
+            self.file.read_exact(&mut first)?;
+            size = (size << 8) | first[0] as u64;
+            unknown &= first[0] == 0xff;
+        }
+        Ok(Some((id, if unknown { u64::MAX } else { size })))
+    }
+
+    fn read_uint(&mut self, size: u64) -> io::Result<u64> {
+        if size > 8 {
+            return Err(invalid(format!("integer element of {size} bytes")));
+        }
+        Ok(read_vec(&mut self.file, size)?
+            .iter()
+            .fold(0, |value, byte| (value << 8) | *byte as u64))
+    }
+
+    fn skip(&mut self, size: u64) -> io::Result<()> {
+        if size == u64::MAX {
+            return Err(invalid("unknown size of a non master element".to_string()));
+        }
+        let skipped = io::copy(&mut (&mut self.file).take(size), &mut io::sink())?;
+        if skipped < size {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+/// Read an EBML variable size integer without its length marker.
+fn read_vint(data: &[u8]) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 || data.len() < len {
+        return None;
+    }
+    let value = data[1..len]
+        .iter()
+        .fold(first as u64 & (0xff >> len), |value, byte| {
+            (value << 8) | *byte as u64
+        });
+    Some((value, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use str0m::{format::Codec, media::MediaKind};
+
+    use super::Demuxer;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("demuxer-{}-{name}", std::process::id()))
+    }
+
+    fn open(name: &str, data: &[u8]) -> std::io::Result<Demuxer> {
+        let path = temp_path(name);
+        std::fs::write(&path, data).unwrap();
+        let demuxer = Demuxer::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        demuxer
+    }
+
+    /// (kind, data, pts) of all the frames.
+    fn frames(demuxer: &mut Demuxer) -> Vec<(MediaKind, Vec<u8>, Duration)> {
+        let mut frames = Vec::new();
+        while let Some(frame) = demuxer.next_frame().unwrap() {
+            frames.push((frame.kind, frame.data, frame.pts));
+        }
+        frames
+    }
+
+    #[test]
+    fn ivf_frames() {
+        let mut file = b"DKIF".to_vec();
+        file.extend_from_slice(&0u16.to_le_bytes());
+        file.extend_from_slice(&32u16.to_le_bytes());
+        file.extend_from_slice(b"VP90");
+        file.extend_from_slice(&320u16.to_le_bytes());
+        file.extend_from_slice(&240u16.to_le_bytes());
+        //30 fps time base
+        file.extend_from_slice(&30u32.to_le_bytes());
+        file.extend_from_slice(&1u32.to_le_bytes());
+        file.extend_from_slice(&[0; 8]);
+        for (pts, frame) in [(0u64, vec![0xaa; 3]), (3, vec![0xbb; 2])] {
+            file.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            file.extend_from_slice(&pts.to_le_bytes());
+            file.extend_from_slice(&frame);
+        }
+
+        let mut demuxer = open("frames.ivf", &file).unwrap();
+        let tracks = demuxer.tracks();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].codec, Codec::Vp9);
+        assert_eq!(
+            frames(&mut demuxer),
+            [
+                (MediaKind::Video, vec![0xaa; 3], Duration::ZERO),
+                (MediaKind::Video, vec![0xbb; 2], Duration::from_millis(100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_bad_files() {
+        assert!(open("bad.ivf", b"RIFF").is_err());
+        assert!(open("bad.ogg", b"OggS").is_err());
+        assert!(open("file.mp4", b"").is_err());
+    }
+
+    /// Ogg page of one logical stream, with a lacing value per 255 bytes of every packet.
+    fn ogg_page(serial: u32, packets: &[&[u8]]) -> Vec<u8> {
+        let mut lacing = Vec::new();
+        for packet in packets {
+            lacing.extend(std::iter::repeat_n(255, packet.len() / 255));
+            lacing.push((packet.len() % 255) as u8);
+        }
+        let mut page = b"OggS".to_vec();
+        page.extend_from_slice(&[0, 0]);
+        page.extend_from_slice(&0u64.to_le_bytes());
+        page.extend_from_slice(&serial.to_le_bytes());
+        page.extend_from_slice(&[0; 8]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        for packet in packets {
+            page.extend_from_slice(packet);
+        }
+        page
+    }
+
+    #[test]
+    fn ogg_opus_packets() {
+        let big = [0xfc; 300];
+        let mut file = ogg_page(1, &[b"OpusHead\x01\x02"]);
+        file.extend(ogg_page(1, &[b"OpusTags"]));
+        //other logical streams are skipped
+        file.extend(ogg_page(2, &[&[0x00]]));
+        //20ms packets, then two 10ms frames in a packet
+        file.extend(ogg_page(1, &[&[0xfc, 0x01], &big, &[0xf1, 0x02]]));
+
+        let mut demuxer = open("opus.ogg", &file).unwrap();
+        assert_eq!(demuxer.tracks()[0].codec, Codec::Opus);
+        assert_eq!(
+            frames(&mut demuxer),
+            [
+                (MediaKind::Audio, vec![0xfc, 0x01], Duration::ZERO),
+                (MediaKind::Audio, big.to_vec(), Duration::from_millis(20)),
+                (
+                    MediaKind::Audio,
+                    vec![0xf1, 0x02],
+                    Duration::from_millis(40)
+                ),
+            ]
+        );
+    }
+
+    fn element(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut element = id.to_vec();
+        element.push(0x80 | body.len() as u8);
+        element.extend_from_slice(body);
+        element
+    }
+
+    /// Element with an unknown size, as written by live muxers.
+    fn open_element(id: &[u8], body: &[u8]) -> Vec<u8> {
+        [id, &[0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], body].concat()
+    }
+
+    fn block(track: u8, relative: i16, flags: u8, data: &[u8]) -> Vec<u8> {
+        [&[0x80 | track][..], &relative.to_be_bytes(), &[flags], data].concat()
+    }
+
+    #[test]
+    fn webm_blocks() {
+        let h264 = [
+            element(&[0xd7], &[1]),
+            element(&[0x86], b"V_MPEG4/ISO/AVC"),
+            element(&[0x63, 0xa2], &[1, 0x42, 0x00, 0x1f]),
+        ]
+        .concat();
+        let vp8 = [element(&[0xd7], &[3]), element(&[0x86], b"V_VP8")].concat();
+        let opus = [element(&[0xd7], &[2]), element(&[0x86], b"A_OPUS")].concat();
+        let tracks = [
+            element(&[0xae], &h264),
+            //only the first video track is played
+            element(&[0xae], &vp8),
+            element(&[0xae], &opus),
+        ]
+        .concat();
+        let cluster = [
+            element(&[0xe7], &[0x03, 0xe8]),
+            element(&[0xa3], &block(1, 0, 0x80, &[0xaa; 5])),
+            element(&[0xa0], &element(&[0xa1], &block(2, 10, 0x00, &[0xbb; 3]))),
+            element(&[0xa3], &block(3, 20, 0x80, &[0xdd])),
+            //laced blocks are skipped
+            element(&[0xa3], &block(2, 20, 0x02, &[0x01, 0xee, 0xee])),
+            element(&[0xa3], &block(1, 40, 0x00, &[0xcc; 4])),
+        ]
+        .concat();
+        let segment = [
+            element(
+                &[0x15, 0x49, 0xa9, 0x66],
+                &element(&[0x2a, 0xd7, 0xb1], &[0x0f, 0x42, 0x40]),
+            ),
+            element(&[0x16, 0x54, 0xae, 0x6b], &tracks),
+            open_element(&[0x1f, 0x43, 0xb6, 0x75], &cluster),
+        ]
+        .concat();
+        let file = [
+            element(&[0x1a, 0x45, 0xdf, 0xa3], &element(&[0x42, 0x82], b"webm")),
+            open_element(&[0x18, 0x53, 0x80, 0x67], &segment),
+        ]
+        .concat();
+
+        let mut demuxer = open("blocks.webm", &file).unwrap();
+        let tracks = demuxer.tracks();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].codec, Codec::H264);
+        assert_eq!(tracks[0].codec_private, Some(vec![1, 0x42, 0x00, 0x1f]));
+        assert_eq!(tracks[1].kind, MediaKind::Audio);
+        assert_eq!(
+            frames(&mut demuxer),
+            [
+                (MediaKind::Video, vec![0xaa; 5], Duration::from_millis(1000)),
+                (MediaKind::Audio, vec![0xbb; 3], Duration::from_millis(1010)),
+                (MediaKind::Video, vec![0xcc; 4], Duration::from_millis(1040)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,835 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use str0m::{
+    change::{DtlsCert, SdpAnswer, SdpPendingOffer},
+    format::Codec,
+    media::{Direction, KeyframeRequestKind, MediaKind, Mid, Pt, Rid},
+    net::{Protocol, Receive},
+    Candidate, Event, IceConnectionState, Input, Output, Rtc,
+};
+
+use crate::{
+    capture::{ExtensionIds, RtpCapture, RtpFields},
+    http::{get_http_header, get_resource_sub_path, problem_response},
+    io::{HttpRequest, HttpResponse, IoAction, IoEvent},
+    tasks::{
+        check_offer_codecs, configure_codecs, layer_selector::LayerSelector, lip_sync::LipSync,
+        negotiated_codecs, parse_sdp_offer, parse_simulcast_rids, payload::is_keyframe_start,
+        rtp_rewriter::RtpRewriter, speaker::SpeakerDetector, trickle_ice::handle_ice_patch,
+        CreateTaskError, RoomEvent, SimulcastLayer, TrackMedia,
+    },
+};
+
+use super::{WebrtcTask, WebrtcTaskInput, WebrtcTaskOutput};
+
+/// A held events request is answered with an empty list after this long.
+const EVENTS_POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Track id of a media published by a participant of a room.
+pub fn room_track_id(room: &str, participant: &str, kind: MediaKind) -> u64 {
+    super::track_id_builder(&format!("{room}/{participant}"), kind)
+}
+
+/// Simulcast layer limit of the video of `participant` when only the dominant speaker is sent
+/// in its best layer: the others are capped to their lowest layer once a speaker is known.
+fn speaker_layer_limit(participant: &str, dominant: Option<&str>) -> Option<u8> {
+    match dominant {
+        Some(dominant) if dominant != participant => Some(0),
+        _ => None,
+    }
+}
+
+/// A media of another participant sent to this one, on its own m-line.
+struct RoomSubscription {
+    participant: String,
+    kind: MediaKind,
+    track_id: u64,
+    /// Set once the m-line is added to an offer.
+    mid: Option<Mid>,
+    /// Set once the offer with the m-line is answered.
+    active: bool,
+    /// The participant left, the m-line is deactivated in the next offer.
+    removed: bool,
+    codecs: Vec<(Pt, Codec)>,
+    rewriter: RtpRewriter,
+    selector: LayerSelector,
+}
+
+/// A participant of a multi-party room: publishes its own audio and video and receives the
+/// media of every other participant.
+///
+/// The client offers its own media on creation. Media of other participants is added and
+/// removed by server offers, delivered on the `events` sub resource (long polling) and answered
+/// with `POST {resource}/answer`.
+pub struct RoomTask {
+    ice_ufrag: String,
+    timeout: Option<Instant>,
+    rtc: Rtc,
+    local_candidates: Vec<Candidate>,
+    outputs: VecDeque<WebrtcTaskOutput>,
+    connected: bool,
+    audio_mid: Option<Mid>,
+    video_mid: Option<Mid>,
+    /// Simulcast rids of the participant video, lowest quality first. Empty without simulcast.
+    video_rids: Vec<Rid>,
+    /// Negotiated payload types of the participant media.
+    codecs: Vec<(Pt, Codec)>,
+    audio_track_id: u64,
+    video_track_id: u64,
+    room: String,
+    participant: String,
+    subscriptions: Vec<RoomSubscription>,
+    pending_offer: Option<SdpPendingOffer>,
+    events: VecDeque<serde_json::Value>,
+    /// Held events request and when it must be answered.
+    events_poll: Option<(u64, Instant)>,
+    speakers: SpeakerDetector,
+    /// Per participant, see [`LipSync`].
+    lip_syncs: HashMap<String, LipSync>,
+    capture: Option<RtpCapture>,
+    /// Header extension ids of the answer, for the RTP capture.
+    extensions: ExtensionIds,
+    /// str0m emits the packets it receives and sends, the capture takes them from there.
+    raw_packets: bool,
+    /// Only the dominant speaker video is sent in its best simulcast layer.
+    speaker_priority: bool,
+}
+
+impl RoomTask {
+    pub fn new(
+        dtls_cert: DtlsCert,
+        req: HttpRequest,
+        room: String,
+        participant: String,
+        others: Vec<String>,
+        resource_path: String,
+        local_addrs: Vec<SocketAddr>,
+        codecs: &[Codec],
+        speaker_priority: bool,
+        raw_packets: bool,
+    ) -> Result<RoomTask, CreateTaskError> {
+        let offer = parse_sdp_offer(&req)?;
+        let sdp = String::from_utf8_lossy(&req.body);
+        check_offer_codecs(&sdp, codecs)?;
+        let video_rids = parse_simulcast_rids(&sdp, "send");
+        let rtc_config = configure_codecs(
+            Rtc::builder()
+                .set_rtp_mode(true)
+                .set_ice_lite(true)
+                .set_dtls_cert(dtls_cert)
+                .enable_raw_packets(raw_packets),
+            codecs,
+        );
+
+        log::info!(
+            "RoomTask::new req: {} addr {:?} => room {} participant {}",
+            req.path,
+            local_addrs,
+            room,
+            participant,
+        );
+        let ice_ufrag = rtc_config.local_ice_credentials().ufrag.clone();
+
+        let mut rtc = rtc_config.build();
+        rtc.direct_api().enable_twcc_feedback();
+
+        let local_candidates: Vec<Candidate> = local_addrs
+            .into_iter()
+            .map(|addr| Candidate::host(addr, Protocol::Udp).expect("Should create candidate"))
+            .collect();
+        for candidate in &local_candidates {
+            rtc.add_local_candidate(candidate.clone());
+        }
+
+        let answer = rtc
+            .sdp_api()
+            .accept_offer(offer)
+            .map_err(|e| CreateTaskError::NotAcceptable(e.to_string()))?
+            .to_sdp_string();
+
+        let mut task = RoomTask {
+            ice_ufrag,
+            timeout: None,
+            rtc,
+            local_candidates,
+            outputs: VecDeque::from(vec![IoAction::HttpResponse(HttpResponse {
+                req_id: req.req_id,
+                status: 201,
+                headers: HashMap::from([
+                    ("Content-Type".to_string(), "application/sdp".to_string()),
+                    ("Location".to_string(), resource_path),
+                    ("X-Participant-Id".to_string(), participant.clone()),
+                ]),
+                body: answer.as_bytes().to_vec(),
+            })
+            .into()]),
+            connected: false,
+            audio_mid: None,
+            video_mid: None,
+            video_rids,
+            codecs: Vec::new(),
+            audio_track_id: room_track_id(&room, &participant, MediaKind::Audio),
+            video_track_id: room_track_id(&room, &participant, MediaKind::Video),
+            room,
+            participant,
+            subscriptions: Vec::new(),
+            pending_offer: None,
+            events: VecDeque::new(),
+            events_poll: None,
+            speakers: SpeakerDetector::default(),
+            lip_syncs: HashMap::new(),
+            capture: None,
+            extensions: ExtensionIds::from_sdp(&answer),
+            raw_packets,
+            speaker_priority,
+        };
+        for other in others {
+            task.on_joined(other);
+        }
+        Ok(task)
+    }
+
+    fn on_joined(&mut self, participant: String) {
+        if participant == self.participant
+            || self
+                .subscriptions
+                .iter()
+                .any(|s| s.participant == participant && !s.removed)
+        {
+            return;
+        }
+        log::info!(
+            "RoomTask {} in room {}: participant {} joined",
+            self.participant,
+            self.room,
+            participant
+        );
+        for (kind, clock_rate) in [(MediaKind::Audio, 48000), (MediaKind::Video, 90000)] {
+            let mut selector = LayerSelector::default();
+            if self.speaker_priority {
+                selector.set_limit(speaker_layer_limit(&participant, self.speakers.dominant()));
+            }
+            self.subscriptions.push(RoomSubscription {
+                participant: participant.clone(),
+                kind,
+                track_id: room_track_id(&self.room, &participant, kind),
+                mid: None,
+                active: false,
+                removed: false,
+                codecs: Vec::new(),
+                rewriter: RtpRewriter::new(clock_rate),
+                selector,
+            });
+        }
+        self.push_event(serde_json::json!({"type": "joined", "participant": participant}));
+    }
+
+    fn on_left(&mut self, participant: String) {
+        log::info!(
+            "RoomTask {} in room {}: participant {} left",
+            self.participant,
+            self.room,
+            participant
+        );
+        for sub in self
+            .subscriptions
+            .iter_mut()
+            .filter(|s| s.participant == participant)
+        {
+            if sub.active && !sub.removed {
+                self.outputs.push_back(WebrtcTaskOutput::UnsubscribeTrack {
+                    track_id: sub.track_id,
+                });
+            }
+            sub.removed = true;
+        }
+        self.speakers.remove(&participant);
+        self.lip_syncs.remove(&participant);
+        self.push_event(serde_json::json!({"type": "left", "participant": participant}));
+    }
+
+    /// Send an offer with the pending media changes, one offer in flight at a time.
+    fn renegotiate(&mut self) {
+        if !self.connected || self.pending_offer.is_some() {
+            return;
+        }
+        //removed media which never made it to an offer can just be forgotten
+        self.subscriptions
+            .retain(|s| !(s.removed && s.mid.is_none()));
+
+        let mut changed = false;
+        let mut api = self.rtc.sdp_api();
+        for sub in self.subscriptions.iter_mut() {
+            if sub.mid.is_none() {
+                sub.mid = Some(api.add_media(sub.kind, Direction::SendOnly, None, None));
+                changed = true;
+            } else if let (Some(mid), true, true) = (sub.mid, sub.removed, sub.active) {
+                api.set_direction(mid, Direction::Inactive);
+                sub.active = false;
+                changed = true;
+            }
+        }
+        if !changed {
+            return;
+        }
+        if let Some((offer, pending)) = api.apply() {
+            log::info!("RoomTask {} sending offer", self.participant);
+            self.pending_offer = Some(pending);
+            //deactivated media are gone for good, their m-line stays inactive
+            self.subscriptions.retain(|s| s.active || !s.removed);
+            self.push_event(serde_json::json!({"type": "offer", "sdp": offer.to_sdp_string()}));
+        }
+    }
+
+    fn on_answer(&mut self, req: &HttpRequest) -> HttpResponse {
+        let content_type = get_http_header(req, "Content-Type").unwrap_or_default();
+        if !content_type.starts_with("application/sdp") {
+            return problem_response(
+                req.req_id,
+                415,
+                "Unsupported Media Type",
+                "expected application/sdp",
+            );
+        }
+        let pending = match self.pending_offer.take() {
+            Some(pending) => pending,
+            None => return problem_response(req.req_id, 409, "Conflict", "no pending offer"),
+        };
+        let answer = match std::str::from_utf8(&req.body)
+            .map_err(|e| e.to_string())
+            .and_then(|sdp| SdpAnswer::from_sdp_string(sdp).map_err(|e| e.to_string()))
+        {
+            Ok(answer) => answer,
+            Err(e) => {
+                self.pending_offer = Some(pending);
+                return problem_response(req.req_id, 400, "Invalid SDP answer", &e);
+            }
+        };
+        if let Err(e) = self.rtc.sdp_api().accept_answer(pending, answer) {
+            log::warn!("RoomTask {} answer rejected: {}", self.participant, e);
+            return problem_response(req.req_id, 400, "Invalid SDP answer", &e.to_string());
+        }
+
+        log::info!("RoomTask {} offer answered", self.participant);
+        for sub in self.subscriptions.iter_mut() {
+            if let (Some(mid), false) = (sub.mid, sub.active) {
+                sub.active = true;
+                sub.codecs = negotiated_codecs(&self.rtc, mid);
+                if !sub.removed {
+                    self.outputs.push_back(WebrtcTaskOutput::SubscribeTrack {
+                        track_id: sub.track_id,
+                    });
+                    if sub.kind == MediaKind::Video {
+                        self.outputs
+                            .push_back(WebrtcTaskOutput::RequestKeyframeTrack {
+                                track_id: sub.track_id,
+                                kind: KeyframeRequestKind::Pli,
+                            });
+                    }
+                }
+            }
+        }
+        //changes which happened while the offer was in flight
+        self.renegotiate();
+        HttpResponse {
+            req_id: req.req_id,
+            status: 204,
+            headers: Default::default(),
+            body: Vec::new(),
+        }
+    }
+
+    fn on_dominant_speaker(&mut self, participant: String) {
+        if self.speaker_priority {
+            for sub in self
+                .subscriptions
+                .iter_mut()
+                .filter(|s| s.kind == MediaKind::Video)
+            {
+                sub.selector
+                    .set_limit(speaker_layer_limit(&sub.participant, Some(&participant)));
+            }
+        }
+        self.push_event(serde_json::json!({"type": "speaker", "participant": participant}));
+    }
+
+    fn push_event(&mut self, event: serde_json::Value) {
+        self.events.push_back(event);
+        if let Some((req_id, _)) = self.events_poll.take() {
+            self.respond_events(req_id);
+        }
+    }
+
+    fn respond_events(&mut self, req_id: u64) {
+        let events: Vec<serde_json::Value> = self.events.drain(..).collect();
+        self.outputs.push_back(
+            IoAction::HttpResponse(HttpResponse {
+                req_id,
+                status: 200,
+                headers: HashMap::from([(
+                    "Content-Type".to_string(),
+                    "application/json".to_string(),
+                )]),
+                body: serde_json::Value::from(events).to_string().into_bytes(),
+            })
+            .into(),
+        );
+    }
+
+    fn on_sub_resource_request(&mut self, now: Instant, req: HttpRequest) {
+        match (req.method.as_str(), get_resource_sub_path(&req.path)) {
+            ("GET", "events") => {
+                //only one request is held, an older one gets an empty answer
+                if let Some((old_req_id, _)) = self.events_poll.take() {
+                    self.respond_events(old_req_id);
+                }
+                if self.events.is_empty() {
+                    self.events_poll = Some((req.req_id, now + EVENTS_POLL_TIMEOUT));
+                } else {
+                    self.respond_events(req.req_id);
+                }
+            }
+            ("POST", "answer") => {
+                let res = self.on_answer(&req);
+                self.outputs.push_back(IoAction::HttpResponse(res).into());
+            }
+            (_, "events") | (_, "answer") => {
+                self.outputs.push_back(
+                    IoAction::HttpResponse(HttpResponse {
+                        req_id: req.req_id,
+                        status: 405,
+                        headers: Default::default(),
+                        body: b"Method Not Allowed".to_vec(),
+                    })
+                    .into(),
+                );
+            }
+            _ => {
+                self.outputs.push_back(
+                    IoAction::HttpResponse(HttpResponse {
+                        req_id: req.req_id,
+                        status: 404,
+                        headers: Default::default(),
+                        body: b"Not Found".to_vec(),
+                    })
+                    .into(),
+                );
+            }
+        }
+    }
+
+    fn on_track_media(&mut self, now: Instant, mut media: TrackMedia) {
+        let sub = if let Some(sub) = self
+            .subscriptions
+            .iter_mut()
+            .find(|s| s.track_id == media.track_id && s.active && !s.removed)
+        {
+            sub
+        } else {
+            return;
+        };
+        let is_video = sub.kind == MediaKind::Video;
+        if let (false, Some(level)) = (is_video, media.header.ext_vals.audio_level) {
+            self.speakers.on_audio_level(
+                &sub.participant,
+                level,
+                media.header.ext_vals.voice_activity,
+                now,
+            );
+        }
+        let keyframe = is_video && is_keyframe_start(media.codec, &media.payload);
+        if let Some(layer) = media.simulcast {
+            let decision = sub.selector.on_packet(layer.index, keyframe, now);
+            if decision.request_keyframe {
+                self.outputs
+                    .push_back(WebrtcTaskOutput::RequestKeyframeTrack {
+                        track_id: sub.track_id,
+                        kind: KeyframeRequestKind::Pli,
+                    });
+            }
+            if !decision.forward {
+                return;
+            }
+            media.header.ext_vals.rid = None;
+            media.header.ext_vals.rid_repair = None;
+        }
+        let pt = if let Some((pt, _)) = sub.codecs.iter().find(|(_, codec)| *codec == media.codec) {
+            *pt
+        } else {
+            log::debug!(
+                "RoomTask {} doesn't support codec {:?}",
+                self.participant,
+                media.codec
+            );
+            return;
+        };
+        let rewritten = sub.rewriter.rewrite(
+            media.header.ssrc,
+            media.seq_no,
+            media.header.timestamp,
+            media.timestamp,
+        );
+        if rewritten.switched && is_video && !keyframe {
+            self.outputs
+                .push_back(WebrtcTaskOutput::RequestKeyframeTrack {
+                    track_id: sub.track_id,
+                    kind: KeyframeRequestKind::Pli,
+                });
+        }
+        //audio and video of a participant share the publisher clock
+        let wallclock = self
+            .lip_syncs
+            .entry(sub.participant.clone())
+            .or_default()
+            .wallclock(
+                media.sender_report,
+                media.header.timestamp,
+                if is_video { 90000 } else { 48000 },
+                media.timestamp,
+            );
+        let mid = sub.mid.expect("Active subscription should have a mid");
+        if let Some(stream) = self.rtc.direct_api().stream_tx_by_mid(mid, None) {
+            if let (Some(capture), false) = (&self.capture, self.raw_packets) {
+                capture.outgoing(
+                    RtpFields {
+                        payload_type: *pt,
+                        marker: media.header.marker,
+                        sequence_number: *rewritten.seq_no as u16,
+                        timestamp: rewritten.timestamp,
+                        ssrc: *stream.ssrc(),
+                        ext_vals: media.header.ext_vals,
+                    },
+                    &media.payload,
+                );
+            }
+            if let Err(e) = stream.write_rtp(
+                pt,
+                rewritten.seq_no,
+                rewritten.timestamp,
+                wallclock,
+                media.header.marker,
+                media.header.ext_vals,
+                is_video,
+                media.payload,
+            ) {
+                log::error!("Error writing rtp: {}", e);
+            }
+            log::trace!("clear timeout with media");
+            self.timeout = None;
+        }
+    }
+}
+
+impl WebrtcTask for RoomTask {
+    fn ufrag(&self) -> String {
+        self.ice_ufrag.clone()
+    }
+
+    fn tick(&mut self, now: Instant) -> bool {
+        let mut has_action = false;
+        if let Some(participant) = self.speakers.poll(now) {
+            self.on_dominant_speaker(participant);
+            has_action = true;
+        }
+        if let Some((req_id, deadline)) = self.events_poll {
+            if now >= deadline {
+                self.events_poll = None;
+                self.respond_events(req_id);
+                has_action = true;
+            }
+        }
+        if let Some(timeout) = self.timeout {
+            if now >= timeout {
+                if let Err(e) = self.rtc.handle_input(Input::Timeout(now)) {
+                    log::error!("Error handling timeout: {}", e);
+                }
+                log::trace!("clear timeout after handled");
+                self.timeout = None;
+                has_action = true;
+            }
+        }
+        has_action
+    }
+
+    fn input<'b>(&mut self, now: Instant, event: WebrtcTaskInput<'b>) -> bool {
+        match event {
+            WebrtcTaskInput::Io(IoEvent::HttpRequest(req))
+                if !get_resource_sub_path(&req.path).is_empty() =>
+            {
+                self.on_sub_resource_request(now, req);
+                true
+            }
+            WebrtcTaskInput::Io(IoEvent::HttpRequest(req)) => match req.method.as_str() {
+                "DELETE" => {
+                    log::info!("RoomTask received delete request, leaving room");
+                    self.rtc.disconnect();
+                    self.outputs.push_back(
+                        IoAction::HttpResponse(HttpResponse {
+                            req_id: req.req_id,
+                            status: 200,
+                            headers: Default::default(),
+                            body: b"OK".to_vec(),
+                        })
+                        .into(),
+                    );
+                    self.outputs.push_back(WebrtcTaskOutput::TaskEnded);
+                    true
+                }
+                "PATCH" => {
+                    let res = handle_ice_patch(&mut self.rtc, &req, &self.local_candidates);
+                    if let Some(new_ufrag) = res.new_ufrag {
+                        log::info!("RoomTask ice restart, new ufrag {}", new_ufrag);
+                        let old_ufrag = std::mem::replace(&mut self.ice_ufrag, new_ufrag.clone());
+                        self.outputs.push_back(WebrtcTaskOutput::IceRestart {
+                            old_ufrag,
+                            new_ufrag,
+                        });
+                    }
+                    self.outputs
+                        .push_back(IoAction::HttpResponse(res.response).into());
+                    log::trace!("clear timeout with ice patch");
+                    self.timeout = None;
+                    true
+                }
+                _ => {
+                    self.outputs.push_back(
+                        IoAction::HttpResponse(HttpResponse {
+                            req_id: req.req_id,
+                            status: 405,
+                            headers: Default::default(),
+                            body: b"Method Not Allowed".to_vec(),
+                        })
+                        .into(),
+                    );
+                    true
+                }
+            },
+            WebrtcTaskInput::Io(IoEvent::UdpSocketRecv { from, to, buf }) => {
+                if let Some(capture) = &mut self.capture {
+                    capture.set_addresses(to, from);
+                }
+                if let Err(e) = self.rtc.handle_input(Input::Receive(
+                    now,
+                    Receive::new(Protocol::Udp, from, to, buf).expect("Should parse udp"),
+                )) {
+                    log::error!("Error handling udp: {}", e);
+                }
+                log::trace!("clear timeout with udp");
+                self.timeout = None;
+                true
+            }
+            WebrtcTaskInput::TrackMedia(media) => {
+                self.on_track_media(now, media);
+                true
+            }
+            WebrtcTaskInput::RequestKeyframeTrack { track_id, kind } => {
+                if track_id == self.video_track_id {
+                    if let Some(mid) = self.video_mid {
+                        log::info!("Requesting keyframe for video mid: {:?}", mid);
+                        let rids: Vec<Option<Rid>> = if self.video_rids.is_empty() {
+                            vec![None]
+                        } else {
+                            self.video_rids.iter().map(|rid| Some(*rid)).collect()
+                        };
+                        for rid in rids {
+                            if let Some(stream) = self.rtc.direct_api().stream_rx_by_mid(mid, rid) {
+                                stream.request_keyframe(kind);
+                            }
+                        }
+                    }
+                }
+                true
+            }
+            WebrtcTaskInput::RoomEvent(event) => {
+                match event {
+                    RoomEvent::Joined(participant) => self.on_joined(participant),
+                    RoomEvent::Left(participant) => self.on_left(participant),
+                }
+                self.renegotiate();
+                true
+            }
+            WebrtcTaskInput::ChannelData(_) => {
+                log::debug!("RoomTask doesn't relay data channels");
+                false
+            }
+            WebrtcTaskInput::Capture(capture) => {
+                self.capture = capture.map(|mut capture| {
+                    capture.set_extensions(self.extensions);
+                    capture
+                });
+                false
+            }
+            WebrtcTaskInput::EndTask => {
+                log::info!("RoomTask ending by request");
+                self.rtc.disconnect();
+                self.outputs.push_back(WebrtcTaskOutput::TaskEnded);
+                true
+            }
+            WebrtcTaskInput::Io(IoEvent::RtmpPublish(_)) => {
+                panic!("Should not receive this event.")
+            }
+        }
+    }
+
+    fn pop_action(&mut self, now: Instant) -> Option<WebrtcTaskOutput> {
+        if let Some(o) = self.outputs.pop_front() {
+            return Some(o);
+        }
+
+        if let Some(timeout) = self.timeout {
+            if timeout > now {
+                return None;
+            }
+        }
+
+        match self.rtc.poll_output().ok()? {
+            Output::Timeout(timeout) => {
+                self.timeout = Some(timeout);
+                log::trace!("set timeout after {:?}", timeout - now);
+                None
+            }
+            Output::Transmit(send) => Some(
+                IoAction::UdpSocketSend {
+                    from: send.source,
+                    to: send.destination,
+                    buf: send.contents.into(),
+                }
+                .into(),
+            ),
+            Output::Event(e) => match e {
+                Event::Connected => {
+                    log::info!("RoomTask {} connected", self.participant);
+                    self.connected = true;
+                    self.outputs.push_back(WebrtcTaskOutput::PublishTrack {
+                        track_id: self.audio_track_id,
+                    });
+                    self.outputs.push_back(WebrtcTaskOutput::PublishTrack {
+                        track_id: self.video_track_id,
+                    });
+                    self.renegotiate();
+                    self.outputs.pop_front()
+                }
+                Event::MediaAdded(media) => {
+                    //media added by our own offers are sendonly, only keep the participant ones
+                    if !matches!(media.direction, Direction::RecvOnly | Direction::SendRecv) {
+                        return None;
+                    }
+                    log::info!("RoomTask media added: {:?}", media);
+                    if media.kind == MediaKind::Audio {
+                        self.audio_mid = Some(media.mid);
+                    } else {
+                        self.video_mid = Some(media.mid);
+                    }
+                    self.codecs.extend(negotiated_codecs(&self.rtc, media.mid));
+                    None
+                }
+                Event::IceConnectionStateChange(state) => match state {
+                    IceConnectionState::Disconnected => Some(WebrtcTaskOutput::TaskEnded),
+                    _ => None,
+                },
+                Event::KeyframeRequest(req) => self
+                    .subscriptions
+                    .iter()
+                    .find(|s| s.mid == Some(req.mid) && s.active && !s.removed)
+                    .map(|s| WebrtcTaskOutput::RequestKeyframeTrack {
+                        track_id: s.track_id,
+                        kind: req.kind,
+                    }),
+                Event::RtpPacket(rtp) => {
+                    if let (Some(capture), false) = (&self.capture, self.raw_packets) {
+                        capture.incoming((&rtp.header).into(), &rtp.payload);
+                    }
+                    let (mid, rid) = self
+                        .rtc
+                        .direct_api()
+                        .stream_rx(&rtp.header.ssrc)
+                        .map(|stream| (Some(stream.mid()), stream.rid()))
+                        .unwrap_or_default();
+                    let track_id = if mid.is_some() && mid == self.audio_mid {
+                        if let Some(level) = rtp.header.ext_vals.audio_level {
+                            self.speakers.on_audio_level(
+                                &self.participant,
+                                level,
+                                rtp.header.ext_vals.voice_activity,
+                                now,
+                            );
+                        }
+                        self.audio_track_id
+                    } else if mid.is_some() && mid == self.video_mid {
+                        self.video_track_id
+                    } else {
+                        return None;
+                    };
+                    let codec = self
+                        .codecs
+                        .iter()
+                        .find(|(pt, _)| *pt == rtp.header.payload_type)
+                        .map(|(_, codec)| *codec)?;
+                    let mut media = TrackMedia::from_raw(track_id, codec, rtp);
+                    media.simulcast = rid.and_then(|rid| {
+                        let index = self.video_rids.iter().position(|r| *r == rid)?;
+                        Some(SimulcastLayer {
+                            rid,
+                            index: index as u8,
+                        })
+                    });
+                    Some(WebrtcTaskOutput::TrackMedia(media))
+                }
+                Event::RawPacket(packet) => {
+                    if let Some(capture) = &self.capture {
+                        capture.raw_packet(&packet);
+                    }
+                    //not an action, keep polling
+                    self.pop_action(now)
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::speaker_layer_limit;
+    use crate::tasks::layer_selector::LayerSelector;
+
+    /// Feed keyframes of three simulcast layers and return the forwarded layer.
+    fn forwarded_layer(selector: &mut LayerSelector, now: Instant) -> Option<u8> {
+        let mut forwarded = None;
+        for layer in 0..3 {
+            if selector.on_packet(layer, true, now).forward {
+                forwarded = Some(layer);
+            }
+        }
+        forwarded
+    }
+
+    #[test]
+    fn non_speaker_capped_to_lowest_layer() {
+        let now = Instant::now();
+        let mut speaker = LayerSelector::default();
+        let mut other = LayerSelector::default();
+        speaker.set_limit(speaker_layer_limit("alice", Some("alice")));
+        other.set_limit(speaker_layer_limit("bob", Some("alice")));
+        //the first round only makes the layers known
+        forwarded_layer(&mut speaker, now);
+        forwarded_layer(&mut other, now);
+
+        let now = now + Duration::from_millis(20);
+        assert_eq!(forwarded_layer(&mut speaker, now), Some(2));
+        assert_eq!(forwarded_layer(&mut other, now), Some(0));
+    }
+
+    #[test]
+    fn no_limit_without_speaker() {
+        assert_eq!(speaker_layer_limit("bob", None), None);
+        assert_eq!(speaker_layer_limit("bob", Some("bob")), None);
+        assert_eq!(speaker_layer_limit("bob", Some("alice")), Some(0));
+    }
+}
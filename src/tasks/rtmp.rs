@@ -0,0 +1,235 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam::channel::{Receiver, TryRecvError};
+use str0m::{format::Codec, media::MediaKind};
+
+use crate::{
+    http::get_resource_sub_path,
+    io::{HttpResponse, IoAction, IoEvent},
+    rtmp::RtmpMedia,
+};
+
+use super::{
+    packetizer::Packetizer, track_id_builder, WebrtcTask, WebrtcTaskInput, WebrtcTaskOutput,
+};
+
+/// Frames handled per tick, so a burst doesn't stall the other tasks of the worker.
+const MAX_FRAMES_PER_TICK: usize = 64;
+
+/// Publisher of a channel fed by an RTMP connection, the WebRTC side is the same as WHIP.
+///
+/// Tracks are published when their codec is announced by the connection. Audio which can't
+/// be forwarded (AAC) is dropped, viewers still negotiate Opus and get silence.
+/// The connection is closed when the task ends, and the task ends with the connection.
+pub struct RtmpTask {
+    resource_path: String,
+    channel: String,
+    media: Receiver<RtmpMedia>,
+    /// Codecs allowed server-wide.
+    codecs: Vec<Codec>,
+    ssrc_seed: u32,
+    packetizers: HashMap<MediaKind, Packetizer>,
+    outputs: VecDeque<WebrtcTaskOutput>,
+    ended: bool,
+}
+
+impl RtmpTask {
+    pub fn new(
+        channel: String,
+        resource_path: String,
+        media: Receiver<RtmpMedia>,
+        codecs: &[Codec],
+    ) -> RtmpTask {
+        //a new ssrc per connection, so viewers see a source switch when the encoder reconnects
+        let ssrc_seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        RtmpTask {
+            resource_path,
+            channel,
+            media,
+            codecs: codecs.to_vec(),
+            ssrc_seed,
+            packetizers: HashMap::new(),
+            outputs: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    fn on_config(&mut self, kind: MediaKind, codec: Codec, codec_private: Option<Vec<u8>>) {
+        if !self.codecs.contains(&codec) {
+            log::warn!(
+                "RtmpTask channel {} codec {:?} is not allowed, {:?} is dropped",
+                self.channel,
+                codec,
+                kind
+            );
+            return;
+        }
+        if let Some(packetizer) = self.packetizers.get_mut(&kind) {
+            if packetizer.codec() == codec {
+                if let Some(codec_private) = &codec_private {
+                    packetizer.set_codec_private(codec_private);
+                }
+                return;
+            }
+        }
+
+        log::info!(
+            "RtmpTask channel {} publishes {:?} {:?}",
+            self.channel,
+            kind,
+            codec
+        );
+        let track_id = track_id_builder(&self.channel, kind);
+        //a codec change restarts the sequence, it must come with a new ssrc
+        self.ssrc_seed = self.ssrc_seed.wrapping_add(1);
+        let is_new = self
+            .packetizers
+            .insert(
+                kind,
+                Packetizer::new(
+                    track_id,
+                    codec,
+                    codec_private.as_deref(),
+                    track_id as u32 ^ self.ssrc_seed,
+                ),
+            )
+            .is_none();
+        if is_new {
+            self.outputs
+                .push_back(WebrtcTaskOutput::PublishTrack { track_id });
+        }
+
+        let mut codecs: Vec<Codec> = self.packetizers.values().map(|p| p.codec()).collect();
+        //viewers offering audio would be rejected without an audio codec, they get silence
+        if !self.packetizers.contains_key(&MediaKind::Audio) && self.codecs.contains(&Codec::Opus) {
+            codecs.push(Codec::Opus);
+        }
+        self.outputs
+            .push_back(WebrtcTaskOutput::PublishCodecs(codecs));
+    }
+
+    fn end(&mut self) {
+        if !self.ended {
+            self.ended = true;
+            self.outputs.push_back(WebrtcTaskOutput::TaskEnded);
+        }
+    }
+}
+
+impl WebrtcTask for RtmpTask {
+    fn ufrag(&self) -> String {
+        //not an ICE session, the resource path can't collide with an ICE ufrag
+        self.resource_path.clone()
+    }
+
+    fn tick(&mut self, now: Instant) -> bool {
+        if self.ended {
+            return false;
+        }
+        for _ in 0..MAX_FRAMES_PER_TICK {
+            match self.media.try_recv() {
+                Ok(RtmpMedia::Config {
+                    kind,
+                    codec,
+                    codec_private,
+                }) => self.on_config(kind, codec, codec_private),
+                Ok(RtmpMedia::Frame { kind, data, pts }) => {
+                    if let Some(packetizer) = self.packetizers.get_mut(&kind) {
+                        for media in packetizer.packetize(&data, pts, now) {
+                            self.outputs.push_back(WebrtcTaskOutput::TrackMedia(media));
+                        }
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    log::info!("RtmpTask channel {} connection closed", self.channel);
+                    self.end();
+                    break;
+                }
+            }
+        }
+        !self.outputs.is_empty()
+    }
+
+    fn input<'b>(&mut self, _now: Instant, event: WebrtcTaskInput<'b>) -> bool {
+        match event {
+            //the session ends with the connection, there is no resource to delete
+            WebrtcTaskInput::Io(IoEvent::HttpRequest(req)) => {
+                let status = if get_resource_sub_path(&req.path).is_empty() {
+                    405
+                } else {
+                    404
+                };
+                self.outputs.push_front(
+                    IoAction::HttpResponse(HttpResponse {
+                        req_id: req.req_id,
+                        status,
+                        headers: Default::default(),
+                        body: Vec::new(),
+                    })
+                    .into(),
+                );
+                true
+            }
+            WebrtcTaskInput::EndTask => {
+                log::info!("RtmpTask ending by request");
+                self.end();
+                true
+            }
+            //nothing to capture and no way to ask the encoder for a keyframe
+            WebrtcTaskInput::RequestKeyframeTrack { .. } | WebrtcTaskInput::Capture(_) => false,
+            //no WebRTC peer, so no socket, room or data channel events
+            _ => false,
+        }
+    }
+
+    fn pop_action(&mut self, _now: Instant) -> Option<WebrtcTaskOutput> {
+        self.outputs.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crossbeam::channel::unbounded;
+    use str0m::format::Codec;
+
+    use crate::io::{HttpRequest, IoAction, IoEvent};
+
+    use super::{RtmpTask, WebrtcTask, WebrtcTaskOutput};
+
+    fn status(task: &mut RtmpTask, method: &str, path: &str) -> u16 {
+        let req = HttpRequest {
+            req_id: 1,
+            remote: None,
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: Default::default(),
+            body: Vec::new(),
+        };
+        assert!(task.input(Instant::now(), IoEvent::HttpRequest(req).into()));
+        match task.pop_action(Instant::now()) {
+            Some(WebrtcTaskOutput::Io(IoAction::HttpResponse(res))) => res.status,
+            _ => panic!("no response"),
+        }
+    }
+
+    #[test]
+    fn answer_http_requests() {
+        let (_send, recv) = unbounded();
+        let path = "/rtmp/endpoint/0-1-abc".to_string();
+        let mut task = RtmpTask::new("live".to_string(), path.clone(), recv, &[Codec::H264]);
+        assert_eq!(status(&mut task, "DELETE", &path), 405);
+        assert_eq!(status(&mut task, "PATCH", &format!("{path}/layer")), 404);
+        //the session is still running
+        assert!(task.pop_action(Instant::now()).is_none());
+        assert!(!task.tick(Instant::now()));
+    }
+}
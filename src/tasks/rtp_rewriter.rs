@@ -0,0 +1,88 @@
+use std::time::Instant;
+
+use str0m::rtp::{SeqNo, Ssrc};
+
+/// Keep outgoing sequence numbers and timestamps of a viewer track continuous when the
+/// source behind the track changes, e.g. a publisher reconnects or is taken over.
+pub struct RtpRewriter {
+    clock_rate: u32,
+    source: Option<Ssrc>,
+    seq_offset: u64,
+    ts_offset: u32,
+    last_seq: u64,
+    last_ts: u32,
+    last_instant: Option<Instant>,
+}
+
+pub struct RewrittenRtp {
+    pub seq_no: SeqNo,
+    pub timestamp: u32,
+    /// True when this is the first packet of a new source.
+    pub switched: bool,
+}
+
+impl RtpRewriter {
+    pub fn new(clock_rate: u32) -> Self {
+        Self {
+            clock_rate,
+            source: None,
+            seq_offset: 0,
+            ts_offset: 0,
+            last_seq: 0,
+            last_ts: 0,
+            last_instant: None,
+        }
+    }
+
+    /// Account a packet of the current source which is not forwarded, so the viewer sees no gap.
+    pub fn skip(&mut self, ssrc: Ssrc) {
+        if self.source == Some(ssrc) {
+            self.seq_offset = self.seq_offset.wrapping_sub(1);
+        }
+    }
+
+    pub fn rewrite(
+        &mut self,
+        ssrc: Ssrc,
+        seq_no: SeqNo,
+        timestamp: u32,
+        now: Instant,
+    ) -> RewrittenRtp {
+        let mut switched = false;
+        if self.source != Some(ssrc) {
+            if let (Some(_), Some(last_instant)) = (self.source, self.last_instant) {
+                //continue right after the last sent packet, advancing time by the wall clock gap
+                let elapsed = now.saturating_duration_since(last_instant);
+                let elapsed_ts =
+                    (elapsed.as_micros() as u64 * self.clock_rate as u64 / 1_000_000) as u32;
+                let next_ts = self.last_ts.wrapping_add(elapsed_ts.max(1));
+                self.seq_offset = self.last_seq.wrapping_add(1).wrapping_sub(*seq_no);
+                self.ts_offset = next_ts.wrapping_sub(timestamp);
+                switched = true;
+                log::info!(
+                    "Rtp source switched {:?} => {:?}, seq offset {}, ts offset {}",
+                    self.source,
+                    ssrc,
+                    self.seq_offset,
+                    self.ts_offset
+                );
+            }
+            self.source = Some(ssrc);
+        }
+
+        let out_seq = (*seq_no).wrapping_add(self.seq_offset);
+        let out_ts = timestamp.wrapping_add(self.ts_offset);
+        //only move forward, reordered packets must not pull back the switching point
+        if self.last_instant.is_none() || out_seq > self.last_seq {
+            self.last_seq = out_seq;
+            self.last_ts = out_ts;
+            self.last_instant = Some(now);
+        }
+
+        RewrittenRtp {
+            seq_no: out_seq.into(),
+            timestamp: out_ts,
+            switched,
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Levels quieter than this dBov are silence.
+const SILENCE_DBOV: u8 = 70;
+/// Weight of a new packet in the smoothed loudness, with 20ms packets this is ~200ms memory.
+const SMOOTHING: f32 = 0.1;
+/// A speaker without audio for this long is silent, e.g. DTX or muted.
+const SILENT_TIMEOUT: Duration = Duration::from_millis(500);
+/// A speaker needs at least this smoothed loudness to become dominant.
+const MIN_SCORE: f32 = 5.0;
+/// A challenger must be this much louder than the dominant speaker.
+const SWITCH_RATIO: f32 = 1.5;
+/// ...for this long.
+const SWITCH_HOLD: Duration = Duration::from_millis(300);
+/// Minimum time between two dominant speaker changes.
+const MIN_SWITCH_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Speaker {
+    score: f32,
+    last_update: Instant,
+}
+
+/// Dominant speaker detection from the RFC 6464 audio level of each participant.
+///
+/// Each participant gets a smoothed loudness; a challenger replaces the dominant speaker when it
+/// stays clearly louder for a while, so short noises and interjections don't flip the speaker.
+#[derive(Default)]
+pub struct SpeakerDetector {
+    speakers: HashMap<String, Speaker>,
+    dominant: Option<String>,
+    challenger: Option<(String, Instant)>,
+    last_switch: Option<Instant>,
+}
+
+impl SpeakerDetector {
+    pub fn dominant(&self) -> Option<&str> {
+        self.dominant.as_deref()
+    }
+
+    /// Account an audio packet, `audio_level` is the header extension value in -dBov and
+    /// `voice_activity` the optional V flag.
+    pub fn on_audio_level(
+        &mut self,
+        participant: &str,
+        audio_level: i8,
+        voice_activity: Option<bool>,
+        now: Instant,
+    ) {
+        let dbov = (-(audio_level as i16)).clamp(0, 127) as u8;
+        let loudness = if voice_activity == Some(false) {
+            0.0
+        } else {
+            SILENCE_DBOV.saturating_sub(dbov) as f32
+        };
+        let speaker = self
+            .speakers
+            .entry(participant.to_string())
+            .or_insert(Speaker {
+                score: 0.0,
+                last_update: now,
+            });
+        speaker.score = speaker.score * (1.0 - SMOOTHING) + loudness * SMOOTHING;
+        speaker.last_update = now;
+    }
+
+    pub fn remove(&mut self, participant: &str) {
+        self.speakers.remove(participant);
+        if self.dominant.as_deref() == Some(participant) {
+            self.dominant = None;
+        }
+    }
+
+    /// Return the new dominant speaker when it changed.
+    pub fn poll(&mut self, now: Instant) -> Option<String> {
+        let score = |speaker: &Speaker| {
+            if now - speaker.last_update >= SILENT_TIMEOUT {
+                0.0
+            } else {
+                speaker.score
+            }
+        };
+        let (loudest, loudest_score) = self
+            .speakers
+            .iter()
+            .map(|(participant, speaker)| (participant, score(speaker)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        if loudest_score < MIN_SCORE || self.dominant.as_ref() == Some(loudest) {
+            self.challenger = None;
+            return None;
+        }
+        let dominant_score = self
+            .dominant
+            .as_ref()
+            .and_then(|dominant| self.speakers.get(dominant))
+            .map_or(0.0, score);
+        if loudest_score < dominant_score * SWITCH_RATIO {
+            self.challenger = None;
+            return None;
+        }
+
+        let since = match &self.challenger {
+            Some((challenger, since)) if challenger == loudest => *since,
+            _ => {
+                self.challenger = Some((loudest.clone(), now));
+                now
+            }
+        };
+        let hold = self.dominant.is_none() || now - since >= SWITCH_HOLD;
+        let interval = self
+            .last_switch
+            .is_none_or(|last| now - last >= MIN_SWITCH_INTERVAL);
+        if hold && interval {
+            log::info!("Dominant speaker {:?} => {}", self.dominant, loudest);
+            self.dominant = Some(loudest.clone());
+            self.challenger = None;
+            self.last_switch = Some(now);
+            self.dominant.clone()
+        } else {
+            None
+        }
+    }
+}
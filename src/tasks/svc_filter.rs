@@ -0,0 +1,196 @@
+use str0m::format::Codec;
+
+use super::payload::parse_vp9_layer;
+
+pub struct SvcDecision {
+    pub forward: bool,
+    /// Set when the packet becomes the last one of the forwarded frame.
+    pub marker: bool,
+}
+
+/// Drop the spatial and temporal layers of an SVC stream above the target of a viewer.
+///
+/// Spatial layers only go up on a keyframe, temporal layers on a switching point, and both go
+/// down at the beginning of a frame so the viewer never gets a partial frame.
+#[derive(Default)]
+pub struct SvcFilter {
+    target_spatial: Option<u8>,
+    target_temporal: Option<u8>,
+    current_spatial: Option<u8>,
+    current_temporal: Option<u8>,
+}
+
+impl SvcFilter {
+    /// Set the target layers, None means all layers.
+    pub fn set_target(&mut self, spatial: Option<u8>, temporal: Option<u8>) {
+        log::info!(
+            "SvcFilter target ({:?}, {:?}) => ({:?}, {:?})",
+            self.target_spatial,
+            self.target_temporal,
+            spatial,
+            temporal
+        );
+        self.target_spatial = spatial;
+        self.target_temporal = temporal;
+    }
+
+    pub fn current(&self) -> (Option<u8>, Option<u8>) {
+        (self.current_spatial, self.current_temporal)
+    }
+
+    pub fn on_packet(&mut self, codec: Codec, payload: &[u8], marker: bool) -> SvcDecision {
+        let layer = match codec {
+            Codec::Vp9 => parse_vp9_layer(payload),
+            //VP9 is the only negotiated codec with layers in its payload, AV1 isn't supported
+            _ => None,
+        };
+        let layer = match layer {
+            Some(layer) => layer,
+            None => {
+                return SvcDecision {
+                    forward: true,
+                    marker,
+                }
+            }
+        };
+
+        if self.current_spatial != self.target_spatial && layer.begin_frame && layer.spatial == 0 {
+            let up = match (self.current_spatial, self.target_spatial) {
+                (Some(current), Some(target)) => target > current,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            if !up || layer.intra {
+                log::info!(
+                    "SvcFilter switch spatial {:?} => {:?}",
+                    self.current_spatial,
+                    self.target_spatial
+                );
+                self.current_spatial = self.target_spatial;
+            }
+        }
+        if self.current_temporal != self.target_temporal && layer.begin_frame {
+            let up = match (self.current_temporal, self.target_temporal) {
+                (Some(current), Some(target)) => target > current,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            if !up || layer.switching_up || layer.temporal == 0 {
+                log::info!(
+                    "SvcFilter switch temporal {:?} => {:?}",
+                    self.current_temporal,
+                    self.target_temporal
+                );
+                self.current_temporal = self.target_temporal;
+            }
+        }
+
+        let forward = self.current_spatial.is_none_or(|s| layer.spatial <= s)
+            && self.current_temporal.is_none_or(|t| layer.temporal <= t);
+        SvcDecision {
+            forward,
+            //the publisher marks the end of the whole superframe, which may have been dropped
+            marker: marker
+                || (forward && layer.end_frame && self.current_spatial == Some(layer.spatial)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use str0m::format::Codec;
+
+    use super::SvcFilter;
+
+    /// VP9 payload descriptor with layer indices, no picture id.
+    fn vp9(spatial: u8, temporal: u8, begin: bool, end: bool, intra: bool, up: bool) -> Vec<u8> {
+        let mut first = 0x20;
+        if !intra {
+            first |= 0x40;
+        }
+        if begin {
+            first |= 0x08;
+        }
+        if end {
+            first |= 0x04;
+        }
+        let layer = (temporal << 5) | if up { 0x10 } else { 0 } | (spatial << 1);
+        vec![first, layer, 0x00, 0xaa]
+    }
+
+    /// Forward a frame of two spatial layers, returns (forwarded, marker) of each layer.
+    fn frame(filter: &mut SvcFilter, temporal: u8, intra: bool) -> [(bool, bool); 2] {
+        let s0 = filter.on_packet(
+            Codec::Vp9,
+            &vp9(0, temporal, true, true, intra, false),
+            false,
+        );
+        let s1 = filter.on_packet(
+            Codec::Vp9,
+            &vp9(1, temporal, true, true, intra, false),
+            true,
+        );
+        [(s0.forward, s0.marker), (s1.forward, s1.marker)]
+    }
+
+    #[test]
+    fn forward_all_without_target() {
+        let mut filter = SvcFilter::default();
+        assert_eq!(frame(&mut filter, 0, false), [(true, false), (true, true)]);
+        assert_eq!(filter.current(), (None, None));
+    }
+
+    #[test]
+    fn forward_other_codecs() {
+        let mut filter = SvcFilter::default();
+        filter.set_target(Some(0), Some(0));
+        let decision = filter.on_packet(Codec::Vp8, &[0x10, 0x00], false);
+        assert!(decision.forward);
+        assert!(!decision.marker);
+    }
+
+    #[test]
+    fn spatial_down_on_next_frame() {
+        let mut filter = SvcFilter::default();
+        filter.set_target(Some(0), None);
+        //the base layer ends the forwarded frame
+        assert_eq!(frame(&mut filter, 0, false), [(true, true), (false, true)]);
+        assert_eq!(filter.current(), (Some(0), None));
+    }
+
+    #[test]
+    fn spatial_up_waits_for_keyframe() {
+        let mut filter = SvcFilter::default();
+        filter.set_target(Some(0), None);
+        frame(&mut filter, 0, false);
+        filter.set_target(Some(1), None);
+        assert_eq!(frame(&mut filter, 0, false), [(true, true), (false, true)]);
+        assert_eq!(frame(&mut filter, 0, true), [(true, false), (true, true)]);
+        assert_eq!(filter.current(), (Some(1), None));
+    }
+
+    #[test]
+    fn temporal_up_waits_for_switching_point() {
+        let mut filter = SvcFilter::default();
+        filter.set_target(None, Some(0));
+        assert_eq!(frame(&mut filter, 0, false), [(true, false), (true, true)]);
+        assert!(
+            !filter
+                .on_packet(Codec::Vp9, &vp9(0, 1, true, true, false, false), false)
+                .forward
+        );
+
+        filter.set_target(None, None);
+        assert!(
+            !filter
+                .on_packet(Codec::Vp9, &vp9(0, 1, true, true, false, false), false)
+                .forward
+        );
+        assert!(
+            filter
+                .on_packet(Codec::Vp9, &vp9(0, 1, true, true, false, true), false)
+                .forward
+        );
+        assert_eq!(filter.current(), (None, None));
+    }
+}
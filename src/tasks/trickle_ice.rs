@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use str0m::{Candidate, IceCreds, Rtc};
+
+use crate::{
+    http::get_http_header,
+    io::{HttpRequest, HttpResponse},
+};
+
+pub const TRICKLE_ICE_CONTENT_TYPE: &str = "application/trickle-ice-sdpfrag";
+
+/// Parsed body of a trickle-ice-sdpfrag PATCH request (RFC 8840).
+#[derive(Debug, Default)]
+pub struct IceFragment {
+    pub ufrag: Option<String>,
+    pub pwd: Option<String>,
+    /// Candidate lines without the leading `a=`.
+    pub candidates: Vec<String>,
+    pub end_of_candidates: bool,
+}
+
+impl IceFragment {
+    pub fn parse(body: &str) -> IceFragment {
+        let mut frag = IceFragment::default();
+        for line in body.lines().map(|l| l.trim()) {
+            if let Some(ufrag) = line.strip_prefix("a=ice-ufrag:") {
+                frag.ufrag = Some(ufrag.to_string());
+            } else if let Some(pwd) = line.strip_prefix("a=ice-pwd:") {
+                frag.pwd = Some(pwd.to_string());
+            } else if let Some(candidate) = line.strip_prefix("a=") {
+                if candidate.starts_with("candidate:") {
+                    frag.candidates.push(candidate.to_string());
+                } else if candidate == "end-of-candidates" {
+                    frag.end_of_candidates = true;
+                }
+            }
+        }
+        frag
+    }
+}
+
+/// Result of applying a PATCH request to a session.
+pub struct IcePatchResult {
+    pub response: HttpResponse,
+    /// Set to the new local ufrag when the request triggered an ICE restart.
+    pub new_ufrag: Option<String>,
+}
+
+/// Apply a trickle ICE / ICE restart PATCH request to the rtc.
+///
+/// Remote candidates are added to the rtc. If the fragment carries remote credentials that differ
+/// from the current ones, the session is restarted with new local credentials which are returned
+/// in the response body.
+pub fn handle_ice_patch(
+    rtc: &mut Rtc,
+    req: &HttpRequest,
+    local_candidates: &[Candidate],
+) -> IcePatchResult {
+    let content_type = get_http_header(req, "Content-Type").unwrap_or_default();
+    if !content_type.starts_with(TRICKLE_ICE_CONTENT_TYPE) {
+        return IcePatchResult {
+            response: HttpResponse {
+                req_id: req.req_id,
+                status: 415,
+                headers: Default::default(),
+                body: b"Unsupported Media Type".to_vec(),
+            },
+            new_ufrag: None,
+        };
+    }
+
+    let frag = IceFragment::parse(&String::from_utf8_lossy(&req.body));
+    log::info!("Received ice patch: {:?}", frag);
+
+    let remote_ufrag = rtc
+        .direct_api()
+        .remote_ice_credentials()
+        .map(|creds| creds.ufrag.clone());
+    let new_ufrag = match (frag.ufrag, frag.pwd) {
+        (Some(ufrag), Some(pass)) if Some(&ufrag) != remote_ufrag.as_ref() => {
+            log::info!("Ice restart with remote ufrag {ufrag}");
+            let local_creds = IceCreds::new();
+            let new_ufrag = local_creds.ufrag.clone();
+            rtc.direct_api()
+                .set_remote_ice_credentials(IceCreds { ufrag, pass });
+            rtc.direct_api().set_local_ice_credentials(local_creds);
+            Some(new_ufrag)
+        }
+        _ => None,
+    };
+
+    for candidate in &frag.candidates {
+        match Candidate::from_sdp_string(candidate) {
+            Ok(candidate) => rtc.add_remote_candidate(candidate),
+            Err(e) => log::warn!("Failed to parse remote candidate {candidate}: {e}"),
+        }
+    }
+
+    let response = if new_ufrag.is_some() {
+        let creds = rtc.direct_api().local_ice_credentials();
+        let mut body = format!(
+            "a=ice-lite\r\na=ice-ufrag:{}\r\na=ice-pwd:{}\r\n",
+            creds.ufrag, creds.pass
+        );
+        for candidate in local_candidates {
+            body.push_str(&format!("a={}\r\n", candidate.to_sdp_string()));
+        }
+        body.push_str("a=end-of-candidates\r\n");
+        HttpResponse {
+            req_id: req.req_id,
+            status: 200,
+            headers: HashMap::from([(
+                "Content-Type".to_string(),
+                TRICKLE_ICE_CONTENT_TYPE.to_string(),
+            )]),
+            body: body.into_bytes(),
+        }
+    } else {
+        HttpResponse {
+            req_id: req.req_id,
+            status: 204,
+            headers: Default::default(),
+            body: Vec::new(),
+        }
+    };
+
+    IcePatchResult {
+        response,
+        new_ufrag,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IceFragment;
+
+    #[test]
+    fn parse_trickle_candidates() {
+        let frag = IceFragment::parse(
+            "a=ice-ufrag:EsAw\r\n\
+             a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r\n\
+             m=audio 9 UDP/TLS/RTP/SAVPF 0\r\n\
+             a=mid:0\r\n\
+             a=candidate:1387637174 1 udp 2122260223 192.0.2.1 61764 typ host generation 0\r\n\
+             a=candidate:3471623853 1 udp 2122194687 198.51.100.2 61765 typ host\r\n\
+             a=end-of-candidates\r\n",
+        );
+        assert_eq!(frag.ufrag.as_deref(), Some("EsAw"));
+        assert_eq!(frag.pwd.as_deref(), Some("P2uYro0UCOQ4zxjKXaWCBui1"));
+        assert_eq!(
+            frag.candidates,
+            vec![
+                "candidate:1387637174 1 udp 2122260223 192.0.2.1 61764 typ host generation 0",
+                "candidate:3471623853 1 udp 2122194687 198.51.100.2 61765 typ host",
+            ]
+        );
+        assert!(frag.end_of_candidates);
+    }
+
+    #[test]
+    fn parse_without_credentials() {
+        let frag = IceFragment::parse("a=mid:0\na=candidate:1 1 udp 1 192.0.2.1 5000 typ host\n");
+        assert_eq!(frag.ufrag, None);
+        assert_eq!(frag.pwd, None);
+        assert_eq!(frag.candidates.len(), 1);
+        assert!(!frag.end_of_candidates);
+    }
+
+    #[test]
+    fn parse_empty_body() {
+        let frag = IceFragment::parse("");
+        assert_eq!(frag.ufrag, None);
+        assert!(frag.candidates.is_empty());
+        assert!(!frag.end_of_candidates);
+    }
+}
@@ -5,42 +5,103 @@ use std::{
 };
 
 use str0m::{
-    change::{DtlsCert, SdpOffer},
-    media::{MediaKind, Mid},
+    bwe::Bitrate,
+    change::DtlsCert,
+    format::Codec,
+    media::{KeyframeRequestKind, MediaKind, Mid, Pt},
     net::{Protocol, Receive},
     Candidate, Event, IceConnectionState, Input, Output, Rtc,
 };
 
+use serde::Deserialize;
+
 use crate::{
-    http::get_http_auth,
+    capture::{ExtensionIds, RtpCapture, RtpFields},
+    http::{get_resource_sub_path, problem_response},
     io::{HttpRequest, HttpResponse, IoAction, IoEvent},
-    tasks::track_id_builder,
+    tasks::{
+        bitrate_allocator::BitrateAllocator,
+        check_offer_codecs, configure_codecs,
+        data_channel::{data_track_id, ChannelMessage, DataChannels},
+        layer_selector::LayerSelector,
+        lip_sync::LipSync,
+        negotiated_codecs, parse_sdp_offer,
+        payload::is_keyframe_start,
+        rtp_rewriter::RtpRewriter,
+        svc_filter::SvcFilter,
+        track_id_builder,
+        trickle_ice::handle_ice_patch,
+        CreateTaskError, SimulcastLayer, TrackMedia,
+    },
 };
 
 use super::{WebrtcTask, WebrtcTaskInput, WebrtcTaskOutput};
 
+/// Starting point of the egress bandwidth estimation.
+const INITIAL_BITRATE_KBPS: u64 = 300;
+
 pub struct WhepServerTask {
-    channel: String,
     ice_ufrag: String,
     timeout: Option<Instant>,
     rtc: Rtc,
+    local_candidates: Vec<Candidate>,
     outputs: VecDeque<WebrtcTaskOutput>,
     audio_mid: Option<Mid>,
     video_mid: Option<Mid>,
+    /// Negotiated payload types of the viewer, publisher payload types are remapped to these.
+    codecs: Vec<(Pt, Codec)>,
+    audio_track_id: u64,
+    video_track_id: u64,
+    audio_rewriter: RtpRewriter,
+    video_rewriter: RtpRewriter,
+    /// Simulcast layers seen from the publisher.
+    video_layers: Vec<SimulcastLayer>,
+    video_selector: LayerSelector,
+    video_svc: SvcFilter,
+    bitrate: BitrateAllocator,
+    /// Set after video was paused for low bandwidth, until the next keyframe.
+    video_wait_keyframe: bool,
+    data_track_id: u64,
+    data_channels: DataChannels,
+    lip_sync: LipSync,
+    capture: Option<RtpCapture>,
+    /// Header extension ids of the answer, for the RTP capture.
+    extensions: ExtensionIds,
+    /// str0m emits the packets it receives and sends, the capture takes them from there.
+    raw_packets: bool,
+}
+
+/// Body of a layer selection request, see the WHEP layer extension.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LayerRequest {
+    encoding_id: Option<String>,
+    spatial_layer_id: Option<u8>,
+    temporal_layer_id: Option<u8>,
 }
 
 impl WhepServerTask {
     pub fn new(
         dtls_cert: DtlsCert,
         req: HttpRequest,
+        channel: String,
+        resource_path: String,
         local_addrs: Vec<SocketAddr>,
-    ) -> WhepServerTask {
-        let rtc_config = Rtc::builder()
-            .set_rtp_mode(true)
-            .set_ice_lite(true)
-            .set_dtls_cert(dtls_cert);
+        codecs: &[Codec],
+        raw_packets: bool,
+    ) -> Result<WhepServerTask, CreateTaskError> {
+        let offer = parse_sdp_offer(&req)?;
+        check_offer_codecs(&String::from_utf8_lossy(&req.body), codecs)?;
+        let rtc_config = configure_codecs(
+            Rtc::builder()
+                .set_rtp_mode(true)
+                .set_ice_lite(true)
+                .set_dtls_cert(dtls_cert)
+                .enable_raw_packets(raw_packets)
+                .enable_bwe(Some(Bitrate::kbps(INITIAL_BITRATE_KBPS))),
+            codecs,
+        );
 
-        let channel = get_http_auth(&req);
         log::info!(
             "WhepServerTask::new req: {} addr {:?} => channel {}",
             req.path,
@@ -51,36 +112,309 @@ impl WhepServerTask {
 
         let mut rtc = rtc_config.build();
 
-        for addr in local_addrs {
-            rtc.add_local_candidate(
-                Candidate::host(addr, Protocol::Udp).expect("Should create candidate"),
-            );
+        let local_candidates: Vec<Candidate> = local_addrs
+            .into_iter()
+            .map(|addr| Candidate::host(addr, Protocol::Udp).expect("Should create candidate"))
+            .collect();
+        for candidate in &local_candidates {
+            rtc.add_local_candidate(candidate.clone());
         }
 
-        let offer = SdpOffer::from_sdp_string(&String::from_utf8_lossy(&req.body))
-            .expect("Should parse offer");
         let answer = rtc
             .sdp_api()
             .accept_offer(offer)
-            .expect("Should accept offer");
+            .map_err(|e| CreateTaskError::NotAcceptable(e.to_string()))?
+            .to_sdp_string();
 
-        WhepServerTask {
-            channel,
+        let audio_track_id = track_id_builder(&channel, MediaKind::Audio);
+        let video_track_id = track_id_builder(&channel, MediaKind::Video);
+        let data_track_id = data_track_id(&channel);
+        Ok(WhepServerTask {
             ice_ufrag,
             timeout: None,
             rtc,
+            local_candidates,
             outputs: VecDeque::from(vec![IoAction::HttpResponse(HttpResponse {
                 req_id: req.req_id,
                 status: 200,
                 headers: HashMap::from([
                     ("Content-Type".to_string(), "application/sdp".to_string()),
-                    ("Location".to_string(), "/whep/endpoint/1234".to_string()),
+                    (
+                        "Link".to_string(),
+                        format!(
+                            "<{resource_path}/layer>; rel=\"urn:ietf:params:whep:ext:core:layer\""
+                        ),
+                    ),
+                    ("Location".to_string(), resource_path),
                 ]),
-                body: answer.to_sdp_string().as_bytes().to_vec(),
+                body: answer.as_bytes().to_vec(),
             })
             .into()]),
             audio_mid: None,
             video_mid: None,
+            codecs: Vec::new(),
+            audio_track_id,
+            video_track_id,
+            audio_rewriter: RtpRewriter::new(48000),
+            video_rewriter: RtpRewriter::new(90000),
+            video_layers: Vec::new(),
+            video_selector: LayerSelector::default(),
+            video_svc: SvcFilter::default(),
+            bitrate: BitrateAllocator::default(),
+            video_wait_keyframe: false,
+            data_track_id,
+            data_channels: DataChannels::default(),
+            lip_sync: LipSync::default(),
+            capture: None,
+            extensions: ExtensionIds::from_sdp(&answer),
+            raw_packets,
+        })
+    }
+}
+
+impl WhepServerTask {
+    fn on_track_media(&mut self, now: Instant, mut media: TrackMedia) {
+        let is_video = media.track_id == self.video_track_id;
+        let keyframe = is_video && is_keyframe_start(media.codec, &media.payload);
+        if is_video {
+            let layer = media.simulcast.map_or(0, |layer| layer.index);
+            self.bitrate
+                .on_video_packet(layer, media.payload.len(), now);
+        }
+        if let Some(layer) = media.simulcast {
+            if !self.video_layers.iter().any(|l| l.rid == layer.rid) {
+                self.video_layers.push(layer);
+            }
+            let decision = self.video_selector.on_packet(layer.index, keyframe, now);
+            if decision.request_keyframe && !self.bitrate.allocation().video_paused {
+                log::debug!("WhepServerTask waiting layer switch, requesting keyframe");
+                self.outputs
+                    .push_back(WebrtcTaskOutput::RequestKeyframeTrack {
+                        track_id: self.video_track_id,
+                        kind: KeyframeRequestKind::Pli,
+                    });
+            }
+            if !decision.forward {
+                return;
+            }
+            //the viewer stream is not simulcast
+            media.header.ext_vals.rid = None;
+            media.header.ext_vals.rid_repair = None;
+        }
+        if is_video {
+            let forward = if self.bitrate.allocation().video_paused
+                || (self.video_wait_keyframe && !keyframe)
+            {
+                false
+            } else {
+                if self.video_wait_keyframe {
+                    log::info!("WhepServerTask video resumed");
+                    self.video_wait_keyframe = false;
+                }
+                let decision =
+                    self.video_svc
+                        .on_packet(media.codec, &media.payload, media.header.marker);
+                media.header.marker = decision.marker;
+                decision.forward
+            };
+            if !forward {
+                self.video_rewriter.skip(media.header.ssrc);
+                return;
+            }
+        }
+
+        let (mid, rewriter) = if is_video {
+            (self.video_mid, &mut self.video_rewriter)
+        } else {
+            (self.audio_mid, &mut self.audio_rewriter)
+        };
+        let pt = if let Some((pt, _)) = self.codecs.iter().find(|(_, codec)| *codec == media.codec)
+        {
+            *pt
+        } else {
+            log::debug!(
+                "WhepServerTask viewer doesn't support codec {:?}",
+                media.codec
+            );
+            return;
+        };
+        let rewritten = rewriter.rewrite(
+            media.header.ssrc,
+            media.seq_no,
+            media.header.timestamp,
+            media.timestamp,
+        );
+        if rewritten.switched && is_video && !keyframe {
+            log::info!("WhepServerTask video source switched, requesting keyframe");
+            self.outputs
+                .push_back(WebrtcTaskOutput::RequestKeyframeTrack {
+                    track_id: self.video_track_id,
+                    kind: KeyframeRequestKind::Pli,
+                });
+        }
+
+        let wallclock = self.lip_sync.wallclock(
+            media.sender_report,
+            media.header.timestamp,
+            if is_video { 90000 } else { 48000 },
+            media.timestamp,
+        );
+
+        if let Some(mid) = mid {
+            if let Some(stream) = self.rtc.direct_api().stream_tx_by_mid(mid, None) {
+                log::debug!(
+                    "Write rtp for mid: {:?} {} {} {}",
+                    mid,
+                    rewritten.seq_no,
+                    rewritten.timestamp,
+                    media.payload.len()
+                );
+                if let (Some(capture), false) = (&self.capture, self.raw_packets) {
+                    capture.outgoing(
+                        RtpFields {
+                            payload_type: *pt,
+                            marker: media.header.marker,
+                            sequence_number: *rewritten.seq_no as u16,
+                            timestamp: rewritten.timestamp,
+                            ssrc: *stream.ssrc(),
+                            ext_vals: media.header.ext_vals,
+                        },
+                        &media.payload,
+                    );
+                }
+                if let Err(e) = stream.write_rtp(
+                    pt,
+                    rewritten.seq_no,
+                    rewritten.timestamp,
+                    wallclock,
+                    media.header.marker,
+                    media.header.ext_vals,
+                    is_video,
+                    media.payload,
+                ) {
+                    log::error!("Error writing rtp: {}", e);
+                }
+                log::trace!("clear timeout with media");
+                self.timeout = None;
+            }
+        } else {
+            log::error!("No mid for media {:?}", media.codec);
+        }
+    }
+
+    fn on_bitrate_estimate(&mut self, now: Instant, estimate: Bitrate) {
+        log::debug!("WhepServerTask egress bitrate estimate {}", estimate);
+        let was_paused = self.bitrate.allocation().video_paused;
+        if let Some(allocation) = self.bitrate.on_estimate(estimate, now) {
+            self.video_selector.set_limit(allocation.layer_limit);
+            if allocation.video_paused && !was_paused {
+                log::warn!("WhepServerTask pausing video, estimate {}", estimate);
+            } else if !allocation.video_paused && was_paused {
+                //the viewer decoder lost its references while paused
+                self.video_wait_keyframe = true;
+                self.outputs
+                    .push_back(WebrtcTaskOutput::RequestKeyframeTrack {
+                        track_id: self.video_track_id,
+                        kind: KeyframeRequestKind::Pli,
+                    });
+            }
+        }
+
+        let current = self.video_selector.current().unwrap_or(0);
+        let mut bwe = self.rtc.bwe();
+        if let Some(bitrate) = self.bitrate.layer_bitrate(current) {
+            bwe.set_current_bitrate(Bitrate::bps(bitrate));
+        }
+        if let Some(desired) = self.bitrate.desired_bitrate() {
+            bwe.set_desired_bitrate(desired);
+        }
+    }
+
+    fn on_sub_resource_request(&mut self, now: Instant, req: &HttpRequest) -> HttpResponse {
+        let response = |status: u16, body: &[u8]| HttpResponse {
+            req_id: req.req_id,
+            status,
+            headers: Default::default(),
+            body: body.to_vec(),
+        };
+        if get_resource_sub_path(&req.path) != "layer" {
+            return response(404, b"Not Found");
+        }
+
+        match req.method.as_str() {
+            "POST" => {
+                let layer: LayerRequest = match serde_json::from_slice(&req.body) {
+                    Ok(layer) => layer,
+                    Err(e) => {
+                        return problem_response(req.req_id, 400, "Invalid layer", &e.to_string())
+                    }
+                };
+                if layer.encoding_id.is_none() && self.video_layers.is_empty() {
+                    //without simulcast the spatial layer is selected inside the SVC stream
+                    log::info!(
+                        "WhepServerTask select svc layer ({:?}, {:?})",
+                        layer.spatial_layer_id,
+                        layer.temporal_layer_id
+                    );
+                    self.video_svc
+                        .set_target(layer.spatial_layer_id, layer.temporal_layer_id);
+                    return response(200, b"OK");
+                }
+                let index = if let Some(encoding_id) = &layer.encoding_id {
+                    self.video_layers
+                        .iter()
+                        .find(|l| l.rid.to_string() == *encoding_id)
+                        .map(|l| l.index)
+                } else {
+                    layer.spatial_layer_id
+                };
+                if let Some(index) = index {
+                    log::info!("WhepServerTask select layer {}", index);
+                    self.video_selector.select(Some(index));
+                    self.video_svc.set_target(None, layer.temporal_layer_id);
+                    response(200, b"OK")
+                } else {
+                    problem_response(
+                        req.req_id,
+                        400,
+                        "Invalid layer",
+                        &format!(
+                            "unknown layer, current target {:?}",
+                            self.video_selector.target(now)
+                        ),
+                    )
+                }
+            }
+            "GET" => {
+                let allocation = self.bitrate.allocation();
+                let body = serde_json::json!({
+                    "layers": self.video_layers.iter().map(|l| serde_json::json!({
+                        "encodingId": l.rid.to_string(),
+                        "spatialLayerId": l.index,
+                    })).collect::<Vec<_>>(),
+                    "currentLayer": self.video_selector.current(),
+                    "targetLayer": self.video_selector.target(now),
+                    "currentSvcLayer": self.video_svc.current(),
+                    "estimatedBitrate": self.bitrate.estimate(),
+                    "videoPaused": allocation.video_paused,
+                });
+                HttpResponse {
+                    req_id: req.req_id,
+                    status: 200,
+                    headers: HashMap::from([(
+                        "Content-Type".to_string(),
+                        "application/json".to_string(),
+                    )]),
+                    body: body.to_string().into_bytes(),
+                }
+            }
+            "DELETE" => {
+                log::info!("WhepServerTask unselect layer");
+                self.video_selector.select(None);
+                self.video_svc.set_target(None, None);
+                response(200, b"OK")
+            }
+            _ => response(405, b"Method Not Allowed"),
         }
     }
 }
@@ -106,10 +440,62 @@ impl WebrtcTask for WhepServerTask {
 
     fn input<'b>(&mut self, now: Instant, event: WebrtcTaskInput<'b>) -> bool {
         match event {
-            WebrtcTaskInput::Io(IoEvent::HttpRequest(_req)) => {
-                todo!()
+            WebrtcTaskInput::Io(IoEvent::HttpRequest(req))
+                if !get_resource_sub_path(&req.path).is_empty() =>
+            {
+                let res = self.on_sub_resource_request(now, &req);
+                self.outputs.push_back(IoAction::HttpResponse(res).into());
+                true
             }
+            WebrtcTaskInput::Io(IoEvent::HttpRequest(req)) => match req.method.as_str() {
+                "DELETE" => {
+                    log::info!("WhepServerTask received delete request, ending session");
+                    self.rtc.disconnect();
+                    self.outputs.push_back(
+                        IoAction::HttpResponse(HttpResponse {
+                            req_id: req.req_id,
+                            status: 200,
+                            headers: Default::default(),
+                            body: b"OK".to_vec(),
+                        })
+                        .into(),
+                    );
+                    self.outputs.push_back(WebrtcTaskOutput::TaskEnded);
+                    true
+                }
+                "PATCH" => {
+                    let res = handle_ice_patch(&mut self.rtc, &req, &self.local_candidates);
+                    if let Some(new_ufrag) = res.new_ufrag {
+                        log::info!("WhepServerTask ice restart, new ufrag {}", new_ufrag);
+                        let old_ufrag = std::mem::replace(&mut self.ice_ufrag, new_ufrag.clone());
+                        self.outputs.push_back(WebrtcTaskOutput::IceRestart {
+                            old_ufrag,
+                            new_ufrag,
+                        });
+                    }
+                    self.outputs
+                        .push_back(IoAction::HttpResponse(res.response).into());
+                    log::trace!("clear timeout with ice patch");
+                    self.timeout = None;
+                    true
+                }
+                _ => {
+                    self.outputs.push_back(
+                        IoAction::HttpResponse(HttpResponse {
+                            req_id: req.req_id,
+                            status: 405,
+                            headers: Default::default(),
+                            body: b"Method Not Allowed".to_vec(),
+                        })
+                        .into(),
+                    );
+                    true
+                }
+            },
             WebrtcTaskInput::Io(IoEvent::UdpSocketRecv { from, to, buf }) => {
+                if let Some(capture) = &mut self.capture {
+                    capture.set_addresses(to, from);
+                }
                 if let Err(e) = self.rtc.handle_input(Input::Receive(
                     now,
                     Receive::new(Protocol::Udp, from, to, buf).expect("Should parse udp"),
@@ -121,41 +507,26 @@ impl WebrtcTask for WhepServerTask {
                 true
             }
             WebrtcTaskInput::TrackMedia(media) => {
-                let (mid, nackable) = if *media.header.payload_type == 111 {
-                    //audio
-                    (self.audio_mid, false)
-                } else {
-                    (self.video_mid, true)
-                };
-
-                if let Some(mid) = mid {
-                    if let Some(stream) = self.rtc.direct_api().stream_tx_by_mid(mid, None) {
-                        log::debug!(
-                            "Write rtp for mid: {:?} {} {} {}",
-                            mid,
-                            media.seq_no,
-                            media.header.timestamp,
-                            media.payload.len()
-                        );
-                        if let Err(e) = stream.write_rtp(
-                            media.header.payload_type,
-                            media.seq_no,
-                            media.header.timestamp,
-                            media.timestamp,
-                            media.header.marker,
-                            media.header.ext_vals,
-                            nackable,
-                            media.payload,
-                        ) {
-                            log::error!("Error writing rtp: {}", e);
-                        }
-                        log::trace!("clear timeout with media");
-                        self.timeout = None;
-                    }
-                } else {
-                    log::error!("No mid for media {}", media.header.payload_type);
-                }
-
+                self.on_track_media(now, media);
+                true
+            }
+            WebrtcTaskInput::ChannelData(msg) => {
+                self.data_channels.write(&mut self.rtc, &msg);
+                log::trace!("clear timeout with channel data");
+                self.timeout = None;
+                true
+            }
+            WebrtcTaskInput::Capture(capture) => {
+                self.capture = capture.map(|mut capture| {
+                    capture.set_extensions(self.extensions);
+                    capture
+                });
+                false
+            }
+            WebrtcTaskInput::EndTask => {
+                log::info!("WhepServerTask ending by request");
+                self.rtc.disconnect();
+                self.outputs.push_back(WebrtcTaskOutput::TaskEnded);
                 true
             }
             _ => panic!("Should not receive this event."),
@@ -191,10 +562,13 @@ impl WebrtcTask for WhepServerTask {
                 Event::Connected => {
                     log::info!("WhepServerTask connected");
                     self.outputs.push_back(WebrtcTaskOutput::SubscribeTrack {
-                        track_id: track_id_builder(&self.channel, MediaKind::Audio),
+                        track_id: self.audio_track_id,
                     });
                     self.outputs.push_back(WebrtcTaskOutput::SubscribeTrack {
-                        track_id: track_id_builder(&self.channel, MediaKind::Video),
+                        track_id: self.video_track_id,
+                    });
+                    self.outputs.push_back(WebrtcTaskOutput::SubscribeTrack {
+                        track_id: self.data_track_id,
                     });
                     None
                 }
@@ -205,19 +579,49 @@ impl WebrtcTask for WhepServerTask {
                     } else {
                         self.video_mid = Some(media.mid);
                     }
+                    self.codecs.extend(negotiated_codecs(&self.rtc, media.mid));
                     None
                 }
                 Event::IceConnectionStateChange(state) => match state {
                     IceConnectionState::Disconnected => Some(WebrtcTaskOutput::TaskEnded),
                     _ => None,
                 },
+                Event::EgressBitrateEstimate(estimate) => {
+                    self.on_bitrate_estimate(now, estimate);
+                    self.outputs.pop_front()
+                }
+                Event::ChannelOpen(id, label) => {
+                    self.data_channels.on_open(id, label);
+                    None
+                }
+                Event::ChannelClose(id) => {
+                    self.data_channels.on_close(id);
+                    None
+                }
+                Event::ChannelData(data) => {
+                    let label = self.data_channels.label(data.id)?.to_string();
+                    Some(WebrtcTaskOutput::ChannelData(ChannelMessage {
+                        track_id: self.data_track_id,
+                        label,
+                        binary: data.binary,
+                        data: data.data,
+                        upstream: true,
+                    }))
+                }
                 Event::KeyframeRequest(mid) => {
                     log::info!("WhepServerTask keyframe request: {:?}", mid);
                     Some(WebrtcTaskOutput::RequestKeyframeTrack {
-                        track_id: track_id_builder(&self.channel, MediaKind::Video),
+                        track_id: self.video_track_id,
                         kind: mid.kind,
                     })
                 }
+                Event::RawPacket(packet) => {
+                    if let Some(capture) = &self.capture {
+                        capture.raw_packet(&packet);
+                    }
+                    //not an action, keep polling
+                    self.pop_action(now)
+                }
                 _ => None,
             },
         }
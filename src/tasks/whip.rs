@@ -5,16 +5,24 @@ use std::{
 };
 
 use str0m::{
-    change::{DtlsCert, SdpOffer},
-    media::{MediaKind, Mid},
+    change::DtlsCert,
+    format::Codec,
+    media::{MediaKind, Mid, Pt, Rid},
     net::{Protocol, Receive},
     Candidate, Event, IceConnectionState, Input, Output, Rtc,
 };
 
 use crate::{
-    http::get_http_auth,
+    capture::{ExtensionIds, RtpCapture},
+    http::get_resource_sub_path,
     io::{HttpRequest, HttpResponse, IoAction, IoEvent},
-    tasks::{track_id_builder, TrackMedia},
+    tasks::{
+        check_offer_codecs, configure_codecs,
+        data_channel::{data_track_id, ChannelMessage, DataChannels},
+        negotiated_codecs, parse_sdp_offer, parse_simulcast_rids, track_id_builder,
+        trickle_ice::handle_ice_patch,
+        CreateTaskError, SimulcastLayer, TrackMedia,
+    },
 };
 
 use super::{WebrtcTask, WebrtcTaskInput, WebrtcTaskOutput};
@@ -23,25 +31,48 @@ pub struct WhipServerTask {
     ice_ufrag: String,
     timeout: Option<Instant>,
     rtc: Rtc,
+    local_candidates: Vec<Candidate>,
     outputs: VecDeque<WebrtcTaskOutput>,
     audio_mid: Option<Mid>,
     video_mid: Option<Mid>,
+    /// Negotiated payload types of the publisher, used to tag media with its codec.
+    codecs: Vec<(Pt, Codec)>,
+    /// Simulcast rids of the video, lowest quality first. Empty without simulcast.
+    video_rids: Vec<Rid>,
     audio_track_id: u64,
     video_track_id: u64,
+    data_track_id: u64,
+    data_channels: DataChannels,
+    capture: Option<RtpCapture>,
+    /// Header extension ids of the answer, for the RTP capture.
+    extensions: ExtensionIds,
+    /// str0m emits the packets it receives and sends, the capture takes them from there.
+    raw_packets: bool,
 }
 
 impl WhipServerTask {
     pub fn new(
         dtls_cert: DtlsCert,
         req: HttpRequest,
+        channel: String,
+        resource_path: String,
         local_addrs: Vec<SocketAddr>,
-    ) -> WhipServerTask {
-        let rtc_config = Rtc::builder()
-            .set_rtp_mode(true)
-            .set_ice_lite(true)
-            .set_dtls_cert(dtls_cert);
+        codecs: &[Codec],
+        raw_packets: bool,
+    ) -> Result<WhipServerTask, CreateTaskError> {
+        let offer = parse_sdp_offer(&req)?;
+        let sdp = String::from_utf8_lossy(&req.body);
+        check_offer_codecs(&sdp, codecs)?;
+        let video_rids = parse_simulcast_rids(&sdp, "send");
+        let rtc_config = configure_codecs(
+            Rtc::builder()
+                .set_rtp_mode(true)
+                .set_ice_lite(true)
+                .set_dtls_cert(dtls_cert)
+                .enable_raw_packets(raw_packets),
+            codecs,
+        );
 
-        let channel = get_http_auth(&req);
         log::info!(
             "WhipServerTask::new req: {} addr {:?} => channel {}",
             req.path,
@@ -53,38 +84,47 @@ impl WhipServerTask {
         let mut rtc = rtc_config.build();
         rtc.direct_api().enable_twcc_feedback();
 
-        for addr in local_addrs {
-            rtc.add_local_candidate(
-                Candidate::host(addr, Protocol::Udp).expect("Should create candidate"),
-            );
+        let local_candidates: Vec<Candidate> = local_addrs
+            .into_iter()
+            .map(|addr| Candidate::host(addr, Protocol::Udp).expect("Should create candidate"))
+            .collect();
+        for candidate in &local_candidates {
+            rtc.add_local_candidate(candidate.clone());
         }
 
-        let offer = SdpOffer::from_sdp_string(&String::from_utf8_lossy(&req.body))
-            .expect("Should parse offer");
         let answer = rtc
             .sdp_api()
             .accept_offer(offer)
-            .expect("Should accept offer");
+            .map_err(|e| CreateTaskError::NotAcceptable(e.to_string()))?
+            .to_sdp_string();
 
-        WhipServerTask {
+        Ok(WhipServerTask {
             ice_ufrag,
             timeout: None,
             rtc,
+            local_candidates,
             outputs: VecDeque::from(vec![IoAction::HttpResponse(HttpResponse {
                 req_id: req.req_id,
                 status: 200,
                 headers: HashMap::from([
                     ("Content-Type".to_string(), "application/sdp".to_string()),
-                    ("Location".to_string(), "/whip/endpoint/1234".to_string()),
+                    ("Location".to_string(), resource_path),
                 ]),
-                body: answer.to_sdp_string().as_bytes().to_vec(),
+                body: answer.as_bytes().to_vec(),
             })
             .into()]),
             audio_mid: None,
             video_mid: None,
+            codecs: Vec::new(),
+            video_rids,
             audio_track_id: track_id_builder(&channel, MediaKind::Audio),
             video_track_id: track_id_builder(&channel, MediaKind::Video),
-        }
+            data_track_id: data_track_id(&channel),
+            data_channels: DataChannels::default(),
+            capture: None,
+            extensions: ExtensionIds::from_sdp(&answer),
+            raw_packets,
+        })
     }
 }
 
@@ -109,10 +149,69 @@ impl WebrtcTask for WhipServerTask {
 
     fn input<'b>(&mut self, now: Instant, event: WebrtcTaskInput<'b>) -> bool {
         match event {
-            WebrtcTaskInput::Io(IoEvent::HttpRequest(_req)) => {
-                todo!()
+            WebrtcTaskInput::Io(IoEvent::HttpRequest(req))
+                if !get_resource_sub_path(&req.path).is_empty() =>
+            {
+                self.outputs.push_back(
+                    IoAction::HttpResponse(HttpResponse {
+                        req_id: req.req_id,
+                        status: 404,
+                        headers: Default::default(),
+                        body: b"Not Found".to_vec(),
+                    })
+                    .into(),
+                );
+                true
             }
+            WebrtcTaskInput::Io(IoEvent::HttpRequest(req)) => match req.method.as_str() {
+                "DELETE" => {
+                    log::info!("WhipServerTask received delete request, ending session");
+                    self.rtc.disconnect();
+                    self.outputs.push_back(
+                        IoAction::HttpResponse(HttpResponse {
+                            req_id: req.req_id,
+                            status: 200,
+                            headers: Default::default(),
+                            body: b"OK".to_vec(),
+                        })
+                        .into(),
+                    );
+                    self.outputs.push_back(WebrtcTaskOutput::TaskEnded);
+                    true
+                }
+                "PATCH" => {
+                    let res = handle_ice_patch(&mut self.rtc, &req, &self.local_candidates);
+                    if let Some(new_ufrag) = res.new_ufrag {
+                        log::info!("WhipServerTask ice restart, new ufrag {}", new_ufrag);
+                        let old_ufrag = std::mem::replace(&mut self.ice_ufrag, new_ufrag.clone());
+                        self.outputs.push_back(WebrtcTaskOutput::IceRestart {
+                            old_ufrag,
+                            new_ufrag,
+                        });
+                    }
+                    self.outputs
+                        .push_back(IoAction::HttpResponse(res.response).into());
+                    log::trace!("clear timeout with ice patch");
+                    self.timeout = None;
+                    true
+                }
+                _ => {
+                    self.outputs.push_back(
+                        IoAction::HttpResponse(HttpResponse {
+                            req_id: req.req_id,
+                            status: 405,
+                            headers: Default::default(),
+                            body: b"Method Not Allowed".to_vec(),
+                        })
+                        .into(),
+                    );
+                    true
+                }
+            },
             WebrtcTaskInput::Io(IoEvent::UdpSocketRecv { from, to, buf }) => {
+                if let Some(capture) = &mut self.capture {
+                    capture.set_addresses(to, from);
+                }
                 if let Err(e) = self.rtc.handle_input(Input::Receive(
                     now,
                     Receive::new(Protocol::Udp, from, to, buf).expect("Should parse udp"),
@@ -127,11 +226,16 @@ impl WebrtcTask for WhipServerTask {
                 if track_id == self.video_track_id {
                     if let Some(mid) = self.video_mid {
                         log::info!("Requesting keyframe for video mid: {:?}", mid);
-                        self.rtc
-                            .direct_api()
-                            .stream_rx_by_mid(mid, None)
-                            .expect("Should has video mid")
-                            .request_keyframe(kind);
+                        let rids: Vec<Option<Rid>> = if self.video_rids.is_empty() {
+                            vec![None]
+                        } else {
+                            self.video_rids.iter().map(|rid| Some(*rid)).collect()
+                        };
+                        for rid in rids {
+                            if let Some(stream) = self.rtc.direct_api().stream_rx_by_mid(mid, rid) {
+                                stream.request_keyframe(kind);
+                            }
+                        }
                     } else {
                         log::error!("No video mid for requesting keyframe");
                     }
@@ -139,6 +243,25 @@ impl WebrtcTask for WhipServerTask {
 
                 true
             }
+            WebrtcTaskInput::ChannelData(msg) => {
+                self.data_channels.write(&mut self.rtc, &msg);
+                log::trace!("clear timeout with channel data");
+                self.timeout = None;
+                true
+            }
+            WebrtcTaskInput::Capture(capture) => {
+                self.capture = capture.map(|mut capture| {
+                    capture.set_extensions(self.extensions);
+                    capture
+                });
+                false
+            }
+            WebrtcTaskInput::EndTask => {
+                log::info!("WhipServerTask ending by request");
+                self.rtc.disconnect();
+                self.outputs.push_back(WebrtcTaskOutput::TaskEnded);
+                true
+            }
             _ => panic!("Should not receive this event."),
         }
     }
@@ -171,12 +294,18 @@ impl WebrtcTask for WhipServerTask {
             Output::Event(e) => match e {
                 Event::Connected => {
                     log::info!("WhipServerTask connected");
+                    self.outputs.push_back(WebrtcTaskOutput::PublishCodecs(
+                        self.codecs.iter().map(|(_, codec)| *codec).collect(),
+                    ));
                     self.outputs.push_back(WebrtcTaskOutput::PublishTrack {
                         track_id: self.audio_track_id,
                     });
                     self.outputs.push_back(WebrtcTaskOutput::PublishTrack {
                         track_id: self.video_track_id,
                     });
+                    self.outputs.push_back(WebrtcTaskOutput::PublishTrack {
+                        track_id: self.data_track_id,
+                    });
                     None
                 }
                 Event::MediaAdded(media) => {
@@ -186,21 +315,78 @@ impl WebrtcTask for WhipServerTask {
                     } else {
                         self.video_mid = Some(media.mid);
                     }
+                    self.codecs.extend(negotiated_codecs(&self.rtc, media.mid));
                     None
                 }
                 Event::IceConnectionStateChange(state) => match state {
                     IceConnectionState::Disconnected => Some(WebrtcTaskOutput::TaskEnded),
                     _ => None,
                 },
+                Event::ChannelOpen(id, label) => {
+                    self.data_channels.on_open(id, label);
+                    None
+                }
+                Event::ChannelClose(id) => {
+                    self.data_channels.on_close(id);
+                    None
+                }
+                Event::ChannelData(data) => {
+                    let label = self.data_channels.label(data.id)?.to_string();
+                    Some(WebrtcTaskOutput::ChannelData(ChannelMessage {
+                        track_id: self.data_track_id,
+                        label,
+                        binary: data.binary,
+                        data: data.data,
+                        upstream: false,
+                    }))
+                }
                 Event::RtpPacket(rtp) => {
-                    let track_id = if *rtp.header.payload_type == 111 {
+                    if let (Some(capture), false) = (&self.capture, self.raw_packets) {
+                        capture.incoming((&rtp.header).into(), &rtp.payload);
+                    }
+                    let (mid, rid) = self
+                        .rtc
+                        .direct_api()
+                        .stream_rx(&rtp.header.ssrc)
+                        .map(|stream| (Some(stream.mid()), stream.rid()))
+                        .unwrap_or_default();
+                    let track_id = if mid.is_some() && mid == self.audio_mid {
                         self.audio_track_id
-                    } else {
+                    } else if mid.is_some() && mid == self.video_mid {
                         self.video_track_id
+                    } else {
+                        log::debug!("WhipServerTask rtp from unknown ssrc {:?}", rtp.header.ssrc);
+                        return None;
                     };
-                    Some(WebrtcTaskOutput::TrackMedia(TrackMedia::from_raw(
-                        track_id, rtp,
-                    )))
+                    let codec = self
+                        .codecs
+                        .iter()
+                        .find(|(pt, _)| *pt == rtp.header.payload_type)
+                        .map(|(_, codec)| *codec);
+                    if let Some(codec) = codec {
+                        let mut media = TrackMedia::from_raw(track_id, codec, rtp);
+                        media.simulcast = rid.and_then(|rid| {
+                            let index = self.video_rids.iter().position(|r| *r == rid)?;
+                            Some(SimulcastLayer {
+                                rid,
+                                index: index as u8,
+                            })
+                        });
+                        Some(WebrtcTaskOutput::TrackMedia(media))
+                    } else {
+                        log::debug!(
+                            "WhipServerTask rtp with unknown payload type {}",
+                            rtp.header.payload_type
+                        );
+                        None
+                    }
+                }
+                Event::RawPacket(packet) => {
+                    if let Some(capture) = &self.capture {
+                        capture.raw_packet(&packet);
+                    }
+                    //not an action, keep polling
+                    self.pop_action(now)
                 }
                 _ => None,
             },
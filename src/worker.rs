@@ -3,12 +3,17 @@ use faster_stun::attribute::*;
 use faster_stun::*;
 use parking_lot::Mutex;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
     time::{Duration, Instant},
 };
-use str0m::{change::DtlsCert, media::KeyframeRequestKind};
+use str0m::{
+    change::DtlsCert,
+    format::Codec,
+    media::{KeyframeRequestKind, MediaKind},
+};
 
 use crossbeam::channel::{Receiver, Sender};
 
@@ -29,15 +34,142 @@ type UdpSocket = net::socket2_mmsg::UdpSocket2Mmsg<1024>;
 type UdpSocket = net::socket2::UdpSocket2;
 
 use crate::{
-    io::{HttpResponse, IoAction, IoEvent},
+    capture::{CaptureTarget, RtpCapture},
+    gop_cache::GopCache,
+    http::{
+        auth::{AllowAllAuthorizer, AuthRole, Authorizer},
+        build_resource_path, get_request_channel, new_resource_token, parse_channel_path,
+        parse_resource_path, problem_response,
+    },
+    io::{HttpRequest, HttpResponse, IoAction, IoEvent},
+    metrics::Metrics,
     net::{self, UdpSocketGeneric},
-    tasks::{ComposeTask, TrackMedia, WebrtcTask, WebrtcTaskInput, WebrtcTaskOutput},
+    recorder::Recording,
+    rtmp::RtmpPublish,
+    tasks::{
+        data_channel::ChannelMessage, track_id_builder, ComposeTask, CreateTaskError, RoomEvent,
+        TrackMedia, WebrtcTask, WebrtcTaskInput, WebrtcTaskOutput, SUPPORTED_CODECS,
+    },
 };
 
+/// What to do when a second WHIP publisher joins a channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PublisherPolicy {
+    /// Reject the new publisher with 409 Conflict.
+    #[default]
+    Reject,
+    /// Accept the new publisher and end the old one.
+    Takeover,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelPublisher {
+    pub worker_id: usize,
+    pub task_id: usize,
+    /// Codecs negotiated by the publisher, empty until it connects.
+    pub codecs: Vec<Codec>,
+}
+
+/// Channel => its publisher, shared by all workers.
+pub type PublisherRegistry = Arc<Mutex<HashMap<String, ChannelPublisher>>>;
+
+/// Room => its participant ids, shared by all workers.
+pub type RoomRegistry = Arc<Mutex<HashMap<String, Vec<String>>>>;
+
+/// Channels which are recorded while published, shared by all workers and the admin API.
+pub type RecordingRegistry = Arc<Mutex<HashSet<String>>>;
+
+#[derive(Clone, Debug)]
+pub struct WorkerConfig {
+    /// Accept the legacy `/whip/endpoint` and `/whep/endpoint` routes which use the raw
+    /// Authorization header as channel name.
+    pub legacy_auth_channel: bool,
+    /// Checks every WHIP/WHEP request before a task is created.
+    pub authorizer: Arc<dyn Authorizer>,
+    pub publisher_policy: PublisherPolicy,
+    /// Codecs allowed server-wide.
+    pub codecs: Vec<Codec>,
+    /// Max bytes of the video GOP cached per channel for new viewers, 0 disables the cache.
+    pub gop_cache_bytes: usize,
+    /// Per channel override of `gop_cache_bytes`.
+    pub gop_cache_channels: HashMap<String, usize>,
+    /// Minimum interval between keyframe requests sent to the publisher of a track.
+    pub keyframe_request_interval: Duration,
+    /// Data channel messages bigger than this are not relayed.
+    pub data_channel_max_message: usize,
+    /// Relay viewer data channel messages to the publisher.
+    pub data_channel_upstream: bool,
+    /// In rooms, send only the dominant speaker video in its best simulcast layer.
+    pub room_speaker_priority: bool,
+    /// Directory of the channel recordings.
+    pub record_dir: PathBuf,
+    /// Channels recorded from startup, more can be added with the admin API.
+    pub record_channels: Vec<String>,
+    /// Directory of the session RTP captures.
+    pub capture_dir: PathBuf,
+    /// Sessions emit their RTCP and sent RTP packets so captures include them, at the cost of a
+    /// copy of every packet.
+    pub capture_raw_packets: bool,
+    /// Directory of the files which can be played to a channel.
+    pub playback_dir: PathBuf,
+    /// Bearer token of the admin API, which is open when not set.
+    pub admin_token: Option<String>,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            legacy_auth_channel: false,
+            authorizer: Arc::new(AllowAllAuthorizer),
+            publisher_policy: PublisherPolicy::default(),
+            codecs: SUPPORTED_CODECS.to_vec(),
+            gop_cache_bytes: 0,
+            gop_cache_channels: HashMap::new(),
+            keyframe_request_interval: Duration::from_millis(500),
+            data_channel_max_message: 16 * 1024,
+            data_channel_upstream: false,
+            room_speaker_priority: false,
+            record_dir: PathBuf::from("recordings"),
+            record_channels: Vec::new(),
+            capture_dir: PathBuf::from("captures"),
+            capture_raw_packets: false,
+            playback_dir: PathBuf::from("media"),
+            admin_token: None,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum BusEvent {
     TrackMedia(TrackMedia),
     TrackKeyframeRequest(u64, KeyframeRequestKind),
+    /// Ask the worker `worker_id` to end one of its tasks.
+    EndTask {
+        worker_id: usize,
+        task_id: usize,
+    },
+    Room {
+        room: String,
+        event: RoomEvent,
+    },
+    ChannelData(ChannelMessage),
+    /// Recording of a channel was enabled or disabled from the admin API.
+    Record {
+        channel: String,
+        enabled: bool,
+    },
+    /// RTP capture of sessions was started or stopped from the admin API.
+    Capture {
+        target: CaptureTarget,
+        enabled: bool,
+    },
+}
+
+/// Keyframe requests of viewers for a track published on this worker.
+struct KeyframeThrottle {
+    last_sent: Instant,
+    /// Requests received since `last_sent`, merged into one.
+    pending: Option<KeyframeRequestKind>,
 }
 
 struct BusChannelContainer {
@@ -47,6 +179,14 @@ struct BusChannelContainer {
 
 struct TaskContainer {
     task: ComposeTask,
+    /// Channel registered in the publisher registry by this task.
+    publish_channel: Option<String>,
+    /// (room, participant) registered in the room registry by this task.
+    room: Option<(String, String)>,
+    /// Secret part of the session resource path, required by follow-up requests.
+    resource_token: String,
+    /// Recording of the published channel, fed with the media output by the task.
+    recording: Option<Recording>,
     remotes: Vec<SocketAddr>,
     sub_channels: Vec<u64>,
     pub_channels: Vec<u64>,
@@ -56,6 +196,10 @@ impl From<ComposeTask> for TaskContainer {
     fn from(task: ComposeTask) -> TaskContainer {
         TaskContainer {
             task,
+            publish_channel: None,
+            room: None,
+            resource_token: String::new(),
+            recording: None,
             remotes: Vec::new(),
             sub_channels: Vec::new(),
             pub_channels: Vec::new(),
@@ -64,6 +208,8 @@ impl From<ComposeTask> for TaskContainer {
 }
 
 pub struct Worker {
+    worker_id: usize,
+    config: WorkerConfig,
     task_id_seed: usize,
     udp_socket: UdpSocket,
     udp_socket_local_addr: SocketAddr,
@@ -71,25 +217,51 @@ pub struct Worker {
     ext_recv: Receiver<IoEvent<'static>>,
     bus_send: Arc<Mutex<Bus<BusEvent>>>,
     bus_recv: BusReader<BusEvent>,
+    publishers: PublisherRegistry,
+    rooms: RoomRegistry,
+    recordings: RecordingRegistry,
     bus_channels: HashMap<u64, BusChannelContainer>,
+    /// Room => participant tasks on this worker.
+    room_tasks: HashMap<String, Vec<usize>>,
     tasks: HashMap<usize, TaskContainer>,
     task_remotes: HashMap<SocketAddr, usize>,
     task_ufrags: HashMap<String, usize>,
     ended_tasks: Vec<usize>,
+    /// Video track => GOP cache limit, from the per channel config.
+    gop_cache_limits: HashMap<u64, usize>,
+    /// Replicated on every worker from the bus.
+    gop_caches: HashMap<u64, GopCache>,
+    /// (task, track) subscribed since the last cycle, waiting for the GOP replay.
+    new_subscribers: Vec<(usize, u64)>,
+    keyframe_throttles: HashMap<u64, KeyframeThrottle>,
+    metrics: Arc<Metrics>,
     dtls_cert: DtlsCert,
 }
 
 impl Worker {
     pub fn new(
+        worker_id: usize,
+        config: WorkerConfig,
         ip_addr: IpAddr,
         ext_send: Sender<IoAction>,
         ext_recv: Receiver<IoEvent<'static>>,
         bus_send: Arc<Mutex<Bus<BusEvent>>>,
         bus_recv: BusReader<BusEvent>,
+        publishers: PublisherRegistry,
+        rooms: RoomRegistry,
+        recordings: RecordingRegistry,
+        metrics: Arc<Metrics>,
     ) -> Worker {
         let udp_socket = UdpSocket::new(SocketAddr::new(ip_addr, 0));
+        let gop_cache_limits = config
+            .gop_cache_channels
+            .iter()
+            .map(|(channel, bytes)| (track_id_builder(channel, MediaKind::Video), *bytes))
+            .collect();
 
         Worker {
+            worker_id,
+            config,
             task_id_seed: 0,
             udp_socket_local_addr: udp_socket.local_addr(),
             udp_socket,
@@ -97,11 +269,20 @@ impl Worker {
             ext_recv,
             bus_send,
             bus_recv,
+            publishers,
+            rooms,
+            recordings,
             bus_channels: HashMap::new(),
+            room_tasks: HashMap::new(),
             tasks: HashMap::new(),
             task_remotes: HashMap::new(),
             task_ufrags: HashMap::new(),
             ended_tasks: Vec::new(),
+            gop_cache_limits,
+            gop_caches: HashMap::new(),
+            new_subscribers: Vec::new(),
+            keyframe_throttles: HashMap::new(),
+            metrics,
             dtls_cert: DtlsCert::new_openssl(),
         }
     }
@@ -112,6 +293,8 @@ impl Worker {
 
     pub fn process_cycle(&mut self) -> Option<()> {
         let started = Instant::now();
+        //viewers subscribed since the last cycle get the cached GOP before any live media
+        self.process_gop_replays();
         self.process_bus_recv();
         self.process_http();
         self.process_tick();
@@ -136,85 +319,529 @@ impl Worker {
         for (_task_id, task) in self.tasks.iter_mut() {
             task.task.tick(instant);
         }
+        self.gop_caches.retain(|_, cache| !cache.is_stale(instant));
+        self.flush_keyframe_requests(instant);
+    }
+
+    /// Send the keyframe requests merged during the throttling interval.
+    fn flush_keyframe_requests(&mut self, now: Instant) {
+        let interval = self.config.keyframe_request_interval;
+        for (track_id, throttle) in self.keyframe_throttles.iter_mut() {
+            if now - throttle.last_sent < interval {
+                continue;
+            }
+            if let Some(kind) = throttle.pending.take() {
+                throttle.last_sent = now;
+                Self::request_keyframe(
+                    now,
+                    *track_id,
+                    kind,
+                    &self.bus_channels,
+                    &mut self.tasks,
+                    &self.metrics,
+                );
+            }
+        }
+        self.keyframe_throttles.retain(|_, throttle| {
+            throttle.pending.is_some() || now - throttle.last_sent < interval
+        });
+    }
+
+    fn request_keyframe(
+        now: Instant,
+        track_id: u64,
+        kind: KeyframeRequestKind,
+        bus_channels: &HashMap<u64, BusChannelContainer>,
+        tasks: &mut HashMap<usize, TaskContainer>,
+        metrics: &Metrics,
+    ) {
+        if let Some(channel) = bus_channels.get(&track_id) {
+            for source in &channel.sources {
+                if let Some(task) = tasks.get_mut(source) {
+                    Metrics::inc(&metrics.keyframe_requests_forwarded);
+                    task.task.input(
+                        now,
+                        WebrtcTaskInput::RequestKeyframeTrack { track_id, kind },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Send the cached GOP of a video track to the viewers which just subscribed to it.
+    fn process_gop_replays(&mut self) {
+        let now = Instant::now();
+        for (task_id, track_id) in self.new_subscribers.drain(..) {
+            if let (Some(cache), Some(task)) =
+                (self.gop_caches.get(&track_id), self.tasks.get_mut(&task_id))
+            {
+                let packets = cache.replay(now);
+                log::info!(
+                    "Replay {} cached packets of track {track_id} to task {task_id}",
+                    packets.len()
+                );
+                for media in packets {
+                    task.task.input(now, WebrtcTaskInput::TrackMedia(media));
+                }
+            }
+        }
     }
 
     fn process_http(&mut self) {
         while let Ok(event) = self.ext_recv.try_recv() {
             match event {
-                IoEvent::HttpRequest(req) => match req.path.as_str() {
-                    "/whip/endpoint" => {
-                        let task_id = self.task_id_seed;
-                        self.task_id_seed += 1;
-
-                        let task = ComposeTask::Whip(crate::tasks::whip::WhipServerTask::new(
-                            self.dtls_cert.clone(),
-                            req,
-                            vec![self.udp_socket.local_addr()],
-                        ));
-                        log::info!("Created whip task id: {}, ufrag: {}", task_id, task.ufrag());
-
-                        self.task_ufrags.insert(task.ufrag(), task_id);
-                        let mut task_container = task.into();
-                        Self::pop_task(
-                            Instant::now(),
-                            task_id,
-                            &mut task_container,
-                            &mut self.udp_socket,
-                            &self.ext_send,
-                            &self.bus_send,
-                            &mut self.bus_channels,
-                            &mut self.ended_tasks,
-                        );
-
-                        self.tasks.insert(task_id, task_container);
+                IoEvent::HttpRequest(req) => {
+                    if req.method == "POST" {
+                        if let Some(channel) = self.get_channel(&req, "whip") {
+                            self.create_whip_task(req, channel);
+                            continue;
+                        }
+                        if let Some(channel) = self.get_channel(&req, "whep") {
+                            self.create_whep_task(req, channel);
+                            continue;
+                        }
+                        if let Some(room) = self.get_channel(&req, "room") {
+                            self.create_room_task(req, room);
+                            continue;
+                        }
+                        //admin token is checked by the controller
+                        if let Some(channel) = parse_channel_path(&req.path, "admin/playbacks") {
+                            let channel = channel.to_string();
+                            self.create_playback_task(req, channel);
+                            continue;
+                        }
                     }
-                    "/whep/endpoint" => {
-                        let task_id = self.task_id_seed;
-                        self.task_id_seed += 1;
-
-                        let task = ComposeTask::Whep(crate::tasks::whep::WhepServerTask::new(
-                            self.dtls_cert.clone(),
-                            req,
-                            vec![self.udp_socket.local_addr()],
-                        ));
-                        log::info!("Created whep task id: {}, ufrag: {}", task_id, task.ufrag());
-
-                        self.task_ufrags.insert(task.ufrag(), task_id);
-                        let mut task_container = task.into();
-                        Self::pop_task(
-                            Instant::now(),
-                            task_id,
-                            &mut task_container,
-                            &mut self.udp_socket,
-                            &self.ext_send,
-                            &self.bus_send,
-                            &mut self.bus_channels,
-                            &mut self.ended_tasks,
-                        );
+                    self.forward_http_to_task(req);
+                }
+                IoEvent::RtmpPublish(publish) => self.create_rtmp_task(publish),
+                _ => panic!("Should not receive this event."),
+            }
+        }
+    }
 
-                        self.tasks.insert(task_id, task_container);
-                    }
-                    _ => {
-                        self.ext_send
-                            .send(IoAction::HttpResponse(HttpResponse {
-                                req_id: req.req_id,
-                                status: 404,
-                                headers: Default::default(),
-                                body: b"Not Found".to_vec(),
-                            }))
-                            .unwrap();
-                    }
+    fn get_channel(&self, req: &HttpRequest, kind: &str) -> Option<String> {
+        get_request_channel(req, kind, self.config.legacy_auth_channel)
+    }
+
+    fn create_whip_task(&mut self, req: HttpRequest, channel: String) {
+        if let Err(e) = self
+            .config
+            .authorizer
+            .authorize(&req, &channel, AuthRole::Publish)
+        {
+            log::warn!("Rejected whip request for channel {}: {:?}", channel, e);
+            let res = problem_response(req.req_id, e.status(), e.title(), e.detail());
+            self.send_response(res);
+            return;
+        }
+
+        let task_id = self.task_id_seed;
+        self.task_id_seed += 1;
+        let resource_token = new_resource_token();
+
+        let req_id = req.req_id;
+        let publish_channel = channel.clone();
+        match crate::tasks::whip::WhipServerTask::new(
+            self.dtls_cert.clone(),
+            req,
+            channel,
+            build_resource_path("whip", self.worker_id, task_id, &resource_token),
+            vec![self.udp_socket.local_addr()],
+            &self.config.codecs,
+            self.config.capture_raw_packets,
+        ) {
+            Ok(task) => {
+                if !self.claim_publisher(&publish_channel, task_id) {
+                    log::warn!(
+                        "Rejected whip task, channel {publish_channel} already has a publisher"
+                    );
+                    let res = problem_response(
+                        req_id,
+                        409,
+                        "Conflict",
+                        "channel already has a publisher",
+                    );
+                    self.send_response(res);
+                    return;
+                }
+                let task = ComposeTask::Whip(task);
+                log::info!("Created whip task id: {}, ufrag: {}", task_id, task.ufrag());
+                let record = self.recordings.lock().contains(&publish_channel);
+                self.add_task(task_id, task, Some(publish_channel), resource_token);
+                if record {
+                    self.start_recording(task_id);
+                }
+            }
+            Err(e) => {
+                log::warn!("Failed to create whip task: {:?}", e);
+                self.send_error(req_id, &e);
+            }
+        }
+    }
+
+    /// Register the task as the publisher of the channel, across all workers.
+    /// Return false if the channel already has a publisher and the policy is to reject.
+    fn claim_publisher(&mut self, channel: &str, task_id: usize) -> bool {
+        let old = {
+            let mut publishers = self.publishers.lock();
+            let old = publishers.get(channel).cloned();
+            if old.is_some() && self.config.publisher_policy == PublisherPolicy::Reject {
+                return false;
+            }
+            publishers.insert(
+                channel.to_string(),
+                ChannelPublisher {
+                    worker_id: self.worker_id,
+                    task_id,
+                    codecs: Vec::new(),
                 },
-                _ => panic!("Should not receive this event."),
+            );
+            old
+        };
+
+        //the registry is unlocked before broadcasting: the bus blocks while a reader is full,
+        //and other workers lock the registry before draining their reader
+        if let Some(ChannelPublisher {
+            worker_id,
+            task_id: old_task_id,
+            ..
+        }) = old
+        {
+            log::info!(
+                "Channel {channel} taken over by task {task_id}, ending task {old_task_id} on worker {worker_id}"
+            );
+            self.bus_send.lock().broadcast(BusEvent::EndTask {
+                worker_id,
+                task_id: old_task_id,
+            });
+        }
+        true
+    }
+
+    /// Record the channel published by a task, if not already recording.
+    fn start_recording(&mut self, task_id: usize) {
+        let container = match self.tasks.get_mut(&task_id) {
+            Some(container) => container,
+            None => return,
+        };
+        let channel = match &container.publish_channel {
+            Some(channel) if container.recording.is_none() => channel.clone(),
+            _ => return,
+        };
+        container.recording = Some(Recording::start(
+            &self.config.record_dir,
+            &channel,
+            self.metrics.clone(),
+        ));
+        //the file starts on a keyframe, don't wait for the next periodic one
+        Self::request_keyframe(
+            Instant::now(),
+            track_id_builder(&channel, MediaKind::Video),
+            KeyframeRequestKind::Pli,
+            &self.bus_channels,
+            &mut self.tasks,
+            &self.metrics,
+        );
+    }
+
+    fn create_whep_task(&mut self, req: HttpRequest, channel: String) {
+        if let Err(e) = self
+            .config
+            .authorizer
+            .authorize(&req, &channel, AuthRole::Subscribe)
+        {
+            log::warn!("Rejected whep request for channel {}: {:?}", channel, e);
+            let res = problem_response(req.req_id, e.status(), e.title(), e.detail());
+            self.send_response(res);
+            return;
+        }
+
+        let task_id = self.task_id_seed;
+        self.task_id_seed += 1;
+        let resource_token = new_resource_token();
+
+        //viewers are constrained to the codecs of the channel publisher when it is known
+        let publisher_codecs = self
+            .publishers
+            .lock()
+            .get(&channel)
+            .map(|p| p.codecs.clone())
+            .unwrap_or_default();
+        let codecs: Vec<Codec> = if publisher_codecs.is_empty() {
+            self.config.codecs.clone()
+        } else {
+            self.config
+                .codecs
+                .iter()
+                .filter(|codec| publisher_codecs.contains(codec))
+                .cloned()
+                .collect()
+        };
+
+        let req_id = req.req_id;
+        match crate::tasks::whep::WhepServerTask::new(
+            self.dtls_cert.clone(),
+            req,
+            channel,
+            build_resource_path("whep", self.worker_id, task_id, &resource_token),
+            vec![self.udp_socket.local_addr()],
+            &codecs,
+            self.config.capture_raw_packets,
+        ) {
+            Ok(task) => {
+                let task = ComposeTask::Whep(task);
+                log::info!("Created whep task id: {}, ufrag: {}", task_id, task.ufrag());
+                self.add_task(task_id, task, None, resource_token);
+            }
+            Err(e) => {
+                log::warn!("Failed to create whep task: {:?}", e);
+                self.send_error(req_id, &e);
             }
         }
     }
 
+    fn create_room_task(&mut self, req: HttpRequest, room: String) {
+        if let Err(e) = self
+            .config
+            .authorizer
+            .authorize(&req, &room, AuthRole::Publish)
+        {
+            log::warn!("Rejected room request for room {}: {:?}", room, e);
+            let res = problem_response(req.req_id, e.status(), e.title(), e.detail());
+            self.send_response(res);
+            return;
+        }
+
+        let task_id = self.task_id_seed;
+        self.task_id_seed += 1;
+        let resource_token = new_resource_token();
+        let participant = format!("{}-{}", self.worker_id, task_id);
+        let others = self.rooms.lock().get(&room).cloned().unwrap_or_default();
+
+        let req_id = req.req_id;
+        match crate::tasks::room::RoomTask::new(
+            self.dtls_cert.clone(),
+            req,
+            room.clone(),
+            participant.clone(),
+            others,
+            build_resource_path("room", self.worker_id, task_id, &resource_token),
+            vec![self.udp_socket.local_addr()],
+            &self.config.codecs,
+            self.config.room_speaker_priority,
+            self.config.capture_raw_packets,
+        ) {
+            Ok(task) => {
+                let task = ComposeTask::Room(task);
+                log::info!(
+                    "Created room task id: {}, room {room}, participant {participant}, ufrag: {}",
+                    task_id,
+                    task.ufrag()
+                );
+                self.rooms
+                    .lock()
+                    .entry(room.clone())
+                    .or_default()
+                    .push(participant.clone());
+                self.room_tasks
+                    .entry(room.clone())
+                    .or_default()
+                    .push(task_id);
+                self.add_task(task_id, task, None, resource_token);
+                if let Some(container) = self.tasks.get_mut(&task_id) {
+                    container.room = Some((room.clone(), participant.clone()));
+                }
+                self.bus_send.lock().broadcast(BusEvent::Room {
+                    room,
+                    event: RoomEvent::Joined(participant),
+                });
+            }
+            Err(e) => {
+                log::warn!("Failed to create room task: {:?}", e);
+                self.send_error(req_id, &e);
+            }
+        }
+    }
+
+    fn create_playback_task(&mut self, req: HttpRequest, channel: String) {
+        let task_id = self.task_id_seed;
+        self.task_id_seed += 1;
+        let resource_token = new_resource_token();
+
+        let req_id = req.req_id;
+        match crate::tasks::playback::PlaybackTask::new(
+            req,
+            channel.clone(),
+            build_resource_path("playback", self.worker_id, task_id, &resource_token),
+            &self.config.playback_dir,
+            &self.config.codecs,
+        ) {
+            Ok(task) => {
+                if !self.claim_publisher(&channel, task_id) {
+                    log::warn!("Rejected playback task, channel {channel} already has a publisher");
+                    let res = problem_response(
+                        req_id,
+                        409,
+                        "Conflict",
+                        "channel already has a publisher",
+                    );
+                    self.send_response(res);
+                    return;
+                }
+                let task = ComposeTask::Playback(task);
+                log::info!("Created playback task id: {task_id}, channel {channel}");
+                let record = self.recordings.lock().contains(&channel);
+                self.add_task(task_id, task, Some(channel), resource_token);
+                if record {
+                    self.start_recording(task_id);
+                }
+            }
+            Err(e) => {
+                log::warn!("Failed to create playback task: {:?}", e);
+                self.send_error(req_id, &e);
+            }
+        }
+    }
+
+    fn create_rtmp_task(&mut self, publish: RtmpPublish) {
+        let channel = publish.channel.clone();
+        if let Err(e) = self
+            .config
+            .authorizer
+            .authorize(&publish.req, &channel, AuthRole::Publish)
+        {
+            log::warn!("Rejected rtmp publish for channel {}: {:?}", channel, e);
+            publish.reject(e.detail().to_string());
+            return;
+        }
+
+        let task_id = self.task_id_seed;
+        self.task_id_seed += 1;
+        let resource_token = new_resource_token();
+        if !self.claim_publisher(&channel, task_id) {
+            log::warn!("Rejected rtmp task, channel {channel} already has a publisher");
+            publish.reject("channel already has a publisher".to_string());
+            return;
+        }
+        let task = ComposeTask::Rtmp(crate::tasks::rtmp::RtmpTask::new(
+            channel.clone(),
+            build_resource_path("rtmp", self.worker_id, task_id, &resource_token),
+            publish.media,
+            &self.config.codecs,
+        ));
+        log::info!("Created rtmp task id: {task_id}, channel {channel}");
+        if publish.result.send(Ok(())).is_err() {
+            //the connection gave up waiting, the task ends when it finds the media closed
+            log::warn!("Rtmp connection of channel {channel} closed before the answer");
+        }
+        let record = self.recordings.lock().contains(&channel);
+        self.add_task(task_id, task, Some(channel), resource_token);
+        if record {
+            self.start_recording(task_id);
+        }
+    }
+
+    fn forward_http_to_task(&mut self, req: HttpRequest) {
+        //an unknown session and a wrong token get the same answer
+        let task = parse_resource_path(&req.path)
+            .filter(|resource| resource.worker_id == self.worker_id)
+            .and_then(|resource| {
+                let task = self.tasks.get_mut(&resource.task_id)?;
+                (task.task.kind() == resource.kind && task.resource_token == resource.token)
+                    .then_some((resource.task_id, task))
+            });
+        if let Some((task_id, task)) = task {
+            log::info!("Forward {} {} to task {}", req.method, req.path, task_id);
+            let now = Instant::now();
+            task.task.input(now, IoEvent::HttpRequest(req).into());
+            Self::pop_task(
+                now,
+                task_id,
+                task,
+                &mut self.udp_socket,
+                &self.ext_send,
+                &self.bus_send,
+                &mut self.bus_channels,
+                &mut self.task_ufrags,
+                &mut self.ended_tasks,
+                &mut self.new_subscribers,
+                self.worker_id,
+                &self.publishers,
+                &self.config,
+                &self.metrics,
+            );
+        } else {
+            self.ext_send
+                .send(IoAction::HttpResponse(HttpResponse {
+                    req_id: req.req_id,
+                    status: 404,
+                    headers: Default::default(),
+                    body: b"Not Found".to_vec(),
+                }))
+                .unwrap();
+        }
+    }
+
+    fn send_error(&mut self, req_id: u64, err: &CreateTaskError) {
+        let res = problem_response(req_id, err.status(), err.title(), err.detail());
+        self.send_response(res);
+    }
+
+    fn send_response(&mut self, res: HttpResponse) {
+        if let Err(e) = self.ext_send.try_send(IoAction::HttpResponse(res)) {
+            log::error!("Failed to send response to controller: {e}");
+        }
+    }
+
+    fn add_task(
+        &mut self,
+        task_id: usize,
+        task: ComposeTask,
+        publish_channel: Option<String>,
+        resource_token: String,
+    ) {
+        self.task_ufrags.insert(task.ufrag(), task_id);
+        let mut task_container: TaskContainer = task.into();
+        task_container.publish_channel = publish_channel;
+        task_container.resource_token = resource_token;
+        Self::pop_task(
+            Instant::now(),
+            task_id,
+            &mut task_container,
+            &mut self.udp_socket,
+            &self.ext_send,
+            &self.bus_send,
+            &mut self.bus_channels,
+            &mut self.task_ufrags,
+            &mut self.ended_tasks,
+            &mut self.new_subscribers,
+            self.worker_id,
+            &self.publishers,
+            &self.config,
+            &self.metrics,
+        );
+
+        self.tasks.insert(task_id, task_container);
+    }
+
     fn process_bus_recv(&mut self) {
         while let Ok(event) = self.bus_recv.try_recv() {
             log::debug!("Received track media from bus");
             match event {
                 BusEvent::TrackMedia(media) => {
+                    if media.codec.is_video() {
+                        let limit = self
+                            .gop_cache_limits
+                            .get(&media.track_id)
+                            .copied()
+                            .unwrap_or(self.config.gop_cache_bytes);
+                        if limit > 0 {
+                            self.gop_caches
+                                .entry(media.track_id)
+                                .or_insert_with(|| GopCache::new(limit))
+                                .push(&media, Instant::now());
+                        }
+                    }
                     if let Some(channel) = self.bus_channels.get(&media.track_id) {
                         for consumer in &channel.consumers {
                             if let Some(task) = self.tasks.get_mut(consumer) {
@@ -226,16 +853,146 @@ impl Worker {
                         }
                     }
                 }
+                BusEvent::Room { room, event } => {
+                    if let Some(task_ids) = self.room_tasks.get(&room) {
+                        let now = Instant::now();
+                        for task_id in task_ids {
+                            if let Some(task) = self.tasks.get_mut(task_id) {
+                                //a participant doesn't get its own events
+                                let own = match (&event, &task.room) {
+                                    (
+                                        RoomEvent::Joined(participant)
+                                        | RoomEvent::Left(participant),
+                                        Some((_, own)),
+                                    ) => participant == own,
+                                    _ => false,
+                                };
+                                if !own {
+                                    task.task
+                                        .input(now, WebrtcTaskInput::RoomEvent(event.clone()));
+                                }
+                            }
+                        }
+                    }
+                }
+                BusEvent::ChannelData(msg) => {
+                    if let Some(channel) = self.bus_channels.get(&msg.track_id) {
+                        let targets = if msg.upstream {
+                            &channel.sources
+                        } else {
+                            &channel.consumers
+                        };
+                        let now = Instant::now();
+                        for target in targets {
+                            if let Some(task) = self.tasks.get_mut(target) {
+                                task.task
+                                    .input(now, WebrtcTaskInput::ChannelData(msg.clone()));
+                            }
+                        }
+                    }
+                }
+                BusEvent::Record { channel, enabled } => {
+                    let task_id = self
+                        .tasks
+                        .iter()
+                        .find(|(_, task)| task.publish_channel.as_ref() == Some(&channel))
+                        .map(|(task_id, _)| *task_id);
+                    if let Some(task_id) = task_id {
+                        if enabled {
+                            self.start_recording(task_id);
+                        } else if let Some(recording) = self
+                            .tasks
+                            .get_mut(&task_id)
+                            .and_then(|task| task.recording.take())
+                        {
+                            log::info!(
+                                "Stop recording channel {channel} to {}",
+                                recording.path().display()
+                            );
+                        }
+                    }
+                }
+                BusEvent::Capture { target, enabled } => {
+                    let now = Instant::now();
+                    for (task_id, task) in self.tasks.iter_mut() {
+                        let matched = match &target {
+                            CaptureTarget::Session {
+                                worker_id,
+                                task_id: target_task_id,
+                            } => *worker_id == self.worker_id && target_task_id == task_id,
+                            CaptureTarget::Channel(channel) => {
+                                let video_track_id = track_id_builder(channel, MediaKind::Video);
+                                task.publish_channel.as_ref() == Some(channel)
+                                    || task.sub_channels.contains(&video_track_id)
+                            }
+                        };
+                        if !matched {
+                            continue;
+                        }
+                        let capture = if enabled {
+                            //starting addresses, the capture follows the session datagrams
+                            let remote = task
+                                .remotes
+                                .last()
+                                .copied()
+                                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+                            Some(RtpCapture::start(
+                                &self.config.capture_dir,
+                                &format!("{}-{task_id}", self.worker_id),
+                                self.udp_socket_local_addr,
+                                remote,
+                            ))
+                        } else {
+                            log::info!("Stop capturing task {task_id}");
+                            None
+                        };
+                        task.task.input(now, WebrtcTaskInput::Capture(capture));
+                    }
+                }
+                BusEvent::EndTask { worker_id, task_id } => {
+                    if worker_id == self.worker_id {
+                        if let Some(task) = self.tasks.get_mut(&task_id) {
+                            log::info!("Ending task {task_id} by bus request");
+                            task.task.input(Instant::now(), WebrtcTaskInput::EndTask);
+                        }
+                    }
+                }
                 BusEvent::TrackKeyframeRequest(track_id, kind) => {
-                    if let Some(channel) = self.bus_channels.get(&track_id) {
-                        for source in &channel.sources {
-                            if let Some(task) = self.tasks.get_mut(source) {
-                                task.task.input(
-                                    Instant::now(),
-                                    WebrtcTaskInput::RequestKeyframeTrack { track_id, kind },
-                                );
+                    //only the worker of the publisher handles the request
+                    let published = self
+                        .bus_channels
+                        .get(&track_id)
+                        .is_some_and(|channel| !channel.sources.is_empty());
+                    if !published {
+                        continue;
+                    }
+                    let now = Instant::now();
+                    match self.keyframe_throttles.get_mut(&track_id) {
+                        Some(throttle) => {
+                            log::debug!("Throttled keyframe request for track {track_id}");
+                            Metrics::inc(&self.metrics.keyframe_requests_suppressed);
+                            //a FIR is stronger than a PLI, keep it when merging
+                            if throttle.pending != Some(KeyframeRequestKind::Fir) {
+                                throttle.pending = Some(kind);
                             }
                         }
+                        None => {
+                            self.keyframe_throttles.insert(
+                                track_id,
+                                KeyframeThrottle {
+                                    last_sent: now,
+                                    pending: None,
+                                },
+                            );
+                            Self::request_keyframe(
+                                now,
+                                track_id,
+                                kind,
+                                &self.bus_channels,
+                                &mut self.tasks,
+                                &self.metrics,
+                            );
+                        }
                     }
                 }
             }
@@ -297,7 +1054,13 @@ impl Worker {
                     &self.ext_send,
                     &self.bus_send,
                     &mut self.bus_channels,
+                    &mut self.task_ufrags,
                     &mut self.ended_tasks,
+                    &mut self.new_subscribers,
+                    self.worker_id,
+                    &self.publishers,
+                    &self.config,
+                    &self.metrics,
                 )
             }
         }
@@ -321,7 +1084,13 @@ impl Worker {
                 &self.ext_send,
                 &self.bus_send,
                 &mut self.bus_channels,
+                &mut self.task_ufrags,
                 &mut self.ended_tasks,
+                &mut self.new_subscribers,
+                self.worker_id,
+                &self.publishers,
+                &self.config,
+                &self.metrics,
             );
         }
     }
@@ -334,7 +1103,13 @@ impl Worker {
         ext_send: &Sender<IoAction>,
         bus_send: &Arc<Mutex<Bus<BusEvent>>>,
         bus_channels: &mut HashMap<u64, BusChannelContainer>,
+        task_ufrags: &mut HashMap<String, usize>,
         ended_tasks: &mut Vec<usize>,
+        new_subscribers: &mut Vec<(usize, u64)>,
+        worker_id: usize,
+        publishers: &PublisherRegistry,
+        config: &WorkerConfig,
+        metrics: &Metrics,
     ) {
         while let Some(action) = task.task.pop_action(now) {
             match action {
@@ -349,9 +1124,36 @@ impl Worker {
                     }
                 }
                 WebrtcTaskOutput::TrackMedia(media) => {
+                    if let Some(recording) = &task.recording {
+                        recording.push(&media);
+                    }
                     bus_send.lock().broadcast(BusEvent::TrackMedia(media));
                     log::debug!("Sent track media to bus");
                 }
+                WebrtcTaskOutput::ChannelData(msg) => {
+                    if msg.data.len() > config.data_channel_max_message
+                        || (msg.upstream && !config.data_channel_upstream)
+                    {
+                        log::debug!(
+                            "Drop data channel message {} of {} bytes",
+                            msg.label,
+                            msg.data.len()
+                        );
+                        Metrics::inc(&metrics.data_channel_messages_dropped);
+                        continue;
+                    }
+                    bus_send.lock().broadcast(BusEvent::ChannelData(msg));
+                }
+                WebrtcTaskOutput::UnsubscribeTrack { track_id } => {
+                    log::info!("Task {task_id} unsubscribed from track {track_id}");
+                    if let Some(channel) = bus_channels.get_mut(&track_id) {
+                        channel.consumers.retain(|c| *c != task_id);
+                        if channel.consumers.is_empty() && channel.sources.is_empty() {
+                            bus_channels.remove(&track_id);
+                        }
+                    }
+                    task.sub_channels.retain(|t| *t != track_id);
+                }
                 WebrtcTaskOutput::RequestKeyframeTrack { track_id, kind } => {
                     bus_send
                         .lock()
@@ -361,6 +1163,24 @@ impl Worker {
                     log::info!("Task {task_id} ended");
                     ended_tasks.push(task_id);
                 }
+                WebrtcTaskOutput::PublishCodecs(codecs) => {
+                    if let Some(channel) = &task.publish_channel {
+                        if let Some(publisher) = publishers.lock().get_mut(channel) {
+                            if publisher.worker_id == worker_id && publisher.task_id == task_id {
+                                log::info!("Channel {channel} publisher codecs {:?}", codecs);
+                                publisher.codecs = codecs;
+                            }
+                        }
+                    }
+                }
+                WebrtcTaskOutput::IceRestart {
+                    old_ufrag,
+                    new_ufrag,
+                } => {
+                    log::info!("Task {task_id} ice restart, ufrag {old_ufrag} => {new_ufrag}");
+                    task_ufrags.remove(&old_ufrag);
+                    task_ufrags.insert(new_ufrag, task_id);
+                }
                 WebrtcTaskOutput::PublishTrack { track_id } => {
                     log::info!("Task {task_id} published track {track_id}");
                     bus_channels
@@ -384,6 +1204,7 @@ impl Worker {
                         .consumers
                         .push(task_id);
                     task.sub_channels.push(track_id);
+                    new_subscribers.push((task_id, track_id));
                 }
             }
         }
@@ -391,11 +1212,46 @@ impl Worker {
 
     fn pop_ended_tasks(&mut self) {
         for task_id in self.ended_tasks.drain(..) {
-            let container = self.tasks.remove(&task_id).expect("Should have a task");
+            //a task can report ended more than once, e.g. DELETE followed by ICE disconnect
+            let container = if let Some(container) = self.tasks.remove(&task_id) {
+                container
+            } else {
+                continue;
+            };
             for remote in container.remotes {
                 self.task_remotes.remove(&remote);
             }
             self.task_ufrags.remove(&container.task.ufrag());
+            if let Some(channel) = &container.publish_channel {
+                let mut publishers = self.publishers.lock();
+                //the channel may already be taken over by another task
+                if publishers
+                    .get(channel)
+                    .is_some_and(|p| p.worker_id == self.worker_id && p.task_id == task_id)
+                {
+                    publishers.remove(channel);
+                }
+            }
+            if let Some((room, participant)) = container.room {
+                let mut rooms = self.rooms.lock();
+                if let Some(participants) = rooms.get_mut(&room) {
+                    participants.retain(|p| *p != participant);
+                    if participants.is_empty() {
+                        rooms.remove(&room);
+                    }
+                }
+                drop(rooms);
+                if let Some(task_ids) = self.room_tasks.get_mut(&room) {
+                    task_ids.retain(|t| *t != task_id);
+                    if task_ids.is_empty() {
+                        self.room_tasks.remove(&room);
+                    }
+                }
+                self.bus_send.lock().broadcast(BusEvent::Room {
+                    room,
+                    event: RoomEvent::Left(participant),
+                });
+            }
             for track_id in container.sub_channels {
                 if let Some(channel) = self.bus_channels.get_mut(&track_id) {
                     channel.consumers.retain(|c| *c != task_id);
@@ -406,7 +1262,7 @@ impl Worker {
             }
             for track_id in container.pub_channels {
                 if let Some(channel) = self.bus_channels.get_mut(&track_id) {
-                    channel.consumers.retain(|c| *c != task_id);
+                    channel.sources.retain(|c| *c != task_id);
                     if channel.consumers.is_empty() && channel.sources.is_empty() {
                         self.bus_channels.remove(&track_id);
                     }